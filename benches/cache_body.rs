@@ -0,0 +1,45 @@
+//! Compare the ways `GET /orders/:order_id` may turn a cache entry into a response body:
+//! decode it into `Order` and serialize back or send JSON payload as it is.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use l_0_demo::{
+    codec::{Codec, Format},
+    model::{demo_order, Order},
+};
+
+fn cache_body(c: &mut Criterion) {
+    for items in [1, 100] {
+        let order = demo_order("b563feb7b2b84b6test", items);
+        let mut group = c.benchmark_group(format!("cache_body/{}_items", items));
+
+        for (name, format, compress) in [
+            ("json", Format::Json, false),
+            ("json+zstd", Format::Json, true),
+            ("msgpack", Format::MsgPack, false),
+        ] {
+            let codec = Codec::new(format, compress);
+            let bytes = codec.encode(&order).unwrap();
+
+            group.bench_function(format!("{}/reserialize", name), |b| {
+                b.iter(|| {
+                    let order: Order = codec.decode(black_box(&bytes)).unwrap().unwrap();
+                    serde_json::to_vec(&order).unwrap()
+                })
+            });
+
+            group.bench_function(format!("{}/as_is", name), |b| {
+                b.iter(|| {
+                    codec
+                        .decode_json::<Order>(black_box(&bytes))
+                        .unwrap()
+                        .unwrap()
+                })
+            });
+        }
+
+        group.finish();
+    }
+}
+
+criterion_group!(benches, cache_body);
+criterion_main!(benches);
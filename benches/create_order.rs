@@ -0,0 +1,120 @@
+//! Compare writing items of an order with a multi-row `INSERT` built by hand
+//! against binary `COPY`, and creating orders one by one against importing them.
+//! It needs a database, so it's run only if `WBTECH_L0_DEMO_BENCH_DB_PARAMS` is set,
+//! e.g. `host=localhost user=postgres password=postgres dbname=wb`.
+//! Created orders are removed afterwards.
+
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, Criterion};
+use l_0_demo::{
+    model::{demo_order, Actor, Order},
+    repo::PostgresRepo,
+    state::StoreOrder,
+};
+use serde_json::value::{to_raw_value, RawValue};
+use tokio::runtime::Runtime;
+
+const DB_PARAMS_VAR: &str = "WBTECH_L0_DEMO_BENCH_DB_PARAMS";
+
+fn actor() -> Actor {
+    Actor {
+        name: "bench".to_owned(),
+        request_id: "bench".to_owned(),
+    }
+}
+
+/// Unique ids let every iteration create new orders, they are paired with their payloads.
+fn orders(prefix: &str, count: usize, items: usize) -> Vec<(Order, Box<RawValue>)> {
+    (0..count)
+        .map(|id| {
+            let order = demo_order(&format!("{}{}", prefix, id), items);
+            let payload = to_raw_value(&order).unwrap();
+            (order, payload)
+        })
+        .collect()
+}
+
+fn remove(rt: &Runtime, repo: &PostgresRepo, orders: &[(Order, Box<RawValue>)]) {
+    rt.block_on(async {
+        for (order, _) in orders {
+            repo.delete_order(&order.order_uid, &actor()).await.unwrap();
+        }
+    });
+}
+
+fn create_order(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let params = std::env::var(DB_PARAMS_VAR).unwrap();
+    let repo = rt.block_on(PostgresRepo::try_new(&params)).unwrap();
+
+    for items in [10, 100, 1000] {
+        let mut group = c.benchmark_group(format!("create_order/{}_items", items));
+
+        for (name, copy_threshold) in [("insert", usize::MAX), ("copy", 0)] {
+            let repo = repo.clone().with_copy_threshold(copy_threshold);
+            group.bench_function(name, |b| {
+                b.iter_custom(|iters| {
+                    let orders = orders(&format!("bench{}", name), iters as usize, items);
+                    let start = Instant::now();
+                    rt.block_on(async {
+                        for (order, payload) in orders.clone() {
+                            repo.create_order(order, payload, &actor()).await.unwrap();
+                        }
+                    });
+                    let elapsed = start.elapsed();
+                    remove(&rt, &repo, &orders);
+                    elapsed
+                })
+            });
+        }
+
+        group.finish();
+    }
+
+    let mut group = c.benchmark_group("create_order/100_orders_of_10_items");
+    group.bench_function("one_by_one", |b| {
+        b.iter_custom(|iters| {
+            let mut elapsed = Duration::ZERO;
+            for _ in 0..iters {
+                let orders = orders("benchone", 100, 10);
+                let start = Instant::now();
+                rt.block_on(async {
+                    for (order, payload) in orders.clone() {
+                        repo.create_order(order, payload, &actor()).await.unwrap();
+                    }
+                });
+                elapsed += start.elapsed();
+                remove(&rt, &repo, &orders);
+            }
+            elapsed
+        })
+    });
+    group.bench_function("import", |b| {
+        b.iter_custom(|iters| {
+            let mut elapsed = Duration::ZERO;
+            for _ in 0..iters {
+                let orders = orders("benchimport", 100, 10);
+                let start = Instant::now();
+                rt.block_on(repo.import_orders(orders.clone(), &actor()))
+                    .unwrap();
+                elapsed += start.elapsed();
+                remove(&rt, &repo, &orders);
+            }
+            elapsed
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, create_order);
+
+fn main() {
+    if std::env::var_os(DB_PARAMS_VAR).is_none() {
+        eprintln!("skip create_order benchmarks, {} is not set", DB_PARAMS_VAR);
+        return;
+    }
+
+    benches();
+    Criterion::default().configure_from_args().final_summary();
+}
@@ -0,0 +1,64 @@
+//! Compare fetching an order from Postgres with a single aggregating query
+//! against a query per its part. It needs a database, so it's run only if
+//! `WBTECH_L0_DEMO_BENCH_DB_PARAMS` is set, e.g.
+//! `host=localhost user=postgres password=postgres dbname=wb`.
+
+use criterion::{criterion_group, Criterion};
+use l_0_demo::{
+    model::{demo_order, Actor},
+    repo::PostgresRepo,
+    state::StoreOrder,
+};
+use serde_json::value::to_raw_value;
+use tokio::runtime::Runtime;
+
+const DB_PARAMS_VAR: &str = "WBTECH_L0_DEMO_BENCH_DB_PARAMS";
+
+fn actor() -> Actor {
+    Actor {
+        name: "bench".to_owned(),
+        request_id: "bench".to_owned(),
+    }
+}
+
+fn get_order(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let params = std::env::var(DB_PARAMS_VAR).unwrap();
+    let repo = rt.block_on(PostgresRepo::try_new(&params)).unwrap();
+
+    for items in [1, 10, 100] {
+        let order_uid = format!("bench{}items", items);
+        rt.block_on(async {
+            repo.delete_order(&order_uid, &actor()).await.unwrap();
+            let order = demo_order(&order_uid, items);
+            let payload = to_raw_value(&order).unwrap();
+            repo.create_order(order, payload, &actor()).await.unwrap();
+        });
+
+        let mut group = c.benchmark_group(format!("get_order/{}_items", items));
+        group.bench_function("single", |b| {
+            b.to_async(&rt)
+                .iter(|| async { repo.get_order(&order_uid).await.unwrap().unwrap() })
+        });
+        group.bench_function("split", |b| {
+            b.to_async(&rt)
+                .iter(|| async { repo.get_order_split(&order_uid).await.unwrap().unwrap() })
+        });
+        group.finish();
+
+        rt.block_on(repo.delete_order(&order_uid, &actor()))
+            .unwrap();
+    }
+}
+
+criterion_group!(benches, get_order);
+
+fn main() {
+    if std::env::var_os(DB_PARAMS_VAR).is_none() {
+        eprintln!("skip get_order benchmarks, {} is not set", DB_PARAMS_VAR);
+        return;
+    }
+
+    benches();
+    Criterion::default().configure_from_args().final_summary();
+}
@@ -5,7 +5,47 @@ CREATE TYPE locale AS ENUM (
 );
 
 CREATE TYPE item_status AS ENUM (
-  'StatusCode'
+  'Accepted',
+  'Assembled',
+  'Shipped',
+  'Delivered',
+  'Returned',
+  'Cancelled'
+);
+
+CREATE TYPE order_status AS ENUM (
+  'Created',
+  'Paid',
+  'InDelivery',
+  'Completed',
+  'Cancelled'
+);
+
+CREATE TYPE event_type AS ENUM (
+  'OrderCreated',
+  'OrderUpdated',
+  'OrderDeleted',
+  'OrderStatusChanged',
+  'ItemStatusChanged'
+);
+
+CREATE TYPE rejection_source AS ENUM (
+  'Http',
+  'Queue',
+  'Import'
+);
+
+CREATE TYPE api_scope AS ENUM (
+  'OrdersRead',
+  'OrdersWrite'
+);
+
+CREATE TYPE audit_operation AS ENUM (
+  'Create',
+  'Update',
+  'Delete',
+  'StatusChange',
+  'ItemStatusChange'
 );
 
 CREATE TYPE currency AS ENUM (
@@ -21,7 +61,8 @@ CREATE TABLE IF NOT EXISTS deliveries (
   city VARCHAR(50),
   address VARCHAR(50),
   region VARCHAR(50),
-  email VARCHAR(50)
+  email VARCHAR(50),
+  content_hash BYTEA UNIQUE
 );
 
 CREATE TABLE IF NOT EXISTS payments (
@@ -50,7 +91,17 @@ CREATE TABLE IF NOT EXISTS orders (
   shardkey VARCHAR(2),
   sm_id INTEGER,
   date_created TIMESTAMPTZ,
-  oof_shard VARCHAR(2)
+  oof_shard VARCHAR(2),
+  status order_status NOT NULL DEFAULT 'Created'
+);
+
+CREATE TABLE IF NOT EXISTS products (
+  id SERIAL PRIMARY KEY,
+  nm_id INTEGER,
+  brand VARCHAR(50),
+  name VARCHAR(50),
+  size VARCHAR(4),
+  UNIQUE (nm_id, brand, name, size)
 );
 
 CREATE TABLE IF NOT EXISTS items (
@@ -65,7 +116,8 @@ CREATE TABLE IF NOT EXISTS items (
   total_price INTEGER,
   nm_id INTEGER,
   brand VARCHAR(50),
-  status item_status
+  status item_status,
+  product_id INTEGER REFERENCES products(id)
 );
 
 CREATE TABLE IF NOT EXISTS items_to_order (
@@ -73,3 +125,136 @@ CREATE TABLE IF NOT EXISTS items_to_order (
   item_id INTEGER REFERENCES items(id) DEFERRABLE INITIALLY DEFERRED,
   order_id VARCHAR(19) REFERENCES orders(order_uid) DEFERRABLE INITIALLY DEFERRED
 );
+
+CREATE INDEX IF NOT EXISTS orders_track_number_idx ON orders (track_number);
+CREATE INDEX IF NOT EXISTS orders_customer_id_idx ON orders (customer_id, date_created DESC);
+CREATE INDEX IF NOT EXISTS orders_payment_id_idx ON orders (payment_id);
+CREATE INDEX IF NOT EXISTS items_to_order_order_id_idx ON items_to_order (order_id);
+
+CREATE TABLE IF NOT EXISTS item_status_history (
+  id SERIAL PRIMARY KEY,
+  item_id INTEGER REFERENCES items(id) ON DELETE CASCADE,
+  status item_status,
+  changed_at TIMESTAMPTZ DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS item_status_history_item_id_idx ON item_status_history (item_id);
+
+CREATE TABLE IF NOT EXISTS order_payloads (
+  order_id VARCHAR(19) PRIMARY KEY REFERENCES orders(order_uid) ON DELETE CASCADE DEFERRABLE INITIALLY DEFERRED,
+  payload JSONB NOT NULL,
+  received_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE TABLE IF NOT EXISTS outbox (
+  id BIGSERIAL PRIMARY KEY,
+  event_type event_type NOT NULL,
+  order_id VARCHAR(19) NOT NULL,
+  data JSONB NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  published_at TIMESTAMPTZ,
+  attempts INTEGER NOT NULL DEFAULT 0,
+  next_attempt_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  last_error TEXT
+);
+
+CREATE INDEX IF NOT EXISTS outbox_pending_idx ON outbox (next_attempt_at) WHERE published_at IS NULL;
+
+CREATE TABLE IF NOT EXISTS webhooks (
+  id SERIAL PRIMARY KEY,
+  url TEXT NOT NULL,
+  event_types event_type[] NOT NULL,
+  secret TEXT NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE TABLE IF NOT EXISTS webhook_deliveries (
+  id BIGSERIAL PRIMARY KEY,
+  webhook_id INTEGER NOT NULL REFERENCES webhooks(id) ON DELETE CASCADE,
+  event_id BIGINT NOT NULL REFERENCES outbox(id),
+  attempts INTEGER NOT NULL DEFAULT 0,
+  next_attempt_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  delivered_at TIMESTAMPTZ,
+  failed_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS webhook_deliveries_pending_idx ON webhook_deliveries (next_attempt_at)
+  WHERE delivered_at IS NULL AND failed_at IS NULL;
+CREATE INDEX IF NOT EXISTS webhook_deliveries_webhook_id_idx ON webhook_deliveries (webhook_id);
+
+CREATE TABLE IF NOT EXISTS webhook_attempts (
+  id BIGSERIAL PRIMARY KEY,
+  delivery_id BIGINT NOT NULL REFERENCES webhook_deliveries(id) ON DELETE CASCADE,
+  attempted_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  status_code INTEGER,
+  error TEXT
+);
+
+CREATE INDEX IF NOT EXISTS webhook_attempts_delivery_id_idx ON webhook_attempts (delivery_id);
+
+-- entries outlive their orders, so there is no reference
+CREATE TABLE IF NOT EXISTS order_audit (
+  id BIGSERIAL PRIMARY KEY,
+  order_id VARCHAR(19) NOT NULL,
+  operation audit_operation NOT NULL,
+  actor TEXT NOT NULL,
+  request_id TEXT NOT NULL,
+  diff JSONB NOT NULL,
+  recorded_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS order_audit_order_id_idx ON order_audit (order_id, id);
+
+CREATE OR REPLACE FUNCTION forbid_audit_change() RETURNS trigger AS $$
+BEGIN
+  RAISE EXCEPTION 'order_audit is append-only';
+END
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE TRIGGER order_audit_append_only
+  BEFORE UPDATE OR DELETE ON order_audit
+  FOR EACH ROW EXECUTE FUNCTION forbid_audit_change();
+
+CREATE OR REPLACE TRIGGER order_audit_no_truncate
+  BEFORE TRUNCATE ON order_audit
+  FOR EACH STATEMENT EXECUTE FUNCTION forbid_audit_change();
+
+-- only hashes of keys are kept, a name is reused once its key is revoked
+CREATE TABLE IF NOT EXISTS api_keys (
+  id SERIAL PRIMARY KEY,
+  name TEXT NOT NULL,
+  key_hash CHAR(64) NOT NULL UNIQUE,
+  scopes api_scope[] NOT NULL,
+  rate_limit INTEGER CHECK (rate_limit > 0),
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  revoked_at TIMESTAMPTZ
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS api_keys_name_idx ON api_keys (name) WHERE revoked_at IS NULL;
+
+-- payload might be not even JSON, so it's kept as text
+CREATE TABLE IF NOT EXISTS rejected_orders (
+  id BIGSERIAL PRIMARY KEY,
+  source rejection_source NOT NULL,
+  payload TEXT NOT NULL,
+  error TEXT NOT NULL,
+  rejected_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS rejected_orders_rejected_at_idx ON rejected_orders (rejected_at);
+
+CREATE OR REPLACE VIEW item_details AS
+SELECT i.id
+  , i.chrt_id
+  , i.track_number
+  , i.price
+  , i.rid
+  , COALESCE(p.name, i.name) AS name
+  , i.sale
+  , COALESCE(p.size, i.size) AS size
+  , i.total_price
+  , COALESCE(p.nm_id, i.nm_id) AS nm_id
+  , COALESCE(p.brand, i.brand) AS brand
+  , i.status
+FROM items i
+LEFT JOIN products p ON p.id = i.product_id;
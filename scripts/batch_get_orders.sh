@@ -0,0 +1,13 @@
+#!/bin/bash
+
+order_ids=("b563feb7b2b84b6test")
+
+if [ $# -gt 0 ];
+then
+    order_ids=("$@")
+fi
+
+ids=$(printf '"%s",' "${order_ids[@]}")
+url="http://localhost:3001/orders:batchGet"
+
+echo "$(curl -v -X POST -H "Content-Type: application/json" -d "{\"ids\": [${ids%,}]}" "$url")"
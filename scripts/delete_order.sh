@@ -0,0 +1,12 @@
+#!/bin/bash
+
+order_id="b563feb7b2b84b6test"
+
+if [ ! -z $1 ];
+then
+    order_id=$1
+fi
+
+url="http://localhost:3001/orders/$order_id"
+
+echo "$(curl -v -X DELETE "$url")"
@@ -0,0 +1,335 @@
+use std::{collections::HashMap, path::Path};
+
+use anyhow::{anyhow, Context};
+use axum::{
+    extract::{Request, State},
+    http::{header, Method},
+    middleware::Next,
+    response::Response,
+};
+use jsonwebtoken::{decode, decode_header, jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tracing::{debug, trace};
+
+use crate::{
+    error::Error,
+    model::ApiScope,
+    state::{AppState, StoreApiKey},
+    webhook::{generate_secret, hex},
+};
+
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+/// Prefix of keys, it lets scanners of leaked secrets tell them.
+const API_KEY_PREFIX: &str = "l0_";
+
+/// Random key for a new client, it's given to the client and never stored as it is.
+pub fn generate_api_key() -> String {
+    format!("{}{}", API_KEY_PREFIX, generate_secret())
+}
+
+/// Keys are random enough to be stored as plain SHA-256 hex without salt.
+pub fn hash_api_key(key: &[u8]) -> String {
+    hex(&Sha256::digest(key))
+}
+
+/// Reading requests need `orders:read`, the rest of them need `orders:write`.
+fn required_scope(request: &Request) -> ApiScope {
+    let is_read = matches!(*request.method(), Method::GET | Method::HEAD)
+        || request.uri().path().ends_with(":batchGet");
+
+    if is_read {
+        ApiScope::OrdersRead
+    } else {
+        ApiScope::OrdersWrite
+    }
+}
+
+/// Authenticated client of the request, it's put into extensions of the request.
+#[derive(Clone, Debug)]
+pub struct Client {
+    /// Name of the API key or subject of the token, it's the actor of changes.
+    pub name: String,
+    /// The client may only read orders of this customer.
+    pub customer_id: Option<String>,
+    /// Bucket of the client in the rate limiter.
+    pub bucket: String,
+    /// Requests per minute, the default rate applies without it.
+    pub rate_limit: Option<u32>,
+}
+
+/// Claims of tokens issued by the identity provider.
+#[derive(Debug, Deserialize)]
+struct Claims {
+    sub: String,
+    /// Space separated scopes, e.g. `orders:read orders:write`.
+    #[serde(default)]
+    scope: String,
+    customer_id: Option<String>,
+}
+
+/// Verifies JWTs with keys configured locally, it never fetches them.
+pub struct JwtVerifier {
+    keys: JwtKeys,
+    validation: Validation,
+}
+
+enum JwtKeys {
+    Static(DecodingKey),
+    /// Keys of JWKS by their ids.
+    Set(HashMap<String, DecodingKey>),
+}
+
+impl JwtVerifier {
+    /// Verify tokens with the key of PEM file, or with the file content as a secret for HMAC.
+    pub fn from_key(
+        path: &Path,
+        algorithm: Algorithm,
+        audience: &str,
+        issuer: &str,
+    ) -> anyhow::Result<Self> {
+        let content = std::fs::read(path)
+            .with_context(|| format!("failed to read jwt key from {:?}", path))?;
+        let key = match algorithm {
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => {
+                DecodingKey::from_secret(content.trim_ascii())
+            }
+            Algorithm::RS256
+            | Algorithm::RS384
+            | Algorithm::RS512
+            | Algorithm::PS256
+            | Algorithm::PS384
+            | Algorithm::PS512 => DecodingKey::from_rsa_pem(&content)?,
+            Algorithm::ES256 | Algorithm::ES384 => DecodingKey::from_ec_pem(&content)?,
+            Algorithm::EdDSA => DecodingKey::from_ed_pem(&content)?,
+        };
+
+        Ok(Self::new(JwtKeys::Static(key), algorithm, audience, issuer))
+    }
+
+    /// Verify tokens with the key of JWKS file which has the same id as the token.
+    pub fn from_jwks(
+        path: &Path,
+        algorithm: Algorithm,
+        audience: &str,
+        issuer: &str,
+    ) -> anyhow::Result<Self> {
+        let content =
+            std::fs::read(path).with_context(|| format!("failed to read jwks from {:?}", path))?;
+        let jwks: JwkSet = serde_json::from_slice(&content)?;
+
+        let mut keys = HashMap::new();
+        for jwk in &jwks.keys {
+            let id = jwk
+                .common
+                .key_id
+                .clone()
+                .ok_or_else(|| anyhow!("every key of jwks must have 'kid'"))?;
+            keys.insert(id, DecodingKey::from_jwk(jwk)?);
+        }
+
+        Ok(Self::new(JwtKeys::Set(keys), algorithm, audience, issuer))
+    }
+
+    fn new(keys: JwtKeys, algorithm: Algorithm, audience: &str, issuer: &str) -> Self {
+        // tokens of other algorithms are refused, so a public key is never used as a secret
+        let mut validation = Validation::new(algorithm);
+        validation.set_audience(&[audience]);
+        validation.set_issuer(&[issuer]);
+        validation.set_required_spec_claims(&["exp", "aud", "iss", "sub"]);
+
+        Self { keys, validation }
+    }
+
+    fn verify(&self, token: &str) -> Result<Claims, Error> {
+        let key = match &self.keys {
+            JwtKeys::Static(key) => key,
+            JwtKeys::Set(keys) => {
+                let header = decode_header(token).map_err(|_| Error::Unauthorized)?;
+                header
+                    .kid
+                    .and_then(|id| keys.get(&id))
+                    .ok_or(Error::Unauthorized)?
+            }
+        };
+
+        decode::<Claims>(token, key, &self.validation)
+            .map(|data| data.claims)
+            .map_err(|e| {
+                debug!("invalid jwt: {}", e);
+                Error::Unauthorized
+            })
+    }
+}
+
+/// Let the request through if it has `X-Api-Key` header with a key or `Authorization: Bearer`
+/// header with a JWT, whichever are enabled. The key (token) must have the scope and the key
+/// must be within its rate limit. The client is put into extensions of the request.
+pub async fn authenticate<R, C>(
+    State(state): State<AppState<R, C>>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, Error>
+where
+    R: StoreApiKey + Clone,
+    C: Clone,
+{
+    let scope = required_scope(&request);
+    let headers = request.headers();
+    let key = headers
+        .get(API_KEY_HEADER)
+        .filter(|_| state.require_api_key);
+    let token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .zip(state.jwt.as_ref());
+
+    let client = match (key, token) {
+        (Some(key), _) => {
+            let key = state
+                .repo
+                .get_api_key(&hash_api_key(key.as_bytes()))
+                .await?
+                .ok_or(Error::Unauthorized)?;
+            trace!(api_key = key.name, %scope, "authenticate request");
+            if !key.scopes.contains(&scope) {
+                return Err(Error::Forbidden(format!(
+                    "api key has no '{}' scope",
+                    scope
+                )));
+            }
+            // the database allows positive limits only, anything else is a broken key
+            let rate_limit = key
+                .rate_limit
+                .map(|limit| {
+                    u32::try_from(limit)
+                        .ok()
+                        .filter(|limit| *limit > 0)
+                        .ok_or_else(|| {
+                            anyhow!("invalid rate limit {} of api key {}", limit, key.id)
+                        })
+                })
+                .transpose()?;
+            Client {
+                name: key.name,
+                customer_id: None,
+                bucket: format!("key:{}", key.id),
+                rate_limit,
+            }
+        }
+        (None, Some((token, jwt))) => {
+            let claims = jwt.verify(token)?;
+            trace!(sub = claims.sub, %scope, "authenticate request");
+            let required = scope.to_string();
+            let has_scope = claims
+                .scope
+                .split_whitespace()
+                .any(|granted| granted == required);
+            if !has_scope {
+                return Err(Error::Forbidden(format!("token has no '{}' scope", scope)));
+            }
+            if claims.customer_id.is_some() && scope != ApiScope::OrdersRead {
+                return Err(Error::Forbidden(
+                    "token of a customer may only read its orders".to_owned(),
+                ));
+            }
+            Client {
+                bucket: format!("jwt:{}", claims.sub),
+                name: claims.sub,
+                customer_id: claims.customer_id,
+                rate_limit: None,
+            }
+        }
+        (None, None) => return Err(Error::Unauthorized),
+    };
+
+    request.extensions_mut().insert(client);
+    Ok(next.run(request).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::{json, Value};
+
+    const SECRET: &str = "secret";
+
+    fn token(kid: Option<&str>, claims: Value) -> String {
+        let header = Header {
+            kid: kid.map(str::to_owned),
+            ..Header::new(Algorithm::HS256)
+        };
+        encode(
+            &header,
+            &claims,
+            &EncodingKey::from_secret(SECRET.as_bytes()),
+        )
+        .unwrap()
+    }
+
+    fn claims(aud: &str, iss: &str) -> Value {
+        json!({
+            "sub": "billing",
+            "aud": aud,
+            "iss": iss,
+            "exp": chrono::Utc::now().timestamp() + 60,
+            "scope": "orders:read",
+            "customer_id": "test",
+        })
+    }
+
+    fn write(name: &str, content: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("{}-{}", std::process::id(), name));
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn verify_token_with_static_key() {
+        let path = write("jwt.key", &format!("{}\n", SECRET));
+        let jwt = JwtVerifier::from_key(&path, Algorithm::HS256, "orders", "idp").unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let verified = jwt.verify(&token(None, claims("orders", "idp"))).unwrap();
+        assert_eq!(verified.sub, "billing");
+        assert_eq!(verified.scope, "orders:read");
+        assert_eq!(verified.customer_id.as_deref(), Some("test"));
+
+        for invalid in [
+            token(None, claims("payments", "idp")),
+            token(None, claims("orders", "other")),
+            token(
+                None,
+                json!({"sub": "billing", "aud": "orders", "iss": "idp"}),
+            ),
+            "not a token".to_owned(),
+        ] {
+            assert!(matches!(jwt.verify(&invalid), Err(Error::Unauthorized)));
+        }
+    }
+
+    #[test]
+    fn verify_token_with_key_of_jwks() {
+        // `k` is base64url of the secret
+        let path = write(
+            "jwks.json",
+            r#"{"keys": [{"kty": "oct", "kid": "first", "k": "c2VjcmV0"}]}"#,
+        );
+        let jwt = JwtVerifier::from_jwks(&path, Algorithm::HS256, "orders", "idp").unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(jwt
+            .verify(&token(Some("first"), claims("orders", "idp")))
+            .is_ok());
+        for kid in [None, Some("second")] {
+            assert!(matches!(
+                jwt.verify(&token(kid, claims("orders", "idp"))),
+                Err(Error::Unauthorized)
+            ));
+        }
+    }
+}
@@ -1,37 +1,221 @@
+pub use self::{connection::RedisTopology, layered::LayeredCache, memory::MemoryCache};
+
+use std::{
+    sync::{Arc, LazyLock},
+    time::Duration,
+};
+
+use anyhow::anyhow;
 use bb8::Pool;
-use bb8_redis::RedisConnectionManager;
-use redis::{AsyncCommands, IntoConnectionInfo};
-use tracing::debug;
+use futures_util::StreamExt;
+use redis::{AsyncCommands, Script};
+use tracing::{debug, trace, warn};
+
+use self::connection::RedisManager;
 
-use crate::{error::Error, model::Order, state::CacheOrder};
+use crate::{
+    codec::{Codec, CodecError},
+    error::Error,
+    model::Order,
+    state::CacheOrder,
+};
+
+mod connection;
+mod layered;
+mod memory;
+
+const INVALIDATION_CHANNEL: &str = "orders:invalidate";
 
 fn get_order_key(order_id: &str) -> String {
     format!("order{}", order_id)
 }
 
+fn get_rate_limit_key(bucket: &str) -> String {
+    format!("ratelimit:{}", bucket)
+}
+
+/// Token bucket which holds `ARGV[1]` tokens and is refilled at that rate per minute.
+/// It's timed by the clock of Redis, so clocks of instances don't matter.
+/// Returns milliseconds to wait for a token, `0` if it's taken.
+static TAKE_TOKEN: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r"
+        local capacity = tonumber(ARGV[1])
+        local per_ms = capacity / 60000
+        local time = redis.call('TIME')
+        local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+
+        local bucket = redis.call('HMGET', KEYS[1], 'tokens', 'updated_at')
+        local tokens = tonumber(bucket[1]) or capacity
+        local updated_at = tonumber(bucket[2]) or now
+        tokens = math.min(capacity, tokens + math.max(0, now - updated_at) * per_ms)
+
+        local wait = 0
+        if tokens >= 1 then
+            tokens = tokens - 1
+        else
+            wait = math.ceil((1 - tokens) / per_ms)
+        end
+
+        redis.call('HSET', KEYS[1], 'tokens', tostring(tokens), 'updated_at', now)
+        -- the bucket is full again by then
+        redis.call('PEXPIRE', KEYS[1], 60000)
+        return wait
+        ",
+    )
+});
+
 #[derive(Clone)]
 pub struct RedisCache {
-    pool: Pool<RedisConnectionManager>,
+    pool: Pool<RedisManager>,
+    codec: Codec,
+    topology: Arc<RedisTopology>,
 }
 
 impl RedisCache {
-    pub async fn try_new(params: &str) -> Result<Self, redis::RedisError> {
-        debug!(cache = "redis", "configure with params: {}", params);
+    pub async fn try_new(
+        topology: &RedisTopology,
+        codec: Codec,
+    ) -> Result<Self, redis::RedisError> {
+        debug!(
+            cache = "redis",
+            ?codec,
+            "configure with topology: {:?}",
+            topology
+        );
 
-        let config = params.into_connection_info()?;
-        let manager = RedisConnectionManager::new(config)?;
+        let manager = RedisManager::new(topology)?;
         let pool = Pool::builder().build(manager).await?;
 
-        Ok(Self { pool })
+        Ok(Self {
+            pool,
+            codec,
+            topology: Arc::new(topology.clone()),
+        })
+    }
+
+    /// Evict orders from `l1` once any instance removes them from Redis.
+    /// Subscription is restored on failure, `l1` is cleared then as messages might be lost.
+    pub fn listen_invalidations(&self, l1: MemoryCache) {
+        let topology = self.topology.clone();
+
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = listen(&topology, &l1).await {
+                    warn!(cache = "redis", "invalidation subscription failed: {}", e);
+                }
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        });
+
+        async fn listen(topology: &RedisTopology, l1: &MemoryCache) -> Result<(), Error> {
+            let client = RedisManager::new(topology)?.master_client(topology).await?;
+            let mut pubsub = client.get_async_pubsub().await?;
+            pubsub.subscribe(INVALIDATION_CHANNEL).await?;
+            l1.clear();
+
+            debug!(cache = "redis", "subscribed to {}", INVALIDATION_CHANNEL);
+            let mut messages = pubsub.on_message();
+            while let Some(message) = messages.next().await {
+                let order_id: String = message.get_payload()?;
+                l1.remove_order(&order_id).await?;
+            }
+
+            Err(anyhow!("subscription to {} is closed", INVALIDATION_CHANNEL).into())
+        }
+    }
+
+    /// Take a token out of the bucket shared by all instances, see [`RateLimiter`].
+    /// Returns how long to wait for a token if there is none.
+    ///
+    /// [`RateLimiter`]: crate::limit::RateLimiter
+    pub async fn take_token(
+        &self,
+        bucket: &str,
+        per_minute: u32,
+    ) -> Result<Option<Duration>, Error> {
+        let key = get_rate_limit_key(bucket);
+
+        trace!(cache = "redis", per_minute, "take token by key: {}", key);
+        let wait_ms: u64 = TAKE_TOKEN
+            .key(key)
+            .arg(per_minute)
+            .invoke_async(&mut *self.pool.get().await?)
+            .await?;
+
+        Ok((wait_ms > 0).then(|| Duration::from_millis(wait_ms)))
+    }
+
+    async fn get_decoded<T>(
+        &self,
+        order_id: &str,
+        decode: impl FnOnce(&Codec, &[u8]) -> Result<Option<T>, CodecError>,
+    ) -> Result<Option<T>, Error> {
+        let key = get_order_key(order_id);
+
+        debug!(cache = "redis", "get order by key: {}", key);
+        let maybe_bytes: Option<Vec<u8>> = self.pool.get().await?.get(&key).await?;
+
+        Ok(maybe_bytes.and_then(|bytes| self.decode_entry(&key, &bytes, decode)))
+    }
+
+    /// Stale or broken entry is just a cache miss, it will be overwritten.
+    fn decode_entry<T>(
+        &self,
+        key: &str,
+        bytes: &[u8],
+        decode: impl FnOnce(&Codec, &[u8]) -> Result<Option<T>, CodecError>,
+    ) -> Option<T> {
+        match decode(&self.codec, bytes) {
+            Ok(Some(value)) => Some(value),
+            Ok(None) => {
+                debug!(
+                    cache = "redis",
+                    "skip entry of another codec by key: {}", key
+                );
+                None
+            }
+            Err(e) => {
+                warn!(
+                    cache = "redis",
+                    "failed to decode entry by key {}: {}", key, e
+                );
+                None
+            }
+        }
     }
 }
 
 impl CacheOrder for RedisCache {
     async fn get_order(&self, order_id: &str) -> Result<Option<Order>, Error> {
-        let key = get_order_key(order_id);
+        self.get_decoded(order_id, |codec, bytes| codec.decode(bytes))
+            .await
+    }
 
-        debug!(cache = "redis", "get order by key: {}", key);
-        Ok(self.pool.get().await?.get(key).await?)
+    async fn get_order_json(&self, order_id: &str) -> Result<Option<Vec<u8>>, Error> {
+        self.get_decoded(order_id, |codec, bytes| codec.decode_json::<Order>(bytes))
+            .await
+    }
+
+    async fn get_orders(&self, order_ids: &[String]) -> Result<Vec<Option<Order>>, Error> {
+        let keys: Vec<String> = order_ids.iter().map(|id| get_order_key(id)).collect();
+
+        debug!(cache = "redis", "get orders by keys: {:?}", keys);
+        // `mget` would send GET for a single key, which returns no array
+        let entries: Vec<Option<Vec<u8>>> = redis::cmd("MGET")
+            .arg(&keys)
+            .query_async(&mut *self.pool.get().await?)
+            .await?;
+
+        Ok(keys
+            .iter()
+            .zip(entries)
+            .map(|(key, maybe_bytes)| {
+                maybe_bytes.and_then(|bytes| {
+                    self.decode_entry(key, &bytes, |codec, bytes| codec.decode(bytes))
+                })
+            })
+            .collect())
     }
 
     async fn insert_order(&self, order: &Order) -> Result<(), Error> {
@@ -46,6 +230,17 @@ impl CacheOrder for RedisCache {
             "insert order with key: {:?}",
             key
         );
-        Ok(self.pool.get().await?.set_ex(key, order, secs).await?)
+        let bytes = self.codec.encode(order)?;
+        Ok(self.pool.get().await?.set_ex(key, bytes, secs).await?)
+    }
+
+    async fn remove_order(&self, order_id: &str) -> Result<(), Error> {
+        let key = get_order_key(order_id);
+
+        debug!(cache = "redis", "remove order by key: {}", key);
+        let mut conn = self.pool.get().await?;
+        conn.del::<_, ()>(key).await?;
+        // let other instances evict the order from their in-process caches
+        Ok(conn.publish(INVALIDATION_CHANNEL, order_id).await?)
     }
 }
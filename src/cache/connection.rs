@@ -0,0 +1,186 @@
+use async_trait::async_trait;
+use bb8::ManageConnection;
+use bb8_redis::RedisConnectionManager;
+use redis::{
+    aio::{ConnectionLike, MultiplexedConnection},
+    cluster::ClusterClient,
+    cluster_async::ClusterConnection,
+    sentinel::Sentinel,
+    Client, Cmd, ErrorKind, Pipeline, RedisError, RedisFuture, Value,
+};
+use tokio::sync::Mutex;
+use tracing::{debug, warn};
+
+/// Where Redis lives and how to reach it.
+#[derive(Clone, Debug)]
+pub enum RedisTopology {
+    /// Standalone server.
+    Single(String),
+    /// Master of `master_name` which is looked up via sentinels.
+    Sentinel {
+        master_name: String,
+        nodes: Vec<String>,
+    },
+    /// Cluster with any subset of its nodes as seeds.
+    Cluster(Vec<String>),
+}
+
+pub enum RedisManager {
+    Single(RedisConnectionManager),
+    Sentinel {
+        master_name: String,
+        sentinel: Mutex<Sentinel>,
+    },
+    Cluster(ClusterClient),
+}
+
+impl RedisManager {
+    pub fn new(topology: &RedisTopology) -> Result<Self, RedisError> {
+        Ok(match topology {
+            RedisTopology::Single(params) => {
+                Self::Single(RedisConnectionManager::new(params.as_str())?)
+            }
+            RedisTopology::Sentinel { master_name, nodes } => Self::Sentinel {
+                master_name: master_name.clone(),
+                sentinel: Mutex::new(Sentinel::build(nodes.clone())?),
+            },
+            RedisTopology::Cluster(nodes) => Self::Cluster(ClusterClient::new(nodes.clone())?),
+        })
+    }
+
+    /// Get client of a node which accepts writes at the moment.
+    /// Cluster nodes forward PUBLISH to each other, so the first seed node which answers fits.
+    pub async fn master_client(&self, topology: &RedisTopology) -> Result<Client, RedisError> {
+        match (self, topology) {
+            (
+                Self::Sentinel {
+                    master_name,
+                    sentinel,
+                },
+                _,
+            ) => {
+                sentinel
+                    .lock()
+                    .await
+                    .async_master_for(master_name, None)
+                    .await
+            }
+            (_, RedisTopology::Single(params)) => Client::open(params.as_str()),
+            (_, RedisTopology::Cluster(nodes)) => {
+                let mut last_error = None;
+                for node in nodes {
+                    match reachable_client(node).await {
+                        Ok(client) => return Ok(client),
+                        Err(e) => {
+                            warn!(
+                                cache = "redis",
+                                "cluster node {} is unreachable: {}", node, e
+                            );
+                            last_error = Some(e);
+                        }
+                    }
+                }
+                Err(last_error
+                    .unwrap_or_else(|| (ErrorKind::InvalidClientConfig, "no cluster nodes").into()))
+            }
+            (_, RedisTopology::Sentinel { .. }) => unreachable!("manager is built from topology"),
+        }
+    }
+}
+
+async fn reachable_client(node: &str) -> Result<Client, RedisError> {
+    let client = Client::open(node)?;
+    let mut conn = client.get_multiplexed_async_connection().await?;
+    let _: String = redis::cmd("PING").query_async(&mut conn).await?;
+    Ok(client)
+}
+
+pub enum RedisConnection {
+    Node(MultiplexedConnection),
+    Cluster(ClusterConnection),
+}
+
+#[async_trait]
+impl ManageConnection for RedisManager {
+    type Connection = RedisConnection;
+    type Error = RedisError;
+
+    async fn connect(&self) -> Result<Self::Connection, Self::Error> {
+        Ok(match self {
+            Self::Single(manager) => RedisConnection::Node(manager.connect().await?),
+            Self::Sentinel {
+                master_name,
+                sentinel,
+            } => {
+                // master is resolved on every connect, so failovers are followed
+                let client = sentinel
+                    .lock()
+                    .await
+                    .async_master_for(master_name, None)
+                    .await?;
+                debug!(
+                    cache = "redis",
+                    "connect to master '{}': {:?}",
+                    master_name,
+                    client.get_connection_info().addr
+                );
+
+                RedisConnection::Node(client.get_multiplexed_async_connection().await?)
+            }
+            Self::Cluster(client) => RedisConnection::Cluster(client.get_async_connection().await?),
+        })
+    }
+
+    async fn is_valid(&self, conn: &mut Self::Connection) -> Result<(), Self::Error> {
+        match (self, conn) {
+            (Self::Single(manager), RedisConnection::Node(conn)) => manager.is_valid(conn).await,
+            (Self::Sentinel { .. }, conn) => {
+                // demoted master still answers PING, so check its role instead
+                let role: Vec<Value> = redis::cmd("ROLE").query_async(conn).await?;
+                match role.first() {
+                    Some(Value::BulkString(role)) if role == b"master" => Ok(()),
+                    _ => Err((ErrorKind::ReadOnly, "node is not a master anymore").into()),
+                }
+            }
+            (_, conn) => {
+                let pong: String = redis::cmd("PING").query_async(conn).await?;
+                match pong.as_str() {
+                    "PONG" => Ok(()),
+                    _ => Err((ErrorKind::ResponseError, "ping request").into()),
+                }
+            }
+        }
+    }
+
+    fn has_broken(&self, _: &mut Self::Connection) -> bool {
+        false
+    }
+}
+
+impl ConnectionLike for RedisConnection {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        match self {
+            Self::Node(conn) => conn.req_packed_command(cmd),
+            Self::Cluster(conn) => conn.req_packed_command(cmd),
+        }
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        cmd: &'a Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        match self {
+            Self::Node(conn) => conn.req_packed_commands(cmd, offset, count),
+            Self::Cluster(conn) => conn.req_packed_commands(cmd, offset, count),
+        }
+    }
+
+    fn get_db(&self) -> i64 {
+        match self {
+            Self::Node(conn) => conn.get_db(),
+            Self::Cluster(conn) => conn.get_db(),
+        }
+    }
+}
@@ -0,0 +1,155 @@
+use tracing::trace;
+
+use crate::{error::Error, model::Order, state::CacheOrder};
+
+/// Checks fast `L1` first and falls back to shared `L2`.
+/// Orders found in `L2` are put into `L1`.
+#[derive(Clone)]
+pub struct LayeredCache<L1, L2> {
+    l1: L1,
+    l2: L2,
+}
+
+impl<L1, L2> LayeredCache<L1, L2> {
+    pub fn new(l1: L1, l2: L2) -> Self {
+        Self { l1, l2 }
+    }
+}
+
+impl<L1, L2> CacheOrder for LayeredCache<L1, L2>
+where
+    L1: CacheOrder + Sync,
+    L2: CacheOrder + Sync,
+{
+    async fn get_order(&self, order_id: &str) -> Result<Option<Order>, Error> {
+        if let Some(order) = self.l1.get_order(order_id).await? {
+            return Ok(Some(order));
+        }
+
+        let version = self.l1.version();
+        let maybe_order = self.l2.get_order(order_id).await?;
+        if let Some(order) = &maybe_order {
+            trace!(order_id, "populate l1 from l2");
+            self.l1.fill_order(order, version).await?;
+        }
+
+        Ok(maybe_order)
+    }
+
+    async fn get_order_json(&self, order_id: &str) -> Result<Option<Vec<u8>>, Error> {
+        if let Some(json) = self.l1.get_order_json(order_id).await? {
+            return Ok(Some(json));
+        }
+
+        // L1 needs the whole order, so there is no fast path in L2
+        match self.get_order(order_id).await? {
+            Some(order) => Ok(Some(
+                serde_json::to_vec(&order).map_err(anyhow::Error::from)?,
+            )),
+            None => Ok(None),
+        }
+    }
+
+    async fn get_orders(&self, order_ids: &[String]) -> Result<Vec<Option<Order>>, Error> {
+        let mut orders = self.l1.get_orders(order_ids).await?;
+
+        let (positions, misses): (Vec<_>, Vec<_>) = orders
+            .iter()
+            .zip(order_ids)
+            .enumerate()
+            .filter(|(_, (order, _))| order.is_none())
+            .map(|(position, (_, order_id))| (position, order_id.clone()))
+            .unzip();
+        if misses.is_empty() {
+            return Ok(orders);
+        }
+
+        let version = self.l1.version();
+        for (position, maybe_order) in positions
+            .into_iter()
+            .zip(self.l2.get_orders(&misses).await?)
+        {
+            if let Some(order) = &maybe_order {
+                trace!(order_id = order.order_uid, "populate l1 from l2");
+                self.l1.fill_order(order, version).await?;
+            }
+            orders[position] = maybe_order;
+        }
+
+        Ok(orders)
+    }
+
+    async fn insert_order(&self, order: &Order) -> Result<(), Error> {
+        self.l2.insert_order(order).await?;
+        self.l1.insert_order(order).await
+    }
+
+    async fn remove_order(&self, order_id: &str) -> Result<(), Error> {
+        self.l2.remove_order(order_id).await?;
+        self.l1.remove_order(order_id).await
+    }
+
+    /// Only `L1` learns about invalidations made by other instances.
+    fn version(&self) -> u64 {
+        self.l1.version()
+    }
+
+    async fn fill_order(&self, order: &Order, version: u64) -> Result<(), Error> {
+        self.l2.insert_order(order).await?;
+        self.l1.fill_order(order, version).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{num::NonZeroUsize, time::Duration};
+
+    use crate::{cache::MemoryCache, model::demo_order};
+
+    fn memory() -> MemoryCache {
+        MemoryCache::new(NonZeroUsize::new(8).unwrap(), Duration::from_secs(60))
+    }
+
+    #[tokio::test]
+    async fn populate_l1_from_l2() {
+        let (l1, l2) = (memory(), memory());
+        l2.insert_order(&demo_order("a", 0)).await.unwrap();
+
+        let cache = LayeredCache::new(l1.clone(), l2);
+
+        assert_eq!(
+            cache.get_order_json("a").await.unwrap(),
+            Some(serde_json::to_vec(&demo_order("a", 0)).unwrap())
+        );
+        assert_eq!(l1.get_order("a").await.unwrap(), Some(demo_order("a", 0)));
+    }
+
+    #[tokio::test]
+    async fn get_orders_from_both_levels() {
+        let (l1, l2) = (memory(), memory());
+        l1.insert_order(&demo_order("a", 0)).await.unwrap();
+        l2.insert_order(&demo_order("b", 0)).await.unwrap();
+
+        let cache = LayeredCache::new(l1.clone(), l2);
+
+        let ids = ["a", "b", "c"].map(String::from);
+        assert_eq!(
+            cache.get_orders(&ids).await.unwrap(),
+            vec![Some(demo_order("a", 0)), Some(demo_order("b", 0)), None]
+        );
+        assert_eq!(l1.get_order("b").await.unwrap(), Some(demo_order("b", 0)));
+    }
+
+    #[tokio::test]
+    async fn remove_from_both_levels() {
+        let (l1, l2) = (memory(), memory());
+        let cache = LayeredCache::new(l1.clone(), l2.clone());
+        cache.insert_order(&demo_order("a", 0)).await.unwrap();
+        cache.remove_order("a").await.unwrap();
+
+        assert_eq!(l1.get_order("a").await.unwrap(), None);
+        assert_eq!(l2.get_order("a").await.unwrap(), None);
+    }
+}
@@ -0,0 +1,279 @@
+use std::{
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use lru::LruCache;
+use tracing::debug;
+
+use crate::{error::Error, model::Order, state::CacheOrder};
+
+struct Entry {
+    order: Order,
+    json: Vec<u8>,
+    expires_at: Instant,
+}
+
+struct Entries {
+    orders: LruCache<String, Entry>,
+    /// Incremented by every removal.
+    version: u64,
+    /// Versions of recent removals, so fills which raced with them are dropped.
+    removals: LruCache<String, u64>,
+    /// Latest version which isn't tracked in `removals` anymore.
+    forgotten: u64,
+}
+
+/// Small in-process cache which forgets least recently used orders.
+#[derive(Clone)]
+pub struct MemoryCache {
+    entries: Arc<Mutex<Entries>>,
+    ttl: Duration,
+}
+
+impl MemoryCache {
+    pub fn new(capacity: NonZeroUsize, ttl: Duration) -> Self {
+        debug!(cache = "memory", capacity, ?ttl, "configure");
+
+        Self {
+            entries: Arc::new(Mutex::new(Entries {
+                orders: LruCache::new(capacity),
+                version: 0,
+                removals: LruCache::new(capacity),
+                forgotten: 0,
+            })),
+            ttl,
+        }
+    }
+
+    /// Forget all orders, e.g. when invalidations might have been missed.
+    pub fn clear(&self) {
+        debug!(cache = "memory", "clear");
+        let mut entries = self.entries.lock().expect("poisoned lock");
+        entries.orders.clear();
+        entries.removals.clear();
+        entries.version += 1;
+        entries.forgotten = entries.version;
+    }
+
+    fn get<T>(&self, order_id: &str, map: impl FnOnce(&Entry) -> T) -> Option<T> {
+        let mut entries = self.entries.lock().expect("poisoned lock");
+
+        match entries.orders.get(order_id) {
+            Some(entry) if entry.expires_at > Instant::now() => Some(map(entry)),
+            Some(_) => {
+                entries.orders.pop(order_id);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn entry(&self, order: &Order) -> Result<Entry, Error> {
+        Ok(Entry {
+            order: order.clone(),
+            json: serde_json::to_vec(order).map_err(anyhow::Error::from)?,
+            expires_at: Instant::now() + self.ttl,
+        })
+    }
+}
+
+impl CacheOrder for MemoryCache {
+    async fn get_order(&self, order_id: &str) -> Result<Option<Order>, Error> {
+        debug!(cache = "memory", "get order by key: {}", order_id);
+        Ok(self.get(order_id, |entry| entry.order.clone()))
+    }
+
+    async fn get_order_json(&self, order_id: &str) -> Result<Option<Vec<u8>>, Error> {
+        debug!(cache = "memory", "get order body by key: {}", order_id);
+        Ok(self.get(order_id, |entry| entry.json.clone()))
+    }
+
+    async fn get_orders(&self, order_ids: &[String]) -> Result<Vec<Option<Order>>, Error> {
+        debug!(cache = "memory", "get orders by keys: {:?}", order_ids);
+
+        Ok(order_ids
+            .iter()
+            .map(|order_id| self.get(order_id, |entry| entry.order.clone()))
+            .collect())
+    }
+
+    async fn insert_order(&self, order: &Order) -> Result<(), Error> {
+        debug!(
+            cache = "memory",
+            "insert order with key: {}", order.order_uid
+        );
+
+        let entry = self.entry(order)?;
+        self.entries
+            .lock()
+            .expect("poisoned lock")
+            .orders
+            .put(order.order_uid.clone(), entry);
+
+        Ok(())
+    }
+
+    async fn remove_order(&self, order_id: &str) -> Result<(), Error> {
+        debug!(cache = "memory", "remove order by key: {}", order_id);
+        let mut entries = self.entries.lock().expect("poisoned lock");
+        entries.orders.pop(order_id);
+
+        entries.version += 1;
+        let version = entries.version;
+        if let Some((evicted, version)) = entries.removals.push(order_id.to_string(), version) {
+            if evicted != order_id {
+                entries.forgotten = entries.forgotten.max(version);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn version(&self) -> u64 {
+        self.entries.lock().expect("poisoned lock").version
+    }
+
+    async fn fill_order(&self, order: &Order, version: u64) -> Result<(), Error> {
+        let entry = self.entry(order)?;
+        let mut entries = self.entries.lock().expect("poisoned lock");
+
+        let removed = entries.removals.peek(&order.order_uid).copied();
+        if entries.forgotten > version || removed.is_some_and(|removed| removed > version) {
+            debug!(
+                cache = "memory",
+                "skip order removed since read with key: {}", order.order_uid
+            );
+            return Ok(());
+        }
+
+        debug!(cache = "memory", "fill order with key: {}", order.order_uid);
+        entries.orders.put(order.order_uid.clone(), entry);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::model::demo_order;
+
+    fn cache(capacity: usize, ttl: Duration) -> MemoryCache {
+        MemoryCache::new(NonZeroUsize::new(capacity).unwrap(), ttl)
+    }
+
+    #[tokio::test]
+    async fn get_inserted_order() {
+        let cache = cache(1, Duration::from_secs(60));
+        cache.insert_order(&demo_order("a", 0)).await.unwrap();
+
+        assert_eq!(
+            cache.get_order("a").await.unwrap(),
+            Some(demo_order("a", 0))
+        );
+        assert_eq!(
+            cache.get_order_json("a").await.unwrap(),
+            Some(serde_json::to_vec(&demo_order("a", 0)).unwrap())
+        );
+        assert_eq!(cache.get_order("b").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn get_inserted_orders() {
+        let cache = cache(2, Duration::from_secs(60));
+        cache.insert_order(&demo_order("a", 0)).await.unwrap();
+        cache.insert_order(&demo_order("c", 0)).await.unwrap();
+
+        let ids = ["a", "b", "c"].map(String::from);
+        assert_eq!(
+            cache.get_orders(&ids).await.unwrap(),
+            vec![Some(demo_order("a", 0)), None, Some(demo_order("c", 0))]
+        );
+    }
+
+    #[tokio::test]
+    async fn forget_expired_order() {
+        let cache = cache(1, Duration::ZERO);
+        cache.insert_order(&demo_order("a", 0)).await.unwrap();
+
+        assert_eq!(cache.get_order("a").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn forget_least_recently_used_order() {
+        let cache = cache(2, Duration::from_secs(60));
+        cache.insert_order(&demo_order("a", 0)).await.unwrap();
+        cache.insert_order(&demo_order("b", 0)).await.unwrap();
+        cache.get_order("a").await.unwrap();
+        cache.insert_order(&demo_order("c", 0)).await.unwrap();
+
+        assert!(cache.get_order("a").await.unwrap().is_some());
+        assert!(cache.get_order("b").await.unwrap().is_none());
+        assert!(cache.get_order("c").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn skip_fill_removed_since_read() {
+        let cache = cache(2, Duration::from_secs(60));
+        let version = cache.version();
+        cache.remove_order("a").await.unwrap();
+        cache
+            .fill_order(&demo_order("a", 0), version)
+            .await
+            .unwrap();
+        cache
+            .fill_order(&demo_order("b", 0), version)
+            .await
+            .unwrap();
+
+        assert_eq!(cache.get_order("a").await.unwrap(), None);
+        assert_eq!(
+            cache.get_order("b").await.unwrap(),
+            Some(demo_order("b", 0))
+        );
+
+        cache
+            .fill_order(&demo_order("a", 0), cache.version())
+            .await
+            .unwrap();
+        assert_eq!(
+            cache.get_order("a").await.unwrap(),
+            Some(demo_order("a", 0))
+        );
+    }
+
+    #[tokio::test]
+    async fn skip_fill_once_removal_is_forgotten() {
+        let cache = cache(1, Duration::from_secs(60));
+        let version = cache.version();
+        cache.remove_order("a").await.unwrap();
+        cache.remove_order("b").await.unwrap();
+        cache
+            .fill_order(&demo_order("a", 0), version)
+            .await
+            .unwrap();
+
+        assert_eq!(cache.get_order("a").await.unwrap(), None);
+
+        let version = cache.version();
+        cache.clear();
+        cache
+            .fill_order(&demo_order("b", 0), version)
+            .await
+            .unwrap();
+
+        assert_eq!(cache.get_order("b").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn forget_removed_order() {
+        let cache = cache(1, Duration::from_secs(60));
+        cache.insert_order(&demo_order("a", 0)).await.unwrap();
+        cache.remove_order("a").await.unwrap();
+
+        assert_eq!(cache.get_order("a").await.unwrap(), None);
+    }
+}
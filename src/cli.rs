@@ -1,9 +1,90 @@
-use std::net::Ipv4Addr;
+use std::{net::Ipv4Addr, num::NonZeroUsize, path::PathBuf};
 
-use clap::builder::NonEmptyStringValueParser;
+use clap::builder::{NonEmptyStringValueParser, RangedU64ValueParser};
+use jsonwebtoken::Algorithm;
+
+use crate::{
+    codec::Format, model::ApiScope, outbox::Relay, repo::PostgresRepo,
+    state::DEFAULT_MAX_BODY_SIZE, tls::Reloader,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+pub enum CacheMode {
+    Single,
+    Sentinel,
+    Cluster,
+}
+
+#[derive(clap::Subcommand)]
+pub enum Command {
+    /// Import orders from NDJSON file instead of serving API
+    Import {
+        /// File with an order per line
+        path: PathBuf,
+
+        /// Number of orders imported in a single transaction
+        #[clap(long, default_value = "1000")]
+        chunk_size: NonZeroUsize,
+    },
+
+    /// Manage API keys of clients instead of serving API
+    ApiKey {
+        #[clap(subcommand)]
+        command: ApiKeyCommand,
+    },
+
+    /// Consume orders from Kafka topic as a member of consumer group instead of serving API
+    #[cfg(feature = "kafka")]
+    Consume {
+        /// Comma separated list of brokers, e.g. `localhost:9092`
+        #[clap(long, env = "WBTECH_L0_DEMO_KAFKA_BROKERS")]
+        brokers: String,
+
+        /// Consumer group, its members share partitions of the topic
+        #[clap(long, default_value = "l0-demo")]
+        group: String,
+
+        /// Topic with an order per message
+        #[clap(long, default_value = "orders")]
+        topic: String,
+
+        /// Topic for messages which can't become orders
+        #[clap(long, default_value = "orders-dead-letter")]
+        dead_letter_topic: String,
+    },
+}
+
+#[derive(clap::Subcommand)]
+pub enum ApiKeyCommand {
+    /// Create a key and print it, it can't be shown again
+    Create {
+        /// Name of the client, it's the actor of changes made with the key
+        name: String,
+
+        /// Comma separated list of scopes granted to the key
+        #[clap(long, value_enum, value_delimiter = ',', required = true)]
+        scopes: Vec<ApiScope>,
+
+        /// Requests per minute (optional), the default rate limit applies if this option isn't used
+        #[clap(long, value_parser = clap::value_parser!(i32).range(1..))]
+        rate_limit: Option<i32>,
+    },
+
+    /// Revoke the key of the client, requests with it are refused at once
+    Revoke {
+        /// Name of the client
+        name: String,
+    },
+
+    /// Print all keys including revoked ones, without the keys themselves
+    List,
+}
 
 #[derive(clap::Parser)]
 pub struct Cli {
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+
     /// Listening IP
     #[clap(
         short, 
@@ -23,6 +104,29 @@ pub struct Cli {
     )]
     pub port: u16,
 
+    /// PEM file with the certificate chain of the server (optional).
+    /// HTTPS is served instead of HTTP with it, files are reloaded once they change
+    #[clap(long, requires = "tls_key", env = "WBTECH_L0_DEMO_TLS_CERT")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM file with the private key of the server certificate
+    #[clap(long, requires = "tls_cert", env = "WBTECH_L0_DEMO_TLS_KEY")]
+    pub tls_key: Option<PathBuf>,
+
+    /// PEM file with CA certificates of clients (optional).
+    /// Clients must present a certificate signed by one of them (mutual TLS)
+    #[clap(long, requires = "tls_cert", env = "WBTECH_L0_DEMO_TLS_CLIENT_CA")]
+    pub tls_client_ca: Option<PathBuf>,
+
+    /// How often files of certificates are checked for changes, in seconds
+    #[clap(
+        long,
+        value_parser = clap::value_parser!(u64).range(1..),
+        default_value_t = Reloader::DEFAULT_INTERVAL.as_secs(),
+        env = "WBTECH_L0_DEMO_TLS_RELOAD_INTERVAL",
+    )]
+    pub tls_reload_interval: u64,
+
     /// Current thread scheduler
     #[clap(long, default_value_t = false, group = "runtime")]
     pub current_thread: bool,
@@ -43,12 +147,207 @@ pub struct Cli {
     )]
     pub db_params: String,
 
-    /// Cache configuration string (optional).
+    /// Items of an order are written with COPY if there are at least that many of them,
+    /// no more than 2979
+    #[clap(
+        long,
+        value_parser = RangedU64ValueParser::<usize>::new()
+            .range(1..=PostgresRepo::MAX_COPY_THRESHOLD as u64),
+        default_value_t = PostgresRepo::DEFAULT_COPY_THRESHOLD,
+        env = "WBTECH_L0_DEMO_DB_COPY_THRESHOLD",
+    )]
+    pub db_copy_threshold: usize,
+
+    /// Reuse identical deliveries and keep product attributes of items in a separate table.
+    /// It affects new orders only, API is the same
+    #[clap(long, default_value_t = false, env = "WBTECH_L0_DEMO_DB_NORMALIZED")]
+    pub db_normalized: bool,
+
+    /// URL of the sink order events are published to (optional):
+    /// http(s)://... for webhook, nats://host:port/subject or file:///path/to/file.
+    /// Events are kept in the outbox until an instance with a sink publishes them.
+    #[clap(
+        long,
+        value_parser = NonEmptyStringValueParser::new(),
+        env = "WBTECH_L0_DEMO_OUTBOX_SINK",
+    )]
+    pub outbox_sink: Option<String>,
+
+    /// Number of events published at once
+    #[clap(
+        long,
+        value_parser = clap::value_parser!(i64).range(1..),
+        default_value_t = Relay::<PostgresRepo>::DEFAULT_BATCH_SIZE,
+        env = "WBTECH_L0_DEMO_OUTBOX_BATCH_SIZE",
+    )]
+    pub outbox_batch_size: i64,
+
+    /// Time to wait for new events once all of them are published, in milliseconds
+    #[clap(
+        long,
+        default_value_t = 1000,
+        env = "WBTECH_L0_DEMO_OUTBOX_POLL_INTERVAL"
+    )]
+    pub outbox_poll_interval: u64,
+
+    /// Time events are kept in the outbox, in hours.
+    /// Older events are removed once they are published,
+    /// unless they are still being delivered to webhooks
+    #[clap(
+        long,
+        value_parser = clap::value_parser!(u64).range(1..),
+        default_value_t = 168,
+        env = "WBTECH_L0_DEMO_OUTBOX_RETENTION"
+    )]
+    pub outbox_retention: u64,
+
+    /// Time payloads of rejected orders are kept, in hours
+    #[clap(
+        long,
+        value_parser = clap::value_parser!(u64).range(1..),
+        default_value_t = 720,
+        env = "WBTECH_L0_DEMO_REJECTED_RETENTION"
+    )]
+    pub rejected_retention: u64,
+
+    /// Time to wait for new webhook deliveries once all of them are made, in milliseconds
+    #[clap(
+        long,
+        default_value_t = 1000,
+        env = "WBTECH_L0_DEMO_WEBHOOK_POLL_INTERVAL"
+    )]
+    pub webhook_poll_interval: u64,
+
+    /// Bearer token of the admin, who may read raw payloads of orders and manage webhooks (optional).
+    /// Administrative endpoints are closed if this option isn't used.
+    #[clap(
+        long,
+        value_parser = NonEmptyStringValueParser::new(),
+        env = "WBTECH_L0_DEMO_ADMIN_TOKEN",
+    )]
+    pub admin_token: Option<String>,
+
+    /// Require `X-Api-Key` header with a key which has the scope of the endpoint:
+    /// `orders:read` for reading orders and `orders:write` for changing them.
+    /// Endpoints of orders are open to anyone if this option isn't used
+    #[clap(long, default_value_t = false, env = "WBTECH_L0_DEMO_REQUIRE_API_KEY")]
+    pub require_api_key: bool,
+
+    /// JWKS file with public keys of the identity provider (optional).
+    /// Endpoints of orders accept `Authorization: Bearer` JWT signed with a key of it
+    /// which has the same `kid`; the token scopes are in its `scope` claim
+    #[clap(
+        long,
+        conflicts_with = "jwt_key",
+        requires_all = ["jwt_audience", "jwt_issuer"],
+        env = "WBTECH_L0_DEMO_JWT_JWKS",
+    )]
+    pub jwt_jwks: Option<PathBuf>,
+
+    /// PEM file with the public key of the identity provider, or a file with the secret
+    /// for HMAC algorithms (optional). It's used instead of JWKS
+    #[clap(
+        long,
+        requires_all = ["jwt_audience", "jwt_issuer"],
+        env = "WBTECH_L0_DEMO_JWT_KEY",
+    )]
+    pub jwt_key: Option<PathBuf>,
+
+    /// The only algorithm tokens may be signed with
+    #[clap(long, default_value = "RS256", env = "WBTECH_L0_DEMO_JWT_ALGORITHM")]
+    pub jwt_algorithm: Algorithm,
+
+    /// Audience (`aud` claim) tokens must be issued for
+    #[clap(
+        long,
+        value_parser = NonEmptyStringValueParser::new(),
+        env = "WBTECH_L0_DEMO_JWT_AUDIENCE",
+    )]
+    pub jwt_audience: Option<String>,
+
+    /// Issuer (`iss` claim) of tokens
+    #[clap(
+        long,
+        value_parser = NonEmptyStringValueParser::new(),
+        env = "WBTECH_L0_DEMO_JWT_ISSUER",
+    )]
+    pub jwt_issuer: Option<String>,
+
+    /// Requests per minute of a client (optional). Clients are told apart by API key,
+    /// subject of the token or IP, a rate limit of the API key takes precedence.
+    /// Buckets of clients are kept in Redis if cache is configured
+    #[clap(
+        long,
+        value_parser = clap::value_parser!(u32).range(1..),
+        env = "WBTECH_L0_DEMO_RATE_LIMIT",
+    )]
+    pub rate_limit: Option<u32>,
+
+    /// Requests per minute from an IP (optional), the default rate limit applies if it isn't set.
+    /// They are counted before authentication, so it must allow API keys with higher rate limits
+    #[clap(
+        long,
+        value_parser = clap::value_parser!(u32).range(1..),
+        env = "WBTECH_L0_DEMO_IP_RATE_LIMIT",
+    )]
+    pub ip_rate_limit: Option<u32>,
+
+    /// Limit of request bodies in bytes
+    #[clap(
+        long,
+        default_value_t = DEFAULT_MAX_BODY_SIZE,
+        env = "WBTECH_L0_DEMO_MAX_BODY_SIZE",
+    )]
+    pub max_body_size: usize,
+
+    /// Cache configuration strings separated by comma (optional).
     /// It won't be configured if this option isn't used.
+    /// Sentinel and cluster modes accept several nodes.
     #[clap(
         long, 
         value_parser = NonEmptyStringValueParser::new(),
+        value_delimiter = ',',
         env = "WBTECH_L0_DEMO_CACHE_PARAMS",
     )]
-    pub cache_params: Option<String>,
+    pub cache_params: Vec<String>,
+
+    /// How cache nodes are deployed
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = CacheMode::Single,
+        env = "WBTECH_L0_DEMO_CACHE_MODE",
+    )]
+    pub cache_mode: CacheMode,
+
+    /// Name of the master monitored by sentinels
+    #[clap(
+        long,
+        value_parser = NonEmptyStringValueParser::new(),
+        required_if_eq("cache_mode", "sentinel"),
+        env = "WBTECH_L0_DEMO_CACHE_SENTINEL_MASTER",
+    )]
+    pub cache_sentinel_master: Option<String>,
+
+    /// Format of values stored in cache
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = Format::Json,
+        env = "WBTECH_L0_DEMO_CACHE_CODEC",
+    )]
+    pub cache_codec: Format,
+
+    /// Compress values stored in cache with zstd
+    #[clap(long, default_value_t = false, env = "WBTECH_L0_DEMO_CACHE_COMPRESS")]
+    pub cache_compress: bool,
+
+    /// Capacity of in-process cache in orders (optional).
+    /// It is checked before Redis and won't be configured if this option isn't used.
+    #[clap(long, env = "WBTECH_L0_DEMO_CACHE_L1_CAPACITY")]
+    pub cache_l1_capacity: Option<NonZeroUsize>,
+
+    /// Time to live of orders in in-process cache, in seconds
+    #[clap(long, default_value_t = 10, env = "WBTECH_L0_DEMO_CACHE_L1_TTL")]
+    pub cache_l1_ttl: u64,
 }
@@ -0,0 +1,197 @@
+use std::io;
+
+use serde::{de::DeserializeOwned, Serialize};
+
+// Bump it whenever cached types change their shape,
+// so entries written by older builds are treated as missing.
+const SCHEMA_VERSION: u8 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+#[repr(u8)]
+pub enum Format {
+    Json = 0,
+    #[value(name = "msgpack")]
+    MsgPack = 1,
+    Bincode = 2,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum CodecError {
+    #[error("json: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("msgpack: {0}")]
+    MsgPackEncode(#[from] rmp_serde::encode::Error),
+
+    #[error("msgpack: {0}")]
+    MsgPackDecode(#[from] rmp_serde::decode::Error),
+
+    #[error("bincode: {0}")]
+    Bincode(#[from] bincode::Error),
+
+    #[error("zstd: {0}")]
+    Zstd(#[from] io::Error),
+}
+
+/// Turns cached values into bytes and back.
+///
+/// Every encoded value starts with a header byte made of schema version,
+/// format and compression flag: `vvvv fff c`. A value with a header
+/// different from the current one is reported as missing.
+#[derive(Clone, Copy, Debug)]
+pub struct Codec {
+    format: Format,
+    compress: bool,
+}
+
+impl Codec {
+    pub fn new(format: Format, compress: bool) -> Self {
+        Self { format, compress }
+    }
+
+    fn header(&self) -> u8 {
+        SCHEMA_VERSION << 4 | (self.format as u8) << 1 | self.compress as u8
+    }
+
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, CodecError> {
+        let payload = match self.format {
+            Format::Json => serde_json::to_vec(value)?,
+            // keep field names to stay tolerant to field reordering
+            Format::MsgPack => rmp_serde::to_vec_named(value)?,
+            Format::Bincode => bincode::serialize(value)?,
+        };
+
+        let mut bytes = vec![self.header()];
+        if self.compress {
+            bytes.extend(zstd::encode_all(payload.as_slice(), 0)?);
+        } else {
+            bytes.extend(payload);
+        }
+
+        Ok(bytes)
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<Option<T>, CodecError> {
+        let Some(payload) = self.payload(bytes)? else {
+            return Ok(None);
+        };
+
+        Ok(Some(match self.format {
+            Format::Json => serde_json::from_slice(&payload)?,
+            Format::MsgPack => rmp_serde::from_slice(&payload)?,
+            Format::Bincode => bincode::deserialize(&payload)?,
+        }))
+    }
+
+    /// Get value as JSON bytes. JSON payload is returned as it is,
+    /// other formats are decoded into `T` and serialized back to JSON.
+    pub fn decode_json<T>(&self, bytes: &[u8]) -> Result<Option<Vec<u8>>, CodecError>
+    where
+        T: Serialize + DeserializeOwned,
+    {
+        if self.format == Format::Json {
+            return self.payload(bytes);
+        }
+
+        match self.decode::<T>(bytes)? {
+            Some(value) => Ok(Some(serde_json::to_vec(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Check the header and get decompressed payload.
+    fn payload(&self, bytes: &[u8]) -> Result<Option<Vec<u8>>, CodecError> {
+        match bytes.split_first() {
+            Some((&header, payload)) if header == self.header() => Ok(Some(if self.compress {
+                zstd::decode_all(payload)?
+            } else {
+                payload.to_vec()
+            })),
+            _ => Ok(None),
+        }
+    }
+}
+
+impl Default for Codec {
+    fn default() -> Self {
+        Self::new(Format::Json, false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde::Deserialize;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Value {
+        name: String,
+        #[serde(skip)]
+        id: Option<i32>,
+        tags: Vec<u16>,
+    }
+
+    fn value() -> Value {
+        Value {
+            name: "test".to_owned(),
+            id: None,
+            tags: vec![202, 0, u16::MAX],
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        for format in [Format::Json, Format::MsgPack, Format::Bincode] {
+            for compress in [false, true] {
+                let codec = Codec::new(format, compress);
+                let bytes = codec.encode(&value()).unwrap();
+
+                assert_eq!(codec.decode::<Value>(&bytes).unwrap(), Some(value()));
+            }
+        }
+    }
+
+    #[test]
+    fn decode_json() {
+        let expected = serde_json::to_vec(&value()).unwrap();
+
+        for format in [Format::Json, Format::MsgPack, Format::Bincode] {
+            for compress in [false, true] {
+                let codec = Codec::new(format, compress);
+                let bytes = codec.encode(&value()).unwrap();
+
+                assert_eq!(
+                    codec.decode_json::<Value>(&bytes).unwrap().as_ref(),
+                    Some(&expected)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn other_header_is_missing() {
+        let bytes = Codec::new(Format::MsgPack, true).encode(&value()).unwrap();
+
+        assert_eq!(
+            Codec::new(Format::MsgPack, false)
+                .decode::<Value>(&bytes)
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            Codec::new(Format::Bincode, true)
+                .decode::<Value>(&bytes)
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn legacy_json_is_missing() {
+        let bytes = serde_json::to_vec(&value()).unwrap();
+
+        assert_eq!(Codec::default().decode::<Value>(&bytes).unwrap(), None);
+        assert_eq!(Codec::default().decode::<Value>(&[]).unwrap(), None);
+    }
+}
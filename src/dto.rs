@@ -2,7 +2,10 @@ use anyhow::Error;
 use postgres_types::{FromSql, ToSql};
 use tokio_postgres::Row;
 
-use crate::model::{Delivery, Item, Locale, Payment};
+use crate::model::{
+    Currency, Delivery, Item, ItemStatus, ItemStatusChange, Locale, Money, Order, OrderStatus,
+    Payment, Percent,
+};
 
 #[derive(Debug, ToSql, FromSql)]
 pub struct OrderRepoDto {
@@ -19,6 +22,7 @@ pub struct OrderRepoDto {
     pub sm_id: i32,
     pub date_created: chrono::DateTime<chrono::Utc>,
     pub oof_shard: String,
+    pub status: OrderStatus,
 }
 
 impl TryFrom<Row> for OrderRepoDto {
@@ -39,6 +43,7 @@ impl TryFrom<Row> for OrderRepoDto {
             sm_id: row.try_get("sm_id")?,
             date_created: row.try_get("date_created")?,
             oof_shard: row.try_get("oof_shard")?,
+            status: row.try_get("status")?,
         })
     }
 }
@@ -96,6 +101,178 @@ impl TryFrom<Row> for Item {
             nm_id: row.try_get("nm_id")?,
             brand: row.try_get("brand")?,
             status: row.try_get("status")?,
+            status_history: Vec::new(),
+        })
+    }
+}
+
+impl TryFrom<Row> for ItemStatusChange {
+    type Error = Error;
+
+    fn try_from(row: Row) -> Result<Self, Self::Error> {
+        Ok(Self {
+            status: row.try_get("status")?,
+            changed_at: row.try_get("changed_at")?,
+        })
+    }
+}
+
+/// Row of `deliveries` selected as a whole, e.g. `SELECT d FROM deliveries d`.
+#[derive(Debug, FromSql)]
+#[postgres(name = "deliveries")]
+pub struct DeliveryRepoDto {
+    pub id: i32,
+    pub name: String,
+    pub phone: String,
+    pub zip: String,
+    pub city: String,
+    pub address: String,
+    pub region: String,
+    pub email: String,
+    pub content_hash: Option<Vec<u8>>,
+}
+
+impl From<DeliveryRepoDto> for Delivery {
+    fn from(dto: DeliveryRepoDto) -> Self {
+        Self {
+            id: Some(dto.id),
+            name: dto.name,
+            phone: dto.phone,
+            zip: dto.zip,
+            city: dto.city,
+            address: dto.address,
+            region: dto.region,
+            email: dto.email,
+        }
+    }
+}
+
+/// Row of `payments` selected as a whole.
+#[derive(Debug, FromSql)]
+#[postgres(name = "payments")]
+pub struct PaymentRepoDto {
+    pub transaction: String,
+    pub request_id: String,
+    pub currency: Currency,
+    pub provider: String,
+    pub amount: Money,
+    pub payment_dt: i32,
+    pub bank: String,
+    pub delivery_cost: Money,
+    pub goods_total: Money,
+    pub custom_fee: Percent,
+}
+
+impl From<PaymentRepoDto> for Payment {
+    fn from(dto: PaymentRepoDto) -> Self {
+        Self {
+            transaction: dto.transaction,
+            request_id: dto.request_id,
+            currency: dto.currency,
+            provider: dto.provider,
+            amount: dto.amount,
+            payment_dt: dto.payment_dt,
+            bank: dto.bank,
+            delivery_cost: dto.delivery_cost,
+            goods_total: dto.goods_total,
+            custom_fee: dto.custom_fee,
+        }
+    }
+}
+
+/// Row of `item_details` view selected as a whole, product attributes are resolved there.
+#[derive(Debug, FromSql)]
+#[postgres(name = "item_details")]
+pub struct ItemRepoDto {
+    pub id: i32,
+    pub chrt_id: i32,
+    pub track_number: String,
+    pub price: Money,
+    pub rid: String,
+    pub name: String,
+    pub sale: Percent,
+    pub size: String,
+    pub total_price: Money,
+    pub nm_id: i32,
+    pub brand: String,
+    pub status: ItemStatus,
+}
+
+impl From<ItemRepoDto> for Item {
+    fn from(dto: ItemRepoDto) -> Self {
+        Self {
+            id: Some(dto.id),
+            chrt_id: dto.chrt_id,
+            track_number: dto.track_number,
+            price: dto.price,
+            rid: dto.rid,
+            name: dto.name,
+            sale: dto.sale,
+            size: dto.size,
+            total_price: dto.total_price,
+            nm_id: dto.nm_id,
+            brand: dto.brand,
+            status: dto.status,
+            status_history: Vec::new(),
+        }
+    }
+}
+
+/// Row of `item_status_history` selected as a whole.
+#[derive(Debug, FromSql)]
+#[postgres(name = "item_status_history")]
+pub struct ItemStatusChangeRepoDto {
+    pub id: i32,
+    pub item_id: i32,
+    pub status: ItemStatus,
+    pub changed_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// Order aggregated by a single query: columns of `orders` along with
+/// `delivery`, `payment`, `items` and `status_history` of the items.
+impl TryFrom<Row> for Order {
+    type Error = Error;
+
+    fn try_from(row: Row) -> Result<Self, Self::Error> {
+        let delivery: DeliveryRepoDto = row.try_get("delivery")?;
+        let payment: PaymentRepoDto = row.try_get("payment")?;
+        let history: Vec<ItemStatusChangeRepoDto> = row.try_get("status_history")?;
+
+        let mut items: Vec<Item> = row
+            .try_get::<_, Vec<ItemRepoDto>>("items")?
+            .into_iter()
+            .map(Item::from)
+            .collect();
+        for change in history {
+            if let Some(item) = items
+                .iter_mut()
+                .find(|item| item.id == Some(change.item_id))
+            {
+                item.status_history.push(ItemStatusChange {
+                    status: change.status,
+                    changed_at: change.changed_at,
+                });
+            }
+        }
+
+        let order_dto = OrderRepoDto::try_from(row)?;
+
+        Ok(Self {
+            order_uid: order_dto.order_uid,
+            track_number: order_dto.track_number,
+            entry: order_dto.entry,
+            delivery: delivery.into(),
+            payment: payment.into(),
+            items,
+            locale: order_dto.locale,
+            internal_signature: order_dto.internal_signature,
+            customer_id: order_dto.customer_id,
+            delivery_service: order_dto.delivery_service,
+            shardkey: order_dto.shardkey,
+            sm_id: order_dto.sm_id,
+            date_created: order_dto.date_created,
+            oof_shard: order_dto.oof_shard,
+            status: order_dto.status,
         })
     }
 }
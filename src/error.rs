@@ -1,8 +1,10 @@
 use Error::*;
 
+use std::time::Duration;
+
 use axum::{
     extract::rejection::JsonRejection,
-    http::StatusCode,
+    http::{header, StatusCode},
     response::{IntoResponse, Response},
     Json,
 };
@@ -29,6 +31,24 @@ pub enum Error {
     #[error("redis failed to execute query: {0}")]
     RedisQueryFailed(#[from] redis::RedisError),
 
+    #[error("cache codec failed: {0}")]
+    CacheCodecFailed(#[from] crate::codec::CodecError),
+
+    #[error("invalid input: {0}")]
+    InvalidInput(String),
+
+    #[error("conflict: {0}")]
+    Conflict(String),
+
+    #[error("unauthorized")]
+    Unauthorized,
+
+    #[error("forbidden: {0}")]
+    Forbidden(String),
+
+    #[error("too many requests, retry after {0:?}")]
+    TooManyRequests(Duration),
+
     #[error("cannot find '{target}' by '{id_name}={id_val}'")]
     NotFound {
         id_name: String,
@@ -48,7 +68,26 @@ impl IntoResponse for Error {
 
         let (status, message) = match self {
             NotFound { .. } => return StatusCode::NOT_FOUND.into_response(),
+            Unauthorized => {
+                return (
+                    StatusCode::UNAUTHORIZED,
+                    [(header::WWW_AUTHENTICATE, "Bearer")],
+                )
+                    .into_response()
+            }
+            TooManyRequests(retry_after) => {
+                // whole seconds, rounded up so the client doesn't retry too early
+                let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+                return (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    [(header::RETRY_AFTER, seconds.to_string())],
+                )
+                    .into_response();
+            }
             JsonRejection(rejection) => (rejection.status(), rejection.body_text()),
+            InvalidInput(message) => (StatusCode::UNPROCESSABLE_ENTITY, message),
+            Conflict(message) => (StatusCode::CONFLICT, message),
+            Forbidden(message) => (StatusCode::FORBIDDEN, message),
             _ => (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 "an internal server error occurred".to_owned(),
@@ -1,15 +1,43 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    convert::Infallible,
+    net::SocketAddr,
+};
+
 use crate::{
+    auth::Client,
     error::Error,
-    model::{Delivery, Item, Order, Payment},
-    state::{AppState, CacheOrder, StoreOrder},
+    model::{
+        Actor, AuditEntry, BatchMode, CreateStatus, Delivery, EventType, Item, ItemStatus, Order,
+        OrderEvent, OrderPayload, OrderStatus, Payment, RejectedOrder, RejectionSource, Webhook,
+        WebhookAttempt,
+    },
+    repo::rejected_status,
+    state::{AppState, CacheOrder, StoreEvent, StoreOrder, StoreRejected, StoreWebhook},
+    webhook,
 };
 
 use axum::{
-    extract::{Path, State},
-    http::StatusCode,
+    async_trait,
+    body::{Body, Bytes},
+    extract::{
+        rejection::JsonRejection, ConnectInfo, FromRequest, FromRequestParts, Path, Query, Request,
+        State,
+    },
+    handler::Handler,
+    http::{header, request::Parts, HeaderMap, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
     Json,
 };
-use tracing::trace;
+use futures_util::{stream, Stream};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::value::RawValue;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{trace, warn};
 
 type Result<T> = std::result::Result<T, Error>;
 type JsonResult<T> = Result<Json<T>>;
@@ -17,50 +45,317 @@ type JsonResult<T> = Result<Json<T>>;
 pub async fn get_order<R, C>(
     Path(order_id): Path<String>,
     State(state): State<AppState<R, C>>,
+    customer: CustomerScope,
+) -> Result<Response>
+where
+    R: StoreOrder + Clone,
+    C: CacheOrder + Clone + Send + 'static,
+{
+    // fast path: cached body is sent without deserialization,
+    // a client limited to a customer gets the order from database to check it
+    if let (Some(cache), CustomerScope(None)) = (&state.cache, &customer) {
+        trace!(order_id, "get order body from cache");
+        if let Some(body) = cache.get_order_json(&order_id).await? {
+            return Ok(([(header::CONTENT_TYPE, "application/json")], body).into_response());
+        }
+    }
+
+    trace!(order_id, "get order from database");
+    let version = state.cache.as_ref().map_or(0, CacheOrder::version);
+    let order = state
+        .repo
+        .get_order(&order_id)
+        .await?
+        .ok_or(Error::not_found("order_id", &order_id, "order"))?;
+
+    if let Some(cache) = state.cache.clone() {
+        let order = order.clone();
+        trace!(?order, "insert order into cache");
+        tokio::spawn(async move { cache.fill_order(&order, version).await });
+    }
+
+    if !customer.allows(&order) {
+        return Err(Error::not_found("order_id", order_id, "order"));
+    }
+
+    Ok(Json(order).into_response())
+}
+
+/// Get the payload the order was received as, it's available to the admin only.
+pub async fn get_order_payload<R, C>(
+    Path(order_id): Path<String>,
+    State(state): State<AppState<R, C>>,
+    headers: HeaderMap,
+) -> JsonResult<OrderPayload>
+where
+    R: StoreOrder + Clone,
+    C: CacheOrder + Clone,
+{
+    authorize_admin(&headers, &state)?;
+
+    trace!(order_id, "get order payload from db");
+
+    let payload = state
+        .repo
+        .get_order_payload(&order_id)
+        .await?
+        .ok_or(Error::not_found("order_id", order_id, "order payload"))?;
+
+    Ok(Json(payload))
+}
+
+/// Get changes of the order, the latest go first. It's available to the admin only.
+pub async fn get_order_audit<R, C>(
+    Path(order_id): Path<String>,
+    Query(page): Query<Page>,
+    State(state): State<AppState<R, C>>,
+    headers: HeaderMap,
+) -> JsonResult<Vec<AuditEntry>>
+where
+    R: StoreOrder + Clone,
+    C: CacheOrder + Clone,
+{
+    authorize_admin(&headers, &state)?;
+
+    trace!(order_id, ?page, "get audit of order from db");
+
+    page.validate()?;
+
+    let entries = state
+        .repo
+        .get_order_audit(&order_id, page.limit, page.offset)
+        .await?;
+    // there is no audit of an order which has never existed
+    if entries.is_empty() && page.offset == 0 {
+        return Err(Error::not_found("order_id", order_id, "order audit"));
+    }
+
+    Ok(Json(entries))
+}
+
+pub async fn get_order_by_track_number<R, C>(
+    Path(track_number): Path<String>,
+    State(state): State<AppState<R, C>>,
+    customer: CustomerScope,
 ) -> JsonResult<Order>
+where
+    R: StoreOrder + Clone,
+    C: CacheOrder + Clone,
+{
+    trace!(track_number, "get order by track_number from db");
+
+    let order = state
+        .repo
+        .get_order_by_track_number(&track_number)
+        .await?
+        .filter(|order| customer.allows(order))
+        .ok_or(Error::not_found("track_number", track_number, "order"))?;
+
+    Ok(Json(order))
+}
+
+pub async fn get_order_by_transaction<R, C>(
+    Path(transaction): Path<String>,
+    State(state): State<AppState<R, C>>,
+    customer: CustomerScope,
+) -> JsonResult<Order>
+where
+    R: StoreOrder + Clone,
+    C: CacheOrder + Clone,
+{
+    trace!(transaction, "get order by payment transaction from db");
+
+    let order = state
+        .repo
+        .get_order_by_transaction(&transaction)
+        .await?
+        .filter(|order| customer.allows(order))
+        .ok_or(Error::not_found("transaction", transaction, "order"))?;
+
+    Ok(Json(order))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Page {
+    #[serde(default = "Page::default_limit")]
+    limit: i64,
+    #[serde(default)]
+    offset: i64,
+}
+
+impl Page {
+    const MAX_LIMIT: i64 = 500;
+
+    fn default_limit() -> i64 {
+        50
+    }
+
+    fn validate(&self) -> Result<()> {
+        if !(1..=Page::MAX_LIMIT).contains(&self.limit) || self.offset < 0 {
+            return Err(Error::InvalidInput(format!(
+                "limit must be within 1..={} and offset must not be negative",
+                Page::MAX_LIMIT
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+pub async fn get_orders_by_customer<R, C>(
+    Path(customer_id): Path<String>,
+    Query(page): Query<Page>,
+    State(state): State<AppState<R, C>>,
+    customer: CustomerScope,
+) -> JsonResult<Vec<Order>>
+where
+    R: StoreOrder + Clone,
+    C: CacheOrder + Clone,
+{
+    trace!(customer_id, ?page, "get orders by customer_id from db");
+
+    page.validate()?;
+    if customer.0.as_ref().is_some_and(|id| *id != customer_id) {
+        return Err(Error::Forbidden(
+            "orders of other customers can't be read".to_owned(),
+        ));
+    }
+
+    let orders = state
+        .repo
+        .get_orders_by_customer(&customer_id, page.limit, page.offset)
+        .await?;
+
+    Ok(Json(orders))
+}
+
+/// Router doesn't allow a static colon in a path, so custom methods
+/// of the orders collection, e.g. `POST /orders:batchGet`, are dispatched here.
+pub async fn call_orders_method<R, C>(
+    Path(method): Path<String>,
+    State(state): State<AppState<R, C>>,
+    request: Request,
+) -> Response
+where
+    R: StoreOrder + StoreRejected + Clone + Send + Sync + 'static,
+    C: CacheOrder + Clone + Send + Sync + 'static,
+{
+    trace!(method, "call method of orders");
+
+    match method.as_str() {
+        ":batchGet" => batch_get_orders.call(request, state).await,
+        ":batchCreate" => batch_create_orders.call(request, state).await,
+        _ => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BatchGet {
+    ids: Vec<String>,
+}
+
+impl BatchGet {
+    const MAX_IDS: usize = 100;
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BatchGetResult {
+    pub orders: Vec<Order>,
+    pub missing: Vec<String>,
+}
+
+pub async fn batch_get_orders<R, C>(
+    State(state): State<AppState<R, C>>,
+    customer: CustomerScope,
+    Json(BatchGet { mut ids }): Json<BatchGet>,
+) -> JsonResult<BatchGetResult>
 where
     R: StoreOrder + Clone,
     C: CacheOrder + Clone + Send + 'static,
 {
-    let mut maybe_order = None;
+    trace!(?ids, "get orders by order_ids");
 
-    if let Some(cache) = &state.cache {
-        trace!(order_id, "get order from cache");
-        maybe_order = cache.get_order(&order_id).await?;
+    if !(1..=BatchGet::MAX_IDS).contains(&ids.len()) {
+        return Err(Error::InvalidInput(format!(
+            "ids must contain from 1 to {} order ids",
+            BatchGet::MAX_IDS
+        )));
     }
 
-    if maybe_order.is_none() {
-        trace!(order_id, "get order from database");
-        maybe_order = state.repo.get_order(&order_id).await?;
+    let mut seen = HashSet::new();
+    ids.retain(|id| seen.insert(id.clone()));
+
+    let mut found = match &state.cache {
+        Some(cache) => cache.get_orders(&ids).await?,
+        None => vec![None; ids.len()],
+    };
+
+    let misses: Vec<String> = ids
+        .iter()
+        .zip(&found)
+        .filter(|(_, maybe_order)| maybe_order.is_none())
+        .map(|(id, _)| id.clone())
+        .collect();
+
+    if !misses.is_empty() {
+        trace!(?misses, "get orders from database");
+        let version = state.cache.as_ref().map_or(0, CacheOrder::version);
+        let orders = state.repo.get_orders(&misses).await?;
 
-        if let Some(order) = maybe_order.clone() {
-            if let Some(cache) = state.cache.clone() {
-                trace!(?order, "insert order into cache");
-                tokio::spawn(async move { cache.insert_order(&order).await });
+        if let Some(cache) = state.cache.clone() {
+            let orders = orders.clone();
+            trace!(count = orders.len(), "insert orders into cache");
+            tokio::spawn(async move {
+                for order in orders {
+                    cache.fill_order(&order, version).await?;
+                }
+                Ok::<_, Error>(())
+            });
+        }
+
+        for order in orders {
+            if let Some(position) = ids.iter().position(|id| *id == order.order_uid) {
+                found[position] = Some(order);
             }
         }
     }
 
-    let order = maybe_order.ok_or(Error::not_found("order_id", order_id, "order"))?;
+    let mut result = BatchGetResult {
+        orders: Vec::with_capacity(ids.len()),
+        missing: Vec::new(),
+    };
+    for (id, maybe_order) in ids.into_iter().zip(found) {
+        match maybe_order {
+            Some(order) if customer.allows(&order) => result.orders.push(order),
+            _ => result.missing.push(id),
+        }
+    }
 
-    Ok(Json(order))
+    Ok(Json(result))
 }
 
 pub async fn get_delivery<R, C>(
     Path(order_id): Path<String>,
     State(state): State<AppState<R, C>>,
+    customer: CustomerScope,
 ) -> JsonResult<Delivery>
 where
     R: StoreOrder + Clone,
-    C: CacheOrder + Clone,
+    C: CacheOrder + Clone + Send + 'static,
 {
-    trace!(order_id, "get order delivery by order_id from db");
+    // the whole order tells its customer
+    let maybe_delivery = if state.cache.is_some() || customer.0.is_some() {
+        get_cached_order(&order_id, &state)
+            .await?
+            .filter(|order| customer.allows(order))
+            .map(|order| order.delivery)
+    } else {
+        trace!(order_id, "get order delivery by order_id from db");
+        state.repo.get_delivery(&order_id).await?
+    };
 
-    let delivery = state
-        .repo
-        .get_delivery(&order_id)
-        .await?
-        .ok_or(Error::not_found("order_id", order_id, "order delivery"))?;
+    let delivery =
+        maybe_delivery.ok_or(Error::not_found("order_id", order_id, "order delivery"))?;
 
     Ok(Json(delivery))
 }
@@ -68,54 +363,1010 @@ where
 pub async fn get_payment<R, C>(
     Path(order_id): Path<String>,
     State(state): State<AppState<R, C>>,
+    customer: CustomerScope,
 ) -> JsonResult<Payment>
 where
     R: StoreOrder + Clone,
+    C: CacheOrder + Clone + Send + 'static,
+{
+    // the whole order tells its customer
+    let maybe_payment = if state.cache.is_some() || customer.0.is_some() {
+        get_cached_order(&order_id, &state)
+            .await?
+            .filter(|order| customer.allows(order))
+            .map(|order| order.payment)
+    } else {
+        trace!(order_id, "get order payment by order_id from db");
+        state.repo.get_payment(&order_id).await?
+    };
+
+    let payment = maybe_payment.ok_or(Error::not_found("order_id", order_id, "order payment"))?;
+
+    Ok(Json(payment))
+}
+
+pub async fn get_items<R, C>(
+    Path(order_id): Path<String>,
+    State(state): State<AppState<R, C>>,
+    customer: CustomerScope,
+) -> JsonResult<Vec<Item>>
+where
+    R: StoreOrder + Clone,
+    C: CacheOrder + Clone + Send + 'static,
+{
+    // the whole order tells its customer
+    let maybe_items = if state.cache.is_some() || customer.0.is_some() {
+        get_cached_order(&order_id, &state)
+            .await?
+            .filter(|order| customer.allows(order))
+            .map(|order| order.items)
+    } else {
+        trace!(order_id, "get order items by order_id from db");
+        state.repo.get_items(&order_id).await?
+    };
+
+    let items = maybe_items.ok_or(Error::not_found("order_id", order_id, "order items"))?;
+
+    Ok(Json(items))
+}
+
+pub async fn create_order<R, C>(
+    State(state): State<AppState<R, C>>,
+    actor: Actor,
+    extracted: std::result::Result<JsonWithPayload<Order>, PayloadRejection>,
+) -> Result<StatusCode>
+where
+    R: StoreOrder + StoreRejected + Clone,
     C: CacheOrder + Clone,
 {
-    trace!(order_id, "get order payment by order_id from db");
+    let JsonWithPayload(order, payload) = match extracted {
+        Ok(extracted) => extracted,
+        Err(PayloadRejection { error, body }) => {
+            // a body sent as JSON is kept even if it's broken, other content types
+            // and bodies which are too large are just refused
+            if let Error::JsonRejection(
+                rejection @ (JsonRejection::JsonDataError(_) | JsonRejection::JsonSyntaxError(_)),
+            ) = &error
+            {
+                reject_order(
+                    &state,
+                    &String::from_utf8_lossy(&body),
+                    &rejection.body_text(),
+                )
+                .await;
+            }
+            return Err(error);
+        }
+    };
 
-    let payment = state
+    trace!(?order, "create order in database");
+
+    match state
         .repo
-        .get_payment(&order_id)
+        .create_order(order, payload.clone(), &actor)
+        .await
+    {
+        Ok(()) => Ok(StatusCode::CREATED),
+        Err(e) => Err(match rejected_status(&e) {
+            Some(CreateStatus::Duplicate) => Error::Conflict("order exists already".to_owned()),
+            Some(_) => {
+                reject_order(&state, payload.get(), &e.to_string()).await;
+                e
+            }
+            None => e,
+        }),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BatchCreate {
+    #[serde(default)]
+    mode: BatchMode,
+}
+
+impl BatchCreate {
+    const MAX_ORDERS: usize = 100;
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BatchCreateResult {
+    pub order_uid: Option<String>,
+    pub status: CreateStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+/// Create orders given as JSON array or NDJSON (`application/x-ndjson`).
+/// Response has a result per order, so an invalid order doesn't fail the request.
+pub async fn batch_create_orders<R, C>(
+    State(state): State<AppState<R, C>>,
+    Query(batch): Query<BatchCreate>,
+    actor: Actor,
+    headers: HeaderMap,
+    body: Bytes,
+) -> JsonResult<Vec<BatchCreateResult>>
+where
+    R: StoreOrder + StoreRejected + Clone,
+    C: CacheOrder + Clone,
+{
+    let entries = parse_orders(&headers, &body)?;
+    trace!(?batch, count = entries.len(), "create orders in database");
+
+    if !(1..=BatchCreate::MAX_ORDERS).contains(&entries.len()) {
+        return Err(Error::InvalidInput(format!(
+            "batch must contain from 1 to {} orders",
+            BatchCreate::MAX_ORDERS
+        )));
+    }
+
+    // valid orders are aborted until they're written
+    let mut results = Vec::with_capacity(entries.len());
+    let mut orders = Vec::with_capacity(entries.len());
+    for entry in entries {
+        match entry {
+            Ok((order, payload)) => {
+                results.push(BatchCreateResult {
+                    order_uid: Some(order.order_uid.clone()),
+                    status: CreateStatus::Aborted,
+                    message: None,
+                });
+                orders.push((order, payload));
+            }
+            Err((result, payload)) => {
+                if let Some(payload) = payload {
+                    let error = result.message.as_deref().unwrap_or_default();
+                    reject_order(&state, payload.get(), error).await;
+                }
+                results.push(result);
+            }
+        }
+    }
+
+    let has_invalid = results.len() > orders.len();
+    if orders.is_empty() || (batch.mode == BatchMode::Atomic && has_invalid) {
+        return Ok(Json(results));
+    }
+
+    // payloads of orders rejected by database are kept too
+    let payloads: Vec<_> = orders.iter().map(|(_, payload)| payload.clone()).collect();
+    let mut outcomes = state
+        .repo
+        .create_orders(orders, batch.mode, &actor)
         .await?
-        .ok_or(Error::not_found("order_id", order_id, "order payment"))?;
+        .into_iter()
+        .zip(payloads);
+    for result in results
+        .iter_mut()
+        .filter(|result| result.status == CreateStatus::Aborted)
+    {
+        let Some((outcome, payload)) = outcomes.next() else {
+            break;
+        };
+        if let (CreateStatus::Invalid, Some(error)) = (outcome.status, &outcome.error) {
+            reject_order(&state, payload.get(), error).await;
+        }
+        result.status = outcome.status;
+    }
 
-    Ok(Json(payment))
+    Ok(Json(results))
 }
 
-pub async fn get_items<R, C>(
+/// Invalid entry is given along with its payload, unless the entry isn't JSON at all.
+type ParsedOrder =
+    std::result::Result<(Order, Box<RawValue>), (BatchCreateResult, Option<Box<RawValue>>)>;
+
+/// Split the body into orders along with their payloads,
+/// entries which aren't valid orders are turned into results.
+fn parse_orders(headers: &HeaderMap, body: &[u8]) -> Result<Vec<ParsedOrder>> {
+    #[derive(Deserialize)]
+    struct Entry {
+        order_uid: Option<String>,
+    }
+
+    let invalid = |order_uid, e: serde_json::Error, payload| {
+        let result = BatchCreateResult {
+            order_uid,
+            status: CreateStatus::Invalid,
+            message: Some(e.to_string()),
+        };
+        (result, payload)
+    };
+
+    let is_ndjson = headers
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|value| value.as_bytes().starts_with(b"application/x-ndjson"));
+
+    let payloads: Vec<_> = if is_ndjson {
+        body.split(|byte| *byte == b'\n')
+            .filter(|line| !line.trim_ascii().is_empty())
+            .map(|line| {
+                serde_json::from_slice::<Box<RawValue>>(line).map_err(|e| invalid(None, e, None))
+            })
+            .collect()
+    } else {
+        serde_json::from_slice::<Vec<Box<RawValue>>>(body)
+            .map_err(|e| {
+                Error::InvalidInput(format!("body must be a JSON array of orders: {}", e))
+            })?
+            .into_iter()
+            .map(Ok)
+            .collect()
+    };
+
+    Ok(payloads
+        .into_iter()
+        .map(|payload| {
+            let payload = payload?;
+            match serde_json::from_str(payload.get()) {
+                Ok(order) => Ok((order, payload)),
+                Err(e) => {
+                    let order_uid = serde_json::from_str::<Entry>(payload.get())
+                        .ok()
+                        .and_then(|entry| entry.order_uid);
+                    Err(invalid(order_uid, e, Some(payload)))
+                }
+            }
+        })
+        .collect())
+}
+
+pub async fn update_order<R, C>(
     Path(order_id): Path<String>,
     State(state): State<AppState<R, C>>,
+    actor: Actor,
+    JsonWithPayload(order, payload): JsonWithPayload<Order>,
+) -> Result<StatusCode>
+where
+    R: StoreOrder + Clone,
+    C: CacheOrder + Clone,
+{
+    if order.order_uid != order_id {
+        return Err(Error::InvalidInput(format!(
+            "order_uid '{}' doesn't match '{}' of the path",
+            order.order_uid, order_id
+        )));
+    }
+
+    trace!(?order, "update order in database");
+    let updated = match state.repo.update_order(order, payload, &actor).await {
+        Ok(updated) => updated,
+        // the transaction is the only key of the order which might be taken
+        Err(e) if rejected_status(&e) == Some(CreateStatus::Duplicate) => {
+            return Err(Error::Conflict(
+                "payment transaction belongs to another order".to_owned(),
+            ))
+        }
+        Err(e) => return Err(e),
+    };
+    if !updated {
+        return Err(Error::not_found("order_id", order_id, "order"));
+    }
+
+    invalidate_order(&order_id, &state).await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn delete_order<R, C>(
+    Path(order_id): Path<String>,
+    State(state): State<AppState<R, C>>,
+    actor: Actor,
+) -> Result<StatusCode>
+where
+    R: StoreOrder + Clone,
+    C: CacheOrder + Clone,
+{
+    trace!(order_id, "delete order from database");
+    if !state.repo.delete_order(&order_id, &actor).await? {
+        return Err(Error::not_found("order_id", order_id, "order"));
+    }
+
+    invalidate_order(&order_id, &state).await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StatusUpdate<S> {
+    status: S,
+}
+
+pub async fn update_order_status<R, C>(
+    Path(order_id): Path<String>,
+    State(state): State<AppState<R, C>>,
+    actor: Actor,
+    Json(update): Json<StatusUpdate<OrderStatus>>,
+) -> JsonResult<StatusUpdate<OrderStatus>>
+where
+    R: StoreOrder + Clone,
+    C: CacheOrder + Clone,
+{
+    trace!(order_id, ?update, "update order status in database");
+
+    let status = state
+        .repo
+        .update_order_status(&order_id, update.status, &actor)
+        .await?
+        .ok_or(Error::not_found("order_id", &order_id, "order"))?;
+
+    invalidate_order(&order_id, &state).await;
+
+    Ok(Json(StatusUpdate { status }))
+}
+
+pub async fn update_item_status<R, C>(
+    Path((order_id, chrt_id)): Path<(String, i32)>,
+    State(state): State<AppState<R, C>>,
+    actor: Actor,
+    Json(update): Json<StatusUpdate<ItemStatus>>,
 ) -> JsonResult<Vec<Item>>
 where
     R: StoreOrder + Clone,
     C: CacheOrder + Clone,
 {
-    trace!(order_id, "get order items by order_id from db");
+    trace!(order_id, chrt_id, ?update, "update item status in database");
 
     let items = state
         .repo
-        .get_items(&order_id)
+        .update_item_status(&order_id, chrt_id, update.status, &actor)
         .await?
-        .ok_or(Error::not_found("order_id", order_id, "order delivery"))?;
+        .ok_or(Error::not_found("chrt_id", chrt_id, "order item"))?;
+
+    invalidate_order(&order_id, &state).await;
 
     Ok(Json(items))
 }
 
-pub async fn create_order<R, C>(
+#[derive(Debug, Deserialize)]
+pub struct NewWebhook {
+    url: String,
+    event_types: Vec<EventType>,
+    /// It's generated if it's missing.
+    #[serde(default)]
+    secret: Option<String>,
+}
+
+/// Subscribe a partner to events, response has the secret events are signed with.
+pub async fn create_webhook<R, C>(
+    State(state): State<AppState<R, C>>,
+    headers: HeaderMap,
+    Json(new): Json<NewWebhook>,
+) -> Result<(StatusCode, Json<Webhook>)>
+where
+    R: StoreWebhook + Clone,
+    C: CacheOrder + Clone,
+{
+    authorize_admin(&headers, &state)?;
+
+    trace!(url = new.url, event_types = ?new.event_types, "create webhook in db");
+
+    let is_http =
+        reqwest::Url::parse(&new.url).is_ok_and(|url| matches!(url.scheme(), "http" | "https"));
+    if !is_http {
+        return Err(Error::InvalidInput(format!(
+            "url '{}' must be an absolute http(s) url",
+            new.url
+        )));
+    }
+    if new.event_types.is_empty() {
+        return Err(Error::InvalidInput(
+            "at least one event type must be given".to_owned(),
+        ));
+    }
+    let secret = match new.secret {
+        Some(secret) if secret.is_empty() => {
+            return Err(Error::InvalidInput("secret must not be empty".to_owned()))
+        }
+        Some(secret) => secret,
+        None => webhook::generate_secret(),
+    };
+
+    let webhook = state
+        .repo
+        .create_webhook(&new.url, &new.event_types, &secret)
+        .await?;
+
+    Ok((StatusCode::CREATED, Json(webhook)))
+}
+
+pub async fn get_webhooks<R, C>(
+    State(state): State<AppState<R, C>>,
+    headers: HeaderMap,
+) -> JsonResult<Vec<Webhook>>
+where
+    R: StoreWebhook + Clone,
+    C: CacheOrder + Clone,
+{
+    authorize_admin(&headers, &state)?;
+
+    trace!("get webhooks from db");
+
+    Ok(Json(state.repo.get_webhooks().await?))
+}
+
+pub async fn get_webhook<R, C>(
+    Path(id): Path<i32>,
+    State(state): State<AppState<R, C>>,
+    headers: HeaderMap,
+) -> JsonResult<Webhook>
+where
+    R: StoreWebhook + Clone,
+    C: CacheOrder + Clone,
+{
+    authorize_admin(&headers, &state)?;
+
+    trace!(id, "get webhook from db");
+
+    let webhook = state
+        .repo
+        .get_webhook(id)
+        .await?
+        .ok_or(Error::not_found("id", id, "webhook"))?;
+
+    Ok(Json(webhook))
+}
+
+pub async fn delete_webhook<R, C>(
+    Path(id): Path<i32>,
     State(state): State<AppState<R, C>>,
-    Json(order): Json<Order>,
+    headers: HeaderMap,
 ) -> Result<StatusCode>
 where
-    R: StoreOrder + Clone,
+    R: StoreWebhook + Clone,
     C: CacheOrder + Clone,
 {
-    trace!(?order, "create order in database");
+    authorize_admin(&headers, &state)?;
+
+    trace!(id, "delete webhook from db");
+
+    if !state.repo.delete_webhook(id).await? {
+        return Err(Error::not_found("id", id, "webhook"));
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Get the delivery log of the webhook, the latest attempts go first.
+pub async fn get_webhook_attempts<R, C>(
+    Path(id): Path<i32>,
+    Query(page): Query<Page>,
+    State(state): State<AppState<R, C>>,
+    headers: HeaderMap,
+) -> JsonResult<Vec<WebhookAttempt>>
+where
+    R: StoreWebhook + Clone,
+    C: CacheOrder + Clone,
+{
+    authorize_admin(&headers, &state)?;
+
+    trace!(id, ?page, "get attempts of webhook from db");
+
+    page.validate()?;
+
+    let attempts = state
+        .repo
+        .get_webhook_attempts(id, page.limit, page.offset)
+        .await?
+        .ok_or(Error::not_found("id", id, "webhook"))?;
+
+    Ok(Json(attempts))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StreamFilter {
+    customer_id: Option<String>,
+    delivery_service: Option<String>,
+}
+
+impl StreamFilter {
+    fn matches(&self, event: &OrderEvent) -> bool {
+        #[derive(Deserialize)]
+        struct Fields {
+            customer_id: String,
+            delivery_service: String,
+        }
+
+        if self.customer_id.is_none() && self.delivery_service.is_none() {
+            return true;
+        }
+        let Ok(fields) = serde_json::from_str::<Fields>(event.data.get()) else {
+            warn!(event_id = event.id, "event data is not an order");
+            return false;
+        };
+
+        self.customer_id
+            .as_ref()
+            .is_none_or(|id| *id == fields.customer_id)
+            && self
+                .delivery_service
+                .as_ref()
+                .is_none_or(|service| *service == fields.delivery_service)
+    }
+}
+
+/// Stream of orders created after the subscription, or after `Last-Event-ID` on reconnection.
+/// Missed events are replayed from the outbox, the ones committed out of order meanwhile
+/// might be skipped.
+pub async fn stream_orders<R, C>(
+    Query(mut filter): Query<StreamFilter>,
+    State(state): State<AppState<R, C>>,
+    CustomerScope(customer_id): CustomerScope,
+    headers: HeaderMap,
+) -> Result<Sse<impl Stream<Item = std::result::Result<Event, Infallible>>>>
+where
+    R: StoreEvent + Clone + Send + Sync + 'static,
+    C: CacheOrder + Clone,
+{
+    let last_id = match headers.get("last-event-id") {
+        Some(value) => value
+            .to_str()
+            .ok()
+            .and_then(|value| value.parse::<i64>().ok())
+            .ok_or_else(|| Error::InvalidInput("Last-Event-ID must be an event id".to_owned()))?,
+        None => 0,
+    };
+    if let Some(customer_id) = customer_id {
+        if filter
+            .customer_id
+            .as_ref()
+            .is_some_and(|id| *id != customer_id)
+        {
+            return Err(Error::Forbidden(
+                "orders of other customers can't be streamed".to_owned(),
+            ));
+        }
+        filter.customer_id = Some(customer_id);
+    }
+    trace!(?filter, last_id, "stream orders");
+
+    // subscribe before replay, so nothing is missed in between
+    let events = state
+        .events
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("feed of events is not configured"))?
+        .subscribe();
+
+    let feed = OrderFeed {
+        repo: state.repo.clone(),
+        events,
+        filter,
+        pending: VecDeque::new(),
+        replaying: headers.contains_key("last-event-id"),
+        last_id,
+        replayed_id: 0,
+    };
+
+    let stream = stream::unfold(feed, |mut feed| async move {
+        let event = feed.next().await?;
+        let sse = Event::default()
+            .id(event.id.to_string())
+            .event("order_created")
+            .data(event.data.get());
+        Some((Ok(sse), feed))
+    });
+
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+/// Created orders replayed from the outbox first, then the live ones.
+struct OrderFeed<R> {
+    repo: R,
+    events: broadcast::Receiver<OrderEvent>,
+    filter: StreamFilter,
+    /// Replayed events which aren't taken yet.
+    pending: VecDeque<OrderEvent>,
+    replaying: bool,
+    /// The latest event taken, replay continues after it.
+    last_id: i64,
+    /// The latest replayed event, live events up to it are duplicates.
+    replayed_id: i64,
+}
+
+impl<R> OrderFeed<R>
+where
+    R: StoreEvent,
+{
+    const REPLAY_PAGE: i64 = 100;
+
+    /// The next matching event, `None` once the feed is over.
+    async fn next(&mut self) -> Option<OrderEvent> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                self.last_id = event.id;
+                if self.filter.matches(&event) {
+                    return Some(event);
+                }
+                continue;
+            }
+
+            if self.replaying {
+                trace!(last_id = self.last_id, "replay events");
+                let page = match self
+                    .repo
+                    .get_events(EventType::OrderCreated, self.last_id, Self::REPLAY_PAGE)
+                    .await
+                {
+                    Ok(page) => page,
+                    Err(e) => {
+                        warn!("failed to replay events: {}", e);
+                        return None;
+                    }
+                };
+                self.replaying = page.len() == Self::REPLAY_PAGE as usize;
+                if let Some(event) = page.last() {
+                    self.replayed_id = event.id;
+                }
+                self.pending.extend(page);
+                continue;
+            }
+
+            match self.events.recv().await {
+                Ok(event)
+                    if event.event_type == EventType::OrderCreated
+                        && event.id > self.replayed_id =>
+                {
+                    self.last_id = self.last_id.max(event.id);
+                    if self.filter.matches(&event) {
+                        return Some(event);
+                    }
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(missed)) => {
+                    warn!(missed, "stream of orders lags behind, replay missed events");
+                    self.replaying = true;
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+/// Get rejected orders without their payloads, the latest go first.
+pub async fn get_rejected_orders<R, C>(
+    Query(page): Query<Page>,
+    State(state): State<AppState<R, C>>,
+    headers: HeaderMap,
+) -> JsonResult<Vec<RejectedOrder>>
+where
+    R: StoreRejected + Clone,
+    C: CacheOrder + Clone,
+{
+    authorize_admin(&headers, &state)?;
+
+    trace!(?page, "get rejected orders from db");
+
+    page.validate()?;
+
+    let orders = state
+        .repo
+        .get_rejected_orders(page.limit, page.offset)
+        .await?;
+
+    Ok(Json(orders))
+}
+
+pub async fn get_rejected_order<R, C>(
+    Path(id): Path<i64>,
+    State(state): State<AppState<R, C>>,
+    headers: HeaderMap,
+) -> JsonResult<RejectedOrder>
+where
+    R: StoreRejected + Clone,
+    C: CacheOrder + Clone,
+{
+    authorize_admin(&headers, &state)?;
+
+    trace!(id, "get rejected order from db");
+
+    let order = state
+        .repo
+        .get_rejected_order(id)
+        .await?
+        .ok_or(Error::not_found("id", id, "rejected order"))?;
+
+    Ok(Json(order))
+}
+
+/// Create the rejected order, either as it was received or with the fixed payload in the body.
+/// It's removed from rejected ones once it's created.
+pub async fn replay_rejected_order<R, C>(
+    Path(id): Path<i64>,
+    State(state): State<AppState<R, C>>,
+    actor: Actor,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<StatusCode>
+where
+    R: StoreRejected + Clone,
+    C: CacheOrder + Clone,
+{
+    authorize_admin(&headers, &state)?;
+
+    trace!(id, fixed = !body.is_empty(), "replay rejected order");
+
+    let payload = if body.is_empty() {
+        state
+            .repo
+            .get_rejected_order(id)
+            .await?
+            .and_then(|order| order.payload)
+            .ok_or(Error::not_found("id", id, "rejected order"))?
+            .into_bytes()
+    } else {
+        body.to_vec()
+    };
+
+    let invalid = |e: serde_json::Error| Error::InvalidInput(format!("invalid order: {}", e));
+    let order: Order = serde_json::from_slice(&payload).map_err(invalid)?;
+    let payload: Box<RawValue> = serde_json::from_slice(&payload).map_err(invalid)?;
+
+    // the change is made by the admin on behalf of the original client
+    let actor = Actor {
+        name: ADMIN_ACTOR.to_owned(),
+        ..actor
+    };
+    match state
+        .repo
+        .replay_rejected_order(id, order, payload, &actor)
+        .await
+    {
+        Ok(true) => Ok(StatusCode::CREATED),
+        Ok(false) => Err(Error::not_found("id", id, "rejected order")),
+        Err(e) => Err(match rejected_status(&e) {
+            Some(CreateStatus::Duplicate) => Error::Conflict("order exists already".to_owned()),
+            Some(_) => Error::InvalidInput(format!("order is rejected by database: {}", e)),
+            None => e,
+        }),
+    }
+}
+
+/// Discard the rejected order for good.
+pub async fn delete_rejected_order<R, C>(
+    Path(id): Path<i64>,
+    State(state): State<AppState<R, C>>,
+    headers: HeaderMap,
+) -> Result<StatusCode>
+where
+    R: StoreRejected + Clone,
+    C: CacheOrder + Clone,
+{
+    authorize_admin(&headers, &state)?;
+
+    trace!(id, "delete rejected order from db");
+
+    if !state.repo.delete_rejected_order(id).await? {
+        return Err(Error::not_found("id", id, "rejected order"));
+    }
 
-    state
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Keep the payload of the order rejected over HTTP.
+/// The client is told about the rejection anyway, so failure is just reported.
+async fn reject_order<R, C>(state: &AppState<R, C>, payload: &str, error: &str)
+where
+    R: StoreRejected + Clone,
+    C: Clone,
+{
+    trace!(error, "keep rejected order");
+    if let Err(e) = state
         .repo
-        .create_order(order)
+        .reject_order(RejectionSource::Http, payload, error)
         .await
-        .map(|_| StatusCode::CREATED)
+    {
+        warn!("failed to keep rejected order: {}", e);
+    }
+}
+
+/// Remove the changed order from cache (if any).
+/// Database is the source of truth, so failure is just reported.
+async fn invalidate_order<R, C>(order_id: &str, state: &AppState<R, C>)
+where
+    R: Clone,
+    C: CacheOrder + Clone,
+{
+    if let Some(cache) = &state.cache {
+        trace!(order_id, "remove order from cache");
+        if let Err(e) = cache.remove_order(order_id).await {
+            warn!(order_id, "failed to remove order from cache: {}", e);
+        }
+    }
+}
+
+/// Look up the order in cache first (if any) and fall back to database.
+/// An order found in database is put into cache in background.
+async fn get_cached_order<R, C>(order_id: &str, state: &AppState<R, C>) -> Result<Option<Order>>
+where
+    R: StoreOrder + Clone,
+    C: CacheOrder + Clone + Send + 'static,
+{
+    if let Some(cache) = &state.cache {
+        trace!(order_id, "get order from cache");
+        if let Some(order) = cache.get_order(order_id).await? {
+            return Ok(Some(order));
+        }
+    }
+
+    trace!(order_id, "get order from database");
+    let version = state.cache.as_ref().map_or(0, CacheOrder::version);
+    let maybe_order = state.repo.get_order(order_id).await?;
+
+    if let Some(order) = maybe_order.clone() {
+        if let Some(cache) = state.cache.clone() {
+            trace!(?order, "insert order into cache");
+            tokio::spawn(async move { cache.fill_order(&order, version).await });
+        }
+    }
+
+    Ok(maybe_order)
+}
+
+/// Check the request has `Authorization: Bearer` header with the admin token.
+fn authorize_admin<R, C>(headers: &HeaderMap, state: &AppState<R, C>) -> Result<()>
+where
+    R: Clone,
+    C: Clone,
+{
+    let token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.as_bytes().strip_prefix(b"Bearer "));
+
+    match (token, &state.admin_token) {
+        (Some(token), Some(admin_token)) if eq_in_constant_time(token, admin_token.as_bytes()) => {
+            Ok(())
+        }
+        _ => Err(Error::Unauthorized),
+    }
+}
+
+/// Compare secrets without telling how long their common prefix is.
+fn eq_in_constant_time(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// `Json` extractor which keeps the body as it was received.
+pub struct JsonWithPayload<T>(pub T, pub Box<RawValue>);
+
+/// Rejection of `JsonWithPayload` which keeps the body, so it can be stored as rejected.
+pub struct PayloadRejection {
+    pub error: Error,
+    /// It's empty if the body couldn't be read.
+    pub body: Bytes,
+}
+
+impl IntoResponse for PayloadRejection {
+    fn into_response(self) -> Response {
+        self.error.into_response()
+    }
+}
+
+#[async_trait]
+impl<T, S> FromRequest<S> for JsonWithPayload<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = PayloadRejection;
+
+    async fn from_request(req: Request, state: &S) -> std::result::Result<Self, PayloadRejection> {
+        let (parts, body) = req.into_parts();
+        let bytes = Bytes::from_request(Request::from_parts(parts.clone(), body), state)
+            .await
+            .map_err(|e| PayloadRejection {
+                error: JsonRejection::from(e).into(),
+                body: Bytes::new(),
+            })?;
+        let rejected = |error: Error| PayloadRejection {
+            error,
+            body: bytes.clone(),
+        };
+
+        // the usual extractor checks content type and reports errors in the same way
+        let req = Request::from_parts(parts, Body::from(bytes.clone()));
+        let Json(value) = Json::<T>::from_request(req, state)
+            .await
+            .map_err(|e| rejected(e.into()))?;
+        let payload =
+            serde_json::from_slice(&bytes).map_err(|e| rejected(anyhow::Error::from(e).into()))?;
+
+        Ok(Self(value, payload))
+    }
+}
+
+/// Name of the actor of changes made via administrative endpoints.
+const ADMIN_ACTOR: &str = "admin";
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Longer ids of clients are replaced, they are kept in the audit along with changes.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Random id of a request which came without one.
+pub fn new_request_id() -> String {
+    format!("{:032x}", rand::random::<u128>())
+}
+
+/// Whether the id given by the client is short and printable ASCII without spaces.
+fn is_valid_request_id(id: &HeaderValue) -> bool {
+    let id = id.as_bytes();
+    (1..=MAX_REQUEST_ID_LEN).contains(&id.len()) && id.iter().all(u8::is_ascii_graphic)
+}
+
+/// Keep a valid `X-Request-Id` of the request or give it a new one, it's sent back in the response.
+pub async fn request_id(mut request: Request, next: Next) -> Response {
+    let id = match request.headers().get(REQUEST_ID_HEADER) {
+        Some(id) if is_valid_request_id(id) => id.clone(),
+        _ => {
+            let id = HeaderValue::try_from(new_request_id()).expect("hex is a valid header value");
+            request.headers_mut().insert(REQUEST_ID_HEADER, id.clone());
+            id
+        }
+    };
+
+    let mut response = next.run(request).await;
+    response.headers_mut().insert(REQUEST_ID_HEADER, id);
+    response
+}
+
+/// The client is told by its name if it's authenticated, or by its address if it's known.
+#[async_trait]
+impl<S> FromRequestParts<S> for Actor
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _: &S,
+    ) -> std::result::Result<Self, Self::Rejection> {
+        let name = match (
+            parts.extensions.get::<Client>(),
+            parts.extensions.get::<ConnectInfo<SocketAddr>>(),
+        ) {
+            (Some(client), _) => client.name.clone(),
+            (None, Some(info)) => info.0.ip().to_string(),
+            (None, None) => "anonymous".to_owned(),
+        };
+        // the id is checked by the middleware already, unless it's missing
+        let request_id = parts
+            .headers
+            .get(REQUEST_ID_HEADER)
+            .filter(|id| is_valid_request_id(id))
+            .and_then(|id| id.to_str().ok())
+            .map_or_else(new_request_id, str::to_owned);
+
+        Ok(Actor { name, request_id })
+    }
+}
+
+/// Customer whose orders the client may only read, if the client is limited to one.
+#[derive(Debug, Default)]
+pub struct CustomerScope(Option<String>);
+
+impl CustomerScope {
+    /// Orders of other customers are told to be missing, so their ids aren't disclosed.
+    fn allows(&self, order: &Order) -> bool {
+        self.0.as_ref().is_none_or(|id| *id == order.customer_id)
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for CustomerScope
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _: &S,
+    ) -> std::result::Result<Self, Self::Rejection> {
+        Ok(CustomerScope(
+            parts
+                .extensions
+                .get::<Client>()
+                .and_then(|client| client.customer_id.clone()),
+        ))
+    }
 }
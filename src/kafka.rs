@@ -0,0 +1,321 @@
+use std::time::Duration;
+
+use rdkafka::{
+    consumer::{CommitMode, Consumer as _, StreamConsumer},
+    error::KafkaResult,
+    message::{BorrowedMessage, Header, Headers, OwnedHeaders},
+    producer::{FutureProducer, FutureRecord},
+    ClientConfig, Message,
+};
+use serde_json::value::RawValue;
+use tracing::{debug, trace, warn};
+
+use crate::{
+    error::Error,
+    model::{Actor, CreateStatus, Order, RejectionSource},
+    outbox::{backoff, PUBLISH_TIMEOUT},
+    repo::rejected_status,
+    state::{StoreOrder, StoreRejected},
+};
+
+/// Consumption is retried at least that often while brokers fail.
+const MAX_RECV_BACKOFF: Duration = Duration::from_secs(30);
+
+/// What happened to a consumed message.
+#[derive(Debug, PartialEq)]
+pub enum Ingested {
+    Created,
+    /// The order exists already, e.g. the message is consumed again after a crash.
+    Duplicate,
+    /// The message can't become an order, it goes to the dead-letter topic.
+    Rejected(String),
+}
+
+/// Create an order out of the message payload.
+/// Failures of the database itself are retried with backoff until the order is stored,
+/// so the offset is never committed past an order which isn't created.
+pub async fn ingest<R>(repo: &R, payload: Option<&[u8]>, actor: &Actor) -> Ingested
+where
+    R: StoreOrder,
+{
+    let payload = match payload {
+        Some(payload) => payload,
+        None => return Ingested::Rejected("message has no payload".to_owned()),
+    };
+    let (order, payload) = match parse(payload) {
+        Ok(parsed) => parsed,
+        Err(e) => return Ingested::Rejected(format!("invalid order: {}", e)),
+    };
+
+    let mut attempts = 0;
+    loop {
+        let e = match repo
+            .create_order(order.clone(), payload.clone(), actor)
+            .await
+        {
+            Ok(()) => return Ingested::Created,
+            Err(e) => e,
+        };
+
+        match rejected_status(&e) {
+            Some(CreateStatus::Duplicate) => return Ingested::Duplicate,
+            Some(_) => return Ingested::Rejected(e.to_string()),
+            None if matches!(e, Error::InvalidInput(_) | Error::Conflict(_)) => {
+                return Ingested::Rejected(e.to_string())
+            }
+            None => {
+                let delay = backoff(attempts);
+                warn!(?delay, "failed to create consumed order: {}", e);
+                tokio::time::sleep(delay).await;
+                attempts += 1;
+            }
+        }
+    }
+}
+
+fn parse(payload: &[u8]) -> serde_json::Result<(Order, Box<RawValue>)> {
+    Ok((
+        serde_json::from_slice(payload)?,
+        serde_json::from_slice(payload)?,
+    ))
+}
+
+/// Member of a consumer group which creates orders out of messages of the topic.
+/// Offset of a message is committed once its order is created or it's dead-lettered,
+/// so a message is ingested at least once. Dead letters are kept in database as well.
+pub struct Consumer<R> {
+    repo: R,
+    consumer: StreamConsumer,
+    producer: FutureProducer,
+    dead_letter_topic: String,
+    /// Name of the consumer in the audit log.
+    name: String,
+}
+
+impl<R> Consumer<R>
+where
+    R: StoreOrder + StoreRejected,
+{
+    pub fn try_new(
+        repo: R,
+        brokers: &str,
+        group: &str,
+        topic: &str,
+        dead_letter_topic: &str,
+    ) -> KafkaResult<Self> {
+        let consumer: StreamConsumer = ClientConfig::new()
+            .set("bootstrap.servers", brokers)
+            .set("group.id", group)
+            .set("enable.auto.commit", "false")
+            .set("auto.offset.reset", "earliest")
+            .create()?;
+        consumer.subscribe(&[topic])?;
+
+        let producer = ClientConfig::new()
+            .set("bootstrap.servers", brokers)
+            .set("enable.idempotence", "true")
+            .create()?;
+
+        Ok(Self {
+            repo,
+            consumer,
+            producer,
+            dead_letter_topic: dead_letter_topic.to_owned(),
+            name: format!("kafka:{}", group),
+        })
+    }
+
+    /// Consume messages until a message can't be dead-lettered,
+    /// it's consumed again after restart as its offset isn't committed.
+    pub async fn run(self) -> anyhow::Result<()> {
+        debug!(
+            dead_letter_topic = self.dead_letter_topic,
+            "run kafka consumer"
+        );
+
+        let mut failures = 0;
+        loop {
+            // the client reconnects to brokers by itself, errors repeat until it does
+            let message = match self.consumer.recv().await {
+                Ok(message) => {
+                    failures = 0;
+                    message
+                }
+                Err(e) => {
+                    let delay = backoff(failures).min(MAX_RECV_BACKOFF);
+                    warn!(?delay, "failed to consume message: {}", e);
+                    tokio::time::sleep(delay).await;
+                    failures += 1;
+                    continue;
+                }
+            };
+            trace!(
+                topic = message.topic(),
+                partition = message.partition(),
+                offset = message.offset(),
+                "consume message"
+            );
+
+            // the message is told by its position in the topic
+            let actor = Actor {
+                name: self.name.clone(),
+                request_id: format!(
+                    "{}/{}/{}",
+                    message.topic(),
+                    message.partition(),
+                    message.offset()
+                ),
+            };
+            match ingest(&self.repo, message.payload(), &actor).await {
+                Ingested::Created => trace!(offset = message.offset(), "order is created"),
+                Ingested::Duplicate => debug!(offset = message.offset(), "order exists already"),
+                Ingested::Rejected(error) => {
+                    self.dead_letter(&message, &error).await?;
+                    // the dead-letter topic has it anyway, so failure is just reported
+                    let payload = String::from_utf8_lossy(message.payload().unwrap_or_default());
+                    if let Err(e) = self
+                        .repo
+                        .reject_order(RejectionSource::Queue, &payload, &error)
+                        .await
+                    {
+                        warn!("failed to keep rejected order: {}", e);
+                    }
+                }
+            }
+
+            self.consumer.commit_message(&message, CommitMode::Async)?;
+        }
+    }
+
+    /// Publish the message as it is to the dead-letter topic along with the reason and its origin.
+    async fn dead_letter(&self, message: &BorrowedMessage<'_>, error: &str) -> anyhow::Result<()> {
+        warn!(
+            topic = message.topic(),
+            partition = message.partition(),
+            offset = message.offset(),
+            "dead-letter message: {}",
+            error
+        );
+
+        let partition = message.partition().to_string();
+        let offset = message.offset().to_string();
+        let mut headers = OwnedHeaders::new()
+            .insert(Header {
+                key: "error",
+                value: Some(error),
+            })
+            .insert(Header {
+                key: "source-topic",
+                value: Some(message.topic()),
+            })
+            .insert(Header {
+                key: "source-partition",
+                value: Some(&partition),
+            })
+            .insert(Header {
+                key: "source-offset",
+                value: Some(&offset),
+            });
+        if let Some(original) = message.headers() {
+            for header in original.iter() {
+                headers = headers.insert(header);
+            }
+        }
+
+        let mut record = FutureRecord::<[u8], [u8]>::to(&self.dead_letter_topic).headers(headers);
+        if let Some(key) = message.key() {
+            record = record.key(key);
+        }
+        if let Some(payload) = message.payload() {
+            record = record.payload(payload);
+        }
+
+        self.producer
+            .send(record, PUBLISH_TIMEOUT)
+            .await
+            .map_err(|(e, _)| e)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    use crate::{mock::MockStore, model::demo_order};
+
+    fn payload() -> Vec<u8> {
+        serde_json::to_vec(&demo_order("b563feb7b2b84b6test", 0)).unwrap()
+    }
+
+    /// Repository which fails to create orders with the given errors first.
+    struct MockRepo {
+        errors: Mutex<Vec<Error>>,
+    }
+
+    fn actor() -> Actor {
+        Actor {
+            name: "kafka:l0-demo".to_owned(),
+            request_id: "orders/0/42".to_owned(),
+        }
+    }
+
+    impl MockRepo {
+        fn failing(errors: Vec<Error>) -> Self {
+            Self {
+                errors: Mutex::new(errors),
+            }
+        }
+    }
+
+    impl MockStore for MockRepo {
+        async fn create_order(
+            &self,
+            order: Order,
+            payload: Box<RawValue>,
+            _: &Actor,
+        ) -> Result<(), Error> {
+            assert_eq!(order.order_uid, "b563feb7b2b84b6test");
+            assert!(payload.get().contains("b563feb7b2b84b6test"));
+            match self.errors.lock().unwrap().pop() {
+                Some(e) => Err(e),
+                None => Ok(()),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn ingest_order() {
+        let repo = MockRepo::failing(vec![]);
+        assert_eq!(
+            ingest(&repo, Some(&payload()), &actor()).await,
+            Ingested::Created
+        );
+    }
+
+    #[tokio::test]
+    async fn reject_poison_message() {
+        let repo = MockRepo::failing(vec![]);
+        assert_eq!(
+            ingest(&repo, None, &actor()).await,
+            Ingested::Rejected("message has no payload".to_owned())
+        );
+        assert!(matches!(
+            ingest(&repo, Some(b"{\"order_uid\": 42}"), &actor()).await,
+            Ingested::Rejected(error) if error.starts_with("invalid order")
+        ));
+    }
+
+    #[tokio::test]
+    async fn retry_failure_of_database() {
+        let repo = MockRepo::failing(vec![Error::Other(anyhow::anyhow!("connection is lost"))]);
+        assert_eq!(
+            ingest(&repo, Some(&payload()), &actor()).await,
+            Ingested::Created
+        );
+        assert!(repo.errors.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,19 @@
+pub mod auth;
+pub mod cache;
+pub mod cli;
+pub mod codec;
+pub mod dto;
+pub mod error;
+pub mod handler;
+#[cfg(feature = "kafka")]
+pub mod kafka;
+pub mod limit;
+#[cfg(test)]
+mod mock;
+pub mod model;
+pub mod outbox;
+pub mod repo;
+pub mod router;
+pub mod state;
+pub mod tls;
+pub mod webhook;
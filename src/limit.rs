@@ -0,0 +1,188 @@
+use std::{
+    net::SocketAddr,
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    middleware::Next,
+    response::Response,
+};
+use lru::LruCache;
+use tracing::warn;
+
+use crate::{auth::Client, cache::RedisCache, error::Error, state::AppState};
+
+/// Number of buckets kept in memory, the least recently used one is dropped over it.
+const MAX_BUCKETS: usize = 10_000;
+
+/// Token buckets of clients. They are kept in Redis if it's configured,
+/// so instances limit clients together, otherwise every instance limits them on its own.
+#[derive(Clone)]
+pub struct RateLimiter {
+    buckets: Arc<Mutex<LruCache<String, Bucket>>>,
+    redis: Option<RedisCache>,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        let capacity = NonZeroUsize::new(MAX_BUCKETS).expect("capacity isn't zero");
+        Self {
+            buckets: Arc::new(Mutex::new(LruCache::new(capacity))),
+            redis: None,
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl RateLimiter {
+    /// Keep buckets in Redis, the ones in memory are used while it's unavailable.
+    pub fn with_redis(mut self, redis: RedisCache) -> Self {
+        self.redis = Some(redis);
+        self
+    }
+
+    /// Take a token of the client, whose bucket holds `per_minute` tokens
+    /// and is refilled at that rate. Returns how long to wait for a token if there is none.
+    pub async fn acquire(&self, client: &str, per_minute: u32) -> Result<(), Duration> {
+        if let Some(redis) = &self.redis {
+            match redis.take_token(client, per_minute).await {
+                Ok(None) => return Ok(()),
+                Ok(Some(wait)) => return Err(wait),
+                Err(e) => warn!(
+                    cache = "redis",
+                    "failed to take token of {}, limit in memory: {}", client, e
+                ),
+            }
+        }
+
+        self.acquire_at(client, per_minute, Instant::now())
+    }
+
+    fn acquire_at(&self, client: &str, per_minute: u32, now: Instant) -> Result<(), Duration> {
+        let capacity = f64::from(per_minute);
+        let per_second = capacity / 60.0;
+
+        // a dropped bucket is refilled at once, which is the price of bounded memory
+        let mut buckets = self.buckets.lock().expect("rate limiter isn't poisoned");
+        let bucket = buckets.get_or_insert_mut(client.to_owned(), || Bucket {
+            tokens: capacity,
+            updated_at: now,
+        });
+
+        let elapsed = now.saturating_duration_since(bucket.updated_at);
+        bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * per_second).min(capacity);
+        bucket.updated_at = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / per_second))
+        }
+    }
+}
+
+/// Limit requests from an IP at the rate of IPs or the default one.
+/// It goes before authentication, so guessing of keys and tokens is limited too.
+pub async fn limit_ips<R, C>(
+    State(state): State<AppState<R, C>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, Error>
+where
+    R: Clone,
+    C: Clone,
+{
+    if let Some(per_minute) = state.ip_rate_limit.or(state.rate_limit) {
+        let ip = request
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip().to_string())
+            .unwrap_or_else(|| "unknown".to_owned());
+        state
+            .limiter
+            .acquire(&format!("ip:{}", ip), per_minute)
+            .await
+            .map_err(Error::TooManyRequests)?;
+    }
+    Ok(next.run(request).await)
+}
+
+/// Limit requests of the authenticated client at its own rate or the default one,
+/// requests of anonymous clients are limited by IP only.
+pub async fn limit_requests<R, C>(
+    State(state): State<AppState<R, C>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, Error>
+where
+    R: Clone,
+    C: Clone,
+{
+    let Some(client) = request.extensions().get::<Client>() else {
+        return Ok(next.run(request).await);
+    };
+
+    if let Some(per_minute) = client.rate_limit.or(state.rate_limit) {
+        state
+            .limiter
+            .acquire(&client.bucket, per_minute)
+            .await
+            .map_err(Error::TooManyRequests)?;
+    }
+    Ok(next.run(request).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refill_bucket_over_time() {
+        let limiter = RateLimiter::default();
+        let start = Instant::now();
+
+        assert_eq!(limiter.acquire_at("a", 2, start), Ok(()));
+        assert_eq!(limiter.acquire_at("a", 2, start), Ok(()));
+        assert_eq!(
+            limiter.acquire_at("a", 2, start),
+            Err(Duration::from_secs(30))
+        );
+        // buckets of clients are apart
+        assert_eq!(limiter.acquire_at("b", 2, start), Ok(()));
+
+        let later = start + Duration::from_secs(20);
+        assert_eq!(
+            limiter.acquire_at("a", 2, later),
+            Err(Duration::from_secs(10))
+        );
+        assert_eq!(
+            limiter.acquire_at("a", 2, later + Duration::from_secs(10)),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn drop_least_recently_used_buckets() {
+        let limiter = RateLimiter::default();
+        let start = Instant::now();
+
+        for i in 0..=MAX_BUCKETS {
+            assert_eq!(limiter.acquire_at(&i.to_string(), 1, start), Ok(()));
+        }
+        assert_eq!(limiter.buckets.lock().unwrap().len(), MAX_BUCKETS);
+
+        // the first bucket is dropped, so it's full again, unlike the last one
+        assert_eq!(limiter.acquire_at("0", 1, start), Ok(()));
+        assert!(limiter
+            .acquire_at(&MAX_BUCKETS.to_string(), 1, start)
+            .is_err());
+    }
+}
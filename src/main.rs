@@ -1,27 +1,41 @@
-mod cache;
-mod cli;
-mod dto;
-mod error;
-mod handler;
-mod model;
-mod repo;
-mod state;
-
-use std::{env, net::SocketAddr};
+use std::{env, net::SocketAddr, path::Path, time::Duration};
 
 use anyhow::Ok;
-use axum::{
-    routing::{get, post},
-    Router,
-};
 use clap::Parser;
-use cli::Cli;
-use state::{CacheOrder, StoreOrder};
-use tokio::{net::TcpListener, runtime as tokio_runtime};
-use tracing::{debug, info};
+use serde_json::value::RawValue;
+use tokio::{
+    fs::File,
+    io::{AsyncBufReadExt, BufReader},
+    net::TcpListener,
+    runtime as tokio_runtime,
+    sync::broadcast,
+};
+use tracing::{debug, info, warn};
 use tracing_subscriber::EnvFilter;
 
-use crate::{cache::RedisCache, repo::PostgresRepo, state::AppState};
+#[cfg(feature = "kafka")]
+use l_0_demo::kafka::Consumer;
+use l_0_demo::{
+    auth::{self, JwtVerifier},
+    cache::{LayeredCache, MemoryCache, RedisCache, RedisTopology},
+    cli::{ApiKeyCommand, CacheMode, Cli, Command},
+    codec::Codec,
+    handler,
+    limit::RateLimiter,
+    model::{Actor, Order, OrderEvent, RejectionSource},
+    outbox::{Relay, Sink},
+    repo::PostgresRepo,
+    router::app_with_state,
+    state::{AppState, StoreRejected},
+    tls::{Reloader, TlsFiles},
+    webhook::Dispatcher,
+};
+
+/// How many events a slow subscriber of the feed may lag behind before it replays them.
+const EVENTS_CAPACITY: usize = 1024;
+
+/// How often old rows are removed.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(3600);
 
 fn main() {
     // parse arguments
@@ -31,32 +45,136 @@ fn main() {
 
     // main task of server
     let run = async {
+        // or just import orders and exit
+        if let Some(Command::Import { path, chunk_size }) = &cli.command {
+            let postgres = PostgresRepo::try_new(&cli.db_params)
+                .await?
+                .with_copy_threshold(cli.db_copy_threshold)
+                .with_normalized(cli.db_normalized);
+            return import_orders(&postgres, path, chunk_size.get()).await;
+        }
+        // or manage api keys
+        if let Some(Command::ApiKey { command }) = &cli.command {
+            let postgres = PostgresRepo::try_new(&cli.db_params).await?;
+            return manage_api_keys(&postgres, command).await;
+        }
+        // or consume orders from kafka
+        #[cfg(feature = "kafka")]
+        if let Some(Command::Consume {
+            brokers,
+            group,
+            topic,
+            dead_letter_topic,
+        }) = &cli.command
+        {
+            info!(brokers, group, topic, "consume orders from kafka");
+            let postgres = PostgresRepo::try_new(&cli.db_params)
+                .await?
+                .with_copy_threshold(cli.db_copy_threshold)
+                .with_normalized(cli.db_normalized);
+            let consumer = Consumer::try_new(postgres, brokers, group, topic, dead_letter_topic)?;
+            return consumer.run().await;
+        }
+
         let listener = {
             let addr = SocketAddr::from((cli.ip, cli.port));
             TcpListener::bind(addr).await?
         };
+        // serve HTTPS with certificates from files (optional)
+        let maybe_tls = match (&cli.tls_cert, &cli.tls_key) {
+            (Some(cert), Some(key)) => {
+                let files = TlsFiles {
+                    cert: cert.clone(),
+                    key: key.clone(),
+                    client_ca: cli.tls_client_ca.clone(),
+                };
+                Some(
+                    Reloader::try_new(files)?
+                        .with_interval(Duration::from_secs(cli.tls_reload_interval)),
+                )
+            }
+            _ => None,
+        };
 
         let app = {
             info!(database = "postgres", cache = "redis");
 
             // setup and get connection to db
-            let postgres = PostgresRepo::try_new(&cli.db_params).await?;
+            let postgres = PostgresRepo::try_new(&cli.db_params)
+                .await?
+                .with_copy_threshold(cli.db_copy_threshold)
+                .with_normalized(cli.db_normalized);
+            // publish events of the outbox (optional)
+            if let Some(url) = &cli.outbox_sink {
+                let relay = Relay::new(postgres.clone(), Sink::try_new(url).await?)
+                    .with_batch_size(cli.outbox_batch_size)
+                    .with_interval(Duration::from_millis(cli.outbox_poll_interval));
+                tokio::spawn(relay.run());
+            }
+            // forget old events and rejections, so their tables don't grow forever
+            tokio::spawn(prune(
+                postgres.clone(),
+                Duration::from_secs(cli.outbox_retention * 3600),
+                Duration::from_secs(cli.rejected_retention * 3600),
+            ));
+            // deliver events to webhooks
+            let dispatcher = Dispatcher::try_new(postgres.clone())?
+                .with_interval(Duration::from_millis(cli.webhook_poll_interval));
+            tokio::spawn(dispatcher.run());
+            // feed of events committed by any instance for streaming
+            let (events, _) = broadcast::channel(EVENTS_CAPACITY);
+            postgres.listen_events(events.clone());
             // setup and get connection to cache service (optional)
             let maybe_redis = {
                 let mut service = None;
-                if let Some(params) = cli.cache_params.as_ref() {
-                    service.replace(RedisCache::try_new(params).await?);
+                if let Some(topology) = cache_topology(&cli)? {
+                    let codec = Codec::new(cli.cache_codec, cli.cache_compress);
+                    service.replace(RedisCache::try_new(&topology, codec).await?);
                 }
                 service
             };
-            // grab all services into one state
-            let state = AppState::new(postgres, maybe_redis);
+            // setup in-process cache (optional)
+            let maybe_memory = cli
+                .cache_l1_capacity
+                .map(|capacity| MemoryCache::new(capacity, Duration::from_secs(cli.cache_l1_ttl)));
+            let jwt = jwt_verifier(&cli)?;
+            // share buckets of clients between instances via redis (if any)
+            let limiter = match &maybe_redis {
+                Some(redis) => RateLimiter::default().with_redis(redis.clone()),
+                None => RateLimiter::default(),
+            };
+            // grab all services into one state and
             // extract it into separate fn for easy testing and cleaner code
-            app_with_state(state)
+            match (maybe_redis, maybe_memory) {
+                (Some(redis), Some(memory)) => {
+                    redis.listen_invalidations(memory.clone());
+                    let cache = LayeredCache::new(memory, redis);
+                    let state = AppState::new(postgres, Some(cache));
+                    app_with_state(configure(state, &cli, jwt, limiter, events))
+                }
+                (maybe_redis, None) => {
+                    let state = AppState::new(postgres, maybe_redis);
+                    app_with_state(configure(state, &cli, jwt, limiter, events))
+                }
+                (None, maybe_memory) => {
+                    let state = AppState::new(postgres, maybe_memory);
+                    app_with_state(configure(state, &cli, jwt, limiter, events))
+                }
+            }
         };
 
+        let service = app.into_make_service_with_connect_info::<SocketAddr>();
+        if let Some(reloader) = maybe_tls {
+            let config = reloader.config();
+            tokio::spawn(reloader.run());
+
+            info!(tls = true, "start listening on {:?}:{}", cli.ip, cli.port);
+            return Ok(axum_server::from_tcp_rustls(listener.into_std()?, config)
+                .serve(service)
+                .await?);
+        }
         info!("start listening on {:?}:{}", cli.ip, cli.port);
-        Ok(axum::serve(listener, app).await?)
+        Ok(axum::serve(listener, service).await?)
     };
 
     // setup runtime
@@ -77,22 +195,183 @@ fn main() {
     .enable_all()
     .build()
     .expect("failed to build tokio runtime")
-    .block_on(async { run.await.expect("failed to serve API") })
+    .block_on(async { run.await.expect("failed to run") })
+}
+
+/// Create, list or revoke API keys, a created key is printed once.
+async fn manage_api_keys(repo: &PostgresRepo, command: &ApiKeyCommand) -> anyhow::Result<()> {
+    match command {
+        ApiKeyCommand::Create {
+            name,
+            scopes,
+            rate_limit,
+        } => {
+            let key = auth::generate_api_key();
+            repo.create_api_key(
+                name,
+                &auth::hash_api_key(key.as_bytes()),
+                scopes,
+                *rate_limit,
+            )
+            .await?;
+            // it's the only output, so the key can be captured by scripts
+            println!("{}", key);
+        }
+        ApiKeyCommand::Revoke { name } => {
+            if !repo.revoke_api_key(name).await? {
+                anyhow::bail!("there is no api key named '{}'", name);
+            }
+            info!(name, "revoked api key");
+        }
+        ApiKeyCommand::List => {
+            for key in repo.get_api_keys().await? {
+                println!("{}", serde_json::to_string(&key)?);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Remove old events from the outbox and old rejected orders once in a while.
+/// Every instance does it, they skip rows being removed by others.
+async fn prune(repo: PostgresRepo, events_retention: Duration, rejected_retention: Duration) {
+    let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+    loop {
+        interval.tick().await;
+        match repo.prune_events(events_retention).await {
+            std::result::Result::Ok(0) => {}
+            std::result::Result::Ok(pruned) => {
+                info!(
+                    "removed {} events older than {:?}",
+                    pruned, events_retention
+                )
+            }
+            Err(e) => warn!("failed to remove old events: {}", e),
+        }
+        match repo.prune_rejected_orders(rejected_retention).await {
+            std::result::Result::Ok(0) => {}
+            std::result::Result::Ok(pruned) => {
+                info!(
+                    "removed {} rejected orders older than {:?}",
+                    pruned, rejected_retention
+                )
+            }
+            Err(e) => warn!("failed to remove old rejected orders: {}", e),
+        }
+    }
+}
+
+/// Read orders line by line and import them in chunks.
+async fn import_orders(repo: &PostgresRepo, path: &Path, chunk_size: usize) -> anyhow::Result<()> {
+    // all the orders of the import are changed by the same request
+    let actor = Actor {
+        name: "import".to_owned(),
+        request_id: handler::new_request_id(),
+    };
+    info!(
+        request_id = actor.request_id,
+        "import orders from {:?}", path
+    );
+
+    let mut lines = BufReader::new(File::open(path).await?).lines();
+    let mut chunk = Vec::with_capacity(chunk_size);
+    let (mut line_number, mut imported, mut rejected) = (0, 0, 0);
+
+    loop {
+        let maybe_line = lines.next_line().await?;
+        if let Some(line) = &maybe_line {
+            line_number += 1;
+            if !line.trim().is_empty() {
+                match serde_json::from_str::<Order>(line) {
+                    std::result::Result::Ok(order) => {
+                        let payload = RawValue::from_string(line.clone())?;
+                        chunk.push((order, payload));
+                    }
+                    // invalid orders are kept aside to be fixed
+                    Err(e) => {
+                        warn!("invalid order at line {}: {}", line_number, e);
+                        repo.reject_order(RejectionSource::Import, line, &e.to_string())
+                            .await?;
+                        rejected += 1;
+                    }
+                }
+            }
+        }
+
+        if chunk.len() == chunk_size || (maybe_line.is_none() && !chunk.is_empty()) {
+            imported += chunk.len();
+            let orders = std::mem::replace(&mut chunk, Vec::with_capacity(chunk_size));
+            repo.import_orders(orders, &actor).await?;
+            info!("imported {} orders", imported);
+        }
+
+        if maybe_line.is_none() {
+            if rejected > 0 {
+                warn!("rejected {} invalid orders", rejected);
+            }
+            return Ok(());
+        }
+    }
+}
+
+fn jwt_verifier(cli: &Cli) -> anyhow::Result<Option<JwtVerifier>> {
+    // audience and issuer are required by cli along with keys
+    let (Some(audience), Some(issuer)) = (&cli.jwt_audience, &cli.jwt_issuer) else {
+        return Ok(None);
+    };
+
+    Ok(match (&cli.jwt_jwks, &cli.jwt_key) {
+        (Some(path), _) => Some(JwtVerifier::from_jwks(
+            path,
+            cli.jwt_algorithm,
+            audience,
+            issuer,
+        )?),
+        (None, Some(path)) => Some(JwtVerifier::from_key(
+            path,
+            cli.jwt_algorithm,
+            audience,
+            issuer,
+        )?),
+        (None, None) => None,
+    })
 }
 
-fn app_with_state(
-    state: AppState<
-        impl StoreOrder + Clone + Send + Sync + 'static,
-        impl CacheOrder + Clone + Send + Sync + 'static,
-    >,
-) -> Router {
-    Router::new()
-        .route("/order", post(handler::create_order))
-        .route("/orders/:order_id", get(handler::get_order))
-        .route("/orders/:order_id/delivery", get(handler::get_delivery))
-        .route("/orders/:order_id/items", get(handler::get_items))
-        .route("/orders/:order_id/payment", get(handler::get_payment))
-        .with_state(state)
+/// Apply options of the service to the state, whichever cache it has.
+fn configure<C: Clone>(
+    state: AppState<PostgresRepo, C>,
+    cli: &Cli,
+    jwt: Option<JwtVerifier>,
+    limiter: RateLimiter,
+    events: broadcast::Sender<OrderEvent>,
+) -> AppState<PostgresRepo, C> {
+    state
+        .with_admin_token(cli.admin_token.clone())
+        .with_require_api_key(cli.require_api_key)
+        .with_jwt(jwt)
+        .with_rate_limit(cli.rate_limit, limiter)
+        .with_ip_rate_limit(cli.ip_rate_limit)
+        .with_max_body_size(cli.max_body_size)
+        .with_events(events)
+}
+
+fn cache_topology(cli: &Cli) -> anyhow::Result<Option<RedisTopology>> {
+    let nodes = cli.cache_params.clone();
+
+    Ok(match (cli.cache_mode, nodes.as_slice()) {
+        (_, []) => None,
+        (CacheMode::Single, [params]) => Some(RedisTopology::Single(params.clone())),
+        (CacheMode::Single, _) => anyhow::bail!("single cache mode expects exactly one node"),
+        (CacheMode::Sentinel, _) => Some(RedisTopology::Sentinel {
+            master_name: cli
+                .cache_sentinel_master
+                .clone()
+                .expect("sentinel master is required by cli"),
+            nodes,
+        }),
+        (CacheMode::Cluster, _) => Some(RedisTopology::Cluster(nodes)),
+    })
 }
 
 fn setup_tracing() {
@@ -118,87 +397,37 @@ fn setup_tracing() {
 
 #[cfg(test)]
 mod tests {
-    use axum::{
-        body::Body,
-        http::{Request, StatusCode},
-    };
-    use tower::ServiceExt;
-
-    use crate::{
-        error::Error,
-        model::{Delivery, Item, Order, Payment},
-        state::{AppState, CacheOrder, StoreOrder},
-    };
-
-    use super::app_with_state;
+    use clap::Parser;
 
-    #[rustfmt::skip]
-    impl CacheOrder for () {
-        async fn get_order(&self, _: &str) -> Result<Option<Order>, Error> { unreachable!() }
-        async fn insert_order(&self, _: &Order) -> Result<(), Error> { unreachable!() }
-    }
+    use l_0_demo::{cache::RedisTopology, cli::Cli};
 
-    #[tokio::test]
-    async fn get_items_of_no_order() {
-        #[derive(Clone)]
-        struct MockRepo;
-
-        #[rustfmt::skip]
-        impl StoreOrder for MockRepo {
-            async fn create_order(&self, _: Order) -> Result<(), Error> { unreachable!() }
-            async fn get_order(&self, _: &str) -> Result<Option<Order>, Error> { unreachable!() }
-            async fn get_delivery(&self, _: &str) -> Result<Option<Delivery>, Error> { unreachable!() }
-            async fn get_payment(&self, _: &str) -> Result<Option<Payment>, Error> { unreachable!() }
-
-            // the only implementation we need
-            async fn get_items(&self, _: &str) -> Result<Option<Vec<Item>>, Error> {
-                Ok(None)
-            }
-        }
+    use super::cache_topology;
 
-        let state = AppState::new(MockRepo, Option::<()>::None);
-        let response = app_with_state(state)
-            .oneshot(
-                Request::builder()
-                    .uri("/orders/defenetly_does_not_exist_order_id/items")
-                    .body(Body::empty())
-                    .unwrap(),
+    #[test]
+    fn cache_topology_from_cli() {
+        let parse = |args: &str| {
+            let cli = Cli::try_parse_from(
+                ["l_0_demo", "--db-params=postgresql://test"]
+                    .into_iter()
+                    .chain(args.split_whitespace()),
             )
-            .await
-            .unwrap();
-
-        assert_eq!(response.status(), StatusCode::NOT_FOUND);
-    }
-
-    #[tokio::test]
-    async fn get_items_of_order_without_items() {
-        #[derive(Clone)]
-        struct MockRepo;
-
-        #[rustfmt::skip]
-        impl StoreOrder for MockRepo {
-            async fn create_order(&self, _: Order) -> Result<(), Error> { unreachable!() }
-            async fn get_order(&self, _: &str) -> Result<Option<Order>, Error> { unreachable!() }
-            async fn get_delivery(&self, _: &str) -> Result<Option<Delivery>, Error> { unreachable!() }
-            async fn get_payment(&self, _: &str) -> Result<Option<Payment>, Error> { unreachable!() }
-
-            // the only implementation we need
-            async fn get_items(&self, _: &str) -> Result<Option<Vec<Item>>, Error> {
-                Ok(Some(vec![]))
-            }
-        }
-
-        let state = AppState::new(MockRepo, Option::<()>::None);
-        let response = app_with_state(state)
-            .oneshot(
-                Request::builder()
-                    .uri("/orders/b563feb7b2b84b6test/items")
-                    .body(Body::empty())
-                    .unwrap(),
-            )
-            .await
             .unwrap();
+            cache_topology(&cli)
+        };
 
-        assert_eq!(response.status(), StatusCode::OK);
+        assert!(parse("").unwrap().is_none());
+        assert!(matches!(
+            parse("--cache-params=redis://a").unwrap(),
+            Some(RedisTopology::Single(node)) if node == "redis://a"
+        ));
+        assert!(parse("--cache-params=redis://a,redis://b").is_err());
+        assert!(matches!(
+            parse("--cache-params=redis://a,redis://b --cache-mode=cluster").unwrap(),
+            Some(RedisTopology::Cluster(nodes)) if nodes.len() == 2
+        ));
+        assert!(matches!(
+            parse("--cache-params=redis://a --cache-mode=sentinel --cache-sentinel-master=main").unwrap(),
+            Some(RedisTopology::Sentinel { master_name, .. }) if master_name == "main"
+        ));
     }
 }
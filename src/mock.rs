@@ -0,0 +1,124 @@
+//! Stores and caches for tests: a test implements only the methods it exercises
+//! and a call of any other method fails it.
+
+use std::{future::Future, time::Duration};
+
+use serde_json::value::RawValue;
+
+use crate::{
+    error::Error,
+    model::{
+        Actor, ApiKey, AttemptOutcome, AuditEntry, BatchMode, CreateOutcome, Delivery, EventType,
+        Item, ItemStatus, Order, OrderEvent, OrderPayload, OrderStatus, Payment, RejectedOrder,
+        RejectionSource, Webhook, WebhookAttempt, WebhookDelivery,
+    },
+    state::{CacheOrder, StoreApiKey, StoreEvent, StoreOrder, StoreRejected, StoreWebhook},
+};
+
+fn unexpected<T>(method: &str) -> T {
+    panic!("unexpected call of {}", method)
+}
+
+/// Declare a mock trait with every method of the given traits, which are implemented by it.
+macro_rules! mock {
+    (
+        $(#[$meta:meta])*
+        pub trait $mock:ident {
+            $(
+                impl $target:ident {
+                    $( fn $name:ident(&self $(, $arg:ident: $ty:ty)*) -> $out:ty; )*
+                }
+            )*
+        }
+    ) => {
+        $(#[$meta])*
+        pub trait $mock {
+            $($(
+                // boxed the same as arguments of the stores
+                #[allow(clippy::boxed_local)]
+                fn $name(&self $(, $arg: $ty)*) -> impl Future<Output = Result<$out, Error>> + Send {
+                    $( let _ = $arg; )*
+                    async move { unexpected(stringify!($name)) }
+                }
+            )*)*
+        }
+
+        $(
+            impl<T: $mock> $target for T {
+                $(
+                    fn $name(&self $(, $arg: $ty)*) -> impl Future<Output = Result<$out, Error>> + Send {
+                        $mock::$name(self $(, $arg)*)
+                    }
+                )*
+            }
+        )*
+    };
+}
+
+mock! {
+    /// Repository of orders along with their events, webhooks, rejections and API keys.
+    pub trait MockStore {
+        impl StoreOrder {
+            fn create_order(&self, order: Order, payload: Box<RawValue>, actor: &Actor) -> ();
+            fn create_orders(&self, orders: Vec<(Order, Box<RawValue>)>, mode: BatchMode, actor: &Actor) -> Vec<CreateOutcome>;
+            fn update_order(&self, order: Order, payload: Box<RawValue>, actor: &Actor) -> bool;
+            fn delete_order(&self, order_id: &str, actor: &Actor) -> bool;
+            fn get_order(&self, order_id: &str) -> Option<Order>;
+            fn get_orders(&self, order_ids: &[String]) -> Vec<Order>;
+            fn get_order_by_track_number(&self, track_number: &str) -> Option<Order>;
+            fn get_order_by_transaction(&self, transaction: &str) -> Option<Order>;
+            fn get_orders_by_customer(&self, customer_id: &str, limit: i64, offset: i64) -> Vec<Order>;
+            fn get_order_payload(&self, order_id: &str) -> Option<OrderPayload>;
+            fn get_delivery(&self, order_id: &str) -> Option<Delivery>;
+            fn get_items(&self, order_id: &str) -> Option<Vec<Item>>;
+            fn get_payment(&self, order_id: &str) -> Option<Payment>;
+            fn update_order_status(&self, order_id: &str, status: OrderStatus, actor: &Actor) -> Option<OrderStatus>;
+            fn update_item_status(&self, order_id: &str, chrt_id: i32, status: ItemStatus, actor: &Actor) -> Option<Vec<Item>>;
+            fn get_order_audit(&self, order_id: &str, limit: i64, offset: i64) -> Vec<AuditEntry>;
+        }
+        impl StoreEvent {
+            fn claim_events(&self, limit: i64, lease: Duration) -> Vec<OrderEvent>;
+            fn get_events(&self, event_type: EventType, after_id: i64, limit: i64) -> Vec<OrderEvent>;
+            fn complete_event(&self, event_id: i64) -> ();
+            fn retry_event(&self, event_id: i64, error: &str, delay: Duration) -> ();
+        }
+        impl StoreWebhook {
+            fn create_webhook(&self, url: &str, event_types: &[EventType], secret: &str) -> Webhook;
+            fn get_webhooks(&self) -> Vec<Webhook>;
+            fn get_webhook(&self, id: i32) -> Option<Webhook>;
+            fn delete_webhook(&self, id: i32) -> bool;
+            fn get_webhook_attempts(&self, id: i32, limit: i64, offset: i64) -> Option<Vec<WebhookAttempt>>;
+            fn claim_deliveries(&self, limit: i64, lease: Duration) -> Vec<WebhookDelivery>;
+            fn record_attempt(&self, delivery_id: i64, outcome: &AttemptOutcome) -> ();
+        }
+        impl StoreRejected {
+            fn reject_order(&self, source: RejectionSource, payload: &str, error: &str) -> ();
+            fn get_rejected_orders(&self, limit: i64, offset: i64) -> Vec<RejectedOrder>;
+            fn get_rejected_order(&self, id: i64) -> Option<RejectedOrder>;
+            fn replay_rejected_order(&self, id: i64, order: Order, payload: Box<RawValue>, actor: &Actor) -> bool;
+            fn delete_rejected_order(&self, id: i64) -> bool;
+        }
+        impl StoreApiKey {
+            fn get_api_key(&self, key_hash: &str) -> Option<ApiKey>;
+        }
+    }
+}
+
+mock! {
+    /// Cache of orders.
+    pub trait MockCache {
+        impl CacheOrder {
+            fn get_order(&self, order_id: &str) -> Option<Order>;
+            fn get_order_json(&self, order_id: &str) -> Option<Vec<u8>>;
+            fn get_orders(&self, order_ids: &[String]) -> Vec<Option<Order>>;
+            fn insert_order(&self, order: &Order) -> ();
+            fn remove_order(&self, order_id: &str) -> ();
+        }
+    }
+}
+
+/// Cache which is never used.
+#[derive(Clone)]
+pub struct NoCache;
+
+impl MockCache for NoCache {}
@@ -1,9 +1,11 @@
 pub use self::percent::Percent;
 
+use std::{fmt, time::Duration};
+
 use chrono::{DateTime, Utc};
 use postgres_types::{FromSql, ToSql};
-use redis_macros::{FromRedisValue, ToRedisArgs};
 use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::{json, value::RawValue, Map, Value};
 use serde_repr::{Deserialize_repr, Serialize_repr};
 
 // reserve a type for operations with money
@@ -46,11 +48,324 @@ pub enum Locale {
 }
 
 // status codes are typically known in advance, let's reserve an enum
-#[derive(Clone, Debug, PartialEq, Serialize_repr, Deserialize_repr, ToSql, FromSql)]
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Serialize_repr, Deserialize_repr, ToSql, FromSql,
+)]
 #[postgres(name = "item_status")]
 #[repr(u16)]
 pub enum ItemStatus {
-    StatusCode = 202,
+    #[default]
+    Accepted = 202,
+    Assembled = 203,
+    Shipped = 204,
+    Delivered = 205,
+    Returned = 206,
+    Cancelled = 207,
+}
+
+impl ItemStatus {
+    /// Check if an item may move from this status to the `next` one.
+    pub fn can_become(self, next: ItemStatus) -> bool {
+        use ItemStatus::*;
+
+        matches!(
+            (self, next),
+            (Accepted, Assembled | Cancelled)
+                | (Assembled, Shipped | Cancelled)
+                | (Shipped, Delivered | Returned)
+                | (Delivered, Returned)
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize, ToSql, FromSql)]
+#[postgres(name = "order_status")]
+#[serde(rename_all = "snake_case")]
+pub enum OrderStatus {
+    #[default]
+    Created,
+    Paid,
+    InDelivery,
+    Completed,
+    Cancelled,
+}
+
+impl OrderStatus {
+    /// Check if an order may move from this status to the `next` one.
+    pub fn can_become(self, next: OrderStatus) -> bool {
+        use OrderStatus::*;
+
+        matches!(
+            (self, next),
+            (Created, Paid | Cancelled)
+                | (Paid, InDelivery | Cancelled)
+                | (InDelivery, Completed | Cancelled)
+        )
+    }
+}
+
+/// How a batch of orders is written.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BatchMode {
+    /// Either all orders are created or none of them.
+    #[default]
+    Atomic,
+    /// Every order is created on its own, failed ones don't affect the rest.
+    Savepoint,
+}
+
+/// Outcome of an order in a batch.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CreateStatus {
+    Created,
+    /// Order (or its payment) already exists.
+    Duplicate,
+    Invalid,
+    /// Order is fine, but it isn't created since another one failed in atomic mode.
+    Aborted,
+}
+
+/// Outcome of an order created in a batch along with the error which rejected it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CreateOutcome {
+    pub status: CreateStatus,
+    pub error: Option<String>,
+}
+
+impl CreateOutcome {
+    pub fn new(status: CreateStatus) -> Self {
+        Self {
+            status,
+            error: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, ToSql, FromSql)]
+#[postgres(name = "event_type")]
+#[serde(rename_all = "snake_case")]
+pub enum EventType {
+    OrderCreated,
+    OrderUpdated,
+    OrderDeleted,
+    OrderStatusChanged,
+    ItemStatusChanged,
+}
+
+/// Event written along with the change of an order, `data` is the order after it
+/// or the deleted one.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OrderEvent {
+    pub id: i64,
+    #[serde(rename = "type")]
+    pub event_type: EventType,
+    pub order_id: String,
+    pub created_at: DateTime<Utc>,
+    pub data: Box<RawValue>,
+    /// Failed attempts to publish the event so far.
+    #[serde(skip)]
+    pub attempts: i32,
+}
+
+/// Subscription of a partner to events, which are delivered to `url` signed with `secret`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Webhook {
+    pub id: i32,
+    pub url: String,
+    pub event_types: Vec<EventType>,
+    /// It's shown only once the webhook is created.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Event which is due to be delivered to a webhook.
+#[derive(Clone, Debug)]
+pub struct WebhookDelivery {
+    pub id: i64,
+    pub url: String,
+    pub secret: String,
+    pub event: OrderEvent,
+    /// Failed attempts to deliver the event so far.
+    pub attempts: i32,
+}
+
+/// Entry of the delivery log, either `status_code` or `error` tells why an attempt failed.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct WebhookAttempt {
+    pub delivery_id: i64,
+    pub event_id: i64,
+    pub event_type: EventType,
+    pub attempted_at: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status_code: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// How an attempt to deliver an event to a webhook ended.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AttemptOutcome {
+    Delivered {
+        status_code: i32,
+    },
+    /// Delivery is retried after `retry_after`, or it's given up without it.
+    Failed {
+        status_code: Option<i32>,
+        error: String,
+        retry_after: Option<Duration>,
+    },
+}
+
+/// Order as it was received, including fields which aren't mapped to columns.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OrderPayload {
+    pub received_at: DateTime<Utc>,
+    pub payload: Box<RawValue>,
+}
+
+/// Where a rejected order came from.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, ToSql, FromSql)]
+#[postgres(name = "rejection_source")]
+#[serde(rename_all = "snake_case")]
+pub enum RejectionSource {
+    Http,
+    /// Message of Kafka topic.
+    Queue,
+    Import,
+}
+
+/// Order which failed deserialization or validation, kept to be fixed and replayed.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RejectedOrder {
+    pub id: i64,
+    pub source: RejectionSource,
+    pub error: String,
+    pub rejected_at: DateTime<Utc>,
+    /// Payload as it was received, it's omitted in lists.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload: Option<String>,
+}
+
+/// What a client may do with its API key.
+#[derive(
+    Clone, Copy, Debug, PartialEq, Serialize, Deserialize, ToSql, FromSql, clap::ValueEnum,
+)]
+#[postgres(name = "api_scope")]
+pub enum ApiScope {
+    #[serde(rename = "orders:read")]
+    #[value(name = "orders:read")]
+    OrdersRead,
+    #[serde(rename = "orders:write")]
+    #[value(name = "orders:write")]
+    OrdersWrite,
+}
+
+impl fmt::Display for ApiScope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ApiScope::OrdersRead => "orders:read",
+            ApiScope::OrdersWrite => "orders:write",
+        })
+    }
+}
+
+/// API key of a client, the key itself is shown only once it's created.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ApiKey {
+    pub id: i32,
+    pub name: String,
+    pub scopes: Vec<ApiScope>,
+    /// Requests per minute, there is no limit if it's missing.
+    pub rate_limit: Option<i32>,
+    pub created_at: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+/// Who makes a change of orders.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Actor {
+    pub name: String,
+    /// Request (or another unit of work, e.g. a message) the change is made by.
+    pub request_id: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, ToSql, FromSql)]
+#[postgres(name = "audit_operation")]
+#[serde(rename_all = "snake_case")]
+pub enum AuditOperation {
+    Create,
+    Update,
+    Delete,
+    StatusChange,
+    ItemStatusChange,
+}
+
+/// Change of an order, `diff` has an entry per changed value (see `diff`).
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub id: i64,
+    pub order_id: String,
+    pub operation: AuditOperation,
+    pub actor: String,
+    pub request_id: String,
+    pub diff: Box<RawValue>,
+    pub recorded_at: DateTime<Utc>,
+}
+
+/// Difference between two JSON values as an object keyed by JSON pointers of changed values,
+/// e.g. `{"/delivery/city": {"before": "Moscow", "after": "Kazan"}}`.
+/// Objects and arrays are compared by their entries, other values as a whole,
+/// so a created (or deleted) order is a single entry with the empty pointer.
+pub fn diff(before: &Value, after: &Value) -> Map<String, Value> {
+    fn walk(pointer: &mut String, before: &Value, after: &Value, changes: &mut Map<String, Value>) {
+        let mut entry = |pointer: &mut String, key: &str, before: &Value, after: &Value| {
+            let len = pointer.len();
+            pointer.push('/');
+            pointer.push_str(&key.replace('~', "~0").replace('/', "~1"));
+            walk(pointer, before, after, changes);
+            pointer.truncate(len);
+        };
+
+        match (before, after) {
+            (Value::Object(before), Value::Object(after)) => {
+                for (key, value) in before {
+                    entry(pointer, key, value, after.get(key).unwrap_or(&Value::Null));
+                }
+                for (key, value) in after.iter().filter(|(key, _)| !before.contains_key(*key)) {
+                    entry(pointer, key, &Value::Null, value);
+                }
+            }
+            (Value::Array(before), Value::Array(after)) => {
+                for i in 0..before.len().max(after.len()) {
+                    let (old, new) = (before.get(i), after.get(i));
+                    entry(
+                        pointer,
+                        &i.to_string(),
+                        old.unwrap_or(&Value::Null),
+                        new.unwrap_or(&Value::Null),
+                    );
+                }
+            }
+            (before, after) if before != after => {
+                changes.insert(pointer.clone(), json!({ "before": before, "after": after }));
+            }
+            _ => {}
+        }
+    }
+
+    let mut changes = Map::new();
+    walk(&mut String::new(), before, after, &mut changes);
+    changes
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ItemStatusChange {
+    pub status: ItemStatus,
+    pub changed_at: DateTime<Utc>,
 }
 
 // same about the currency
@@ -62,7 +377,7 @@ pub enum Currency {
     RU,
 }
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, FromRedisValue, ToRedisArgs)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Order {
     pub order_uid: String,
     pub track_number: String,
@@ -78,9 +393,35 @@ pub struct Order {
     pub sm_id: i32,
     pub date_created: DateTime<Utc>,
     pub oof_shard: String,
+    #[serde(default)]
+    pub status: OrderStatus,
 }
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, FromRedisValue, ToRedisArgs)]
+impl Order {
+    /// Put the new order and its items into initial statuses,
+    /// they are changed only by transitions afterwards.
+    pub fn reset_statuses(&mut self) {
+        self.status = OrderStatus::default();
+        for item in &mut self.items {
+            item.status = ItemStatus::default();
+        }
+    }
+
+    /// Take statuses of the stored order, which is replaced by this one.
+    /// Items are matched by `chrt_id`, new ones get the initial status.
+    pub fn keep_statuses(&mut self, stored: &Order) {
+        self.status = stored.status;
+        for item in &mut self.items {
+            item.status = stored
+                .items
+                .iter()
+                .find(|stored| stored.chrt_id == item.chrt_id)
+                .map_or_else(ItemStatus::default, |stored| stored.status);
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Delivery {
     #[serde(skip)]
     pub id: Option<i32>,
@@ -95,7 +436,7 @@ pub struct Delivery {
     pub email: String,
 }
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, FromRedisValue, ToRedisArgs)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Payment {
     pub transaction: String,
     pub request_id: String,
@@ -109,7 +450,7 @@ pub struct Payment {
     pub custom_fee: Percent,
 }
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, FromRedisValue, ToRedisArgs)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Item {
     #[serde(skip)]
     pub id: Option<i32>,
@@ -124,6 +465,9 @@ pub struct Item {
     pub nm_id: i32,
     pub brand: String,
     pub status: ItemStatus,
+    // it's kept by database, so input is ignored
+    #[serde(default)]
+    pub status_history: Vec<ItemStatusChange>,
 }
 
 /// Keep number as it is, but check if it starts with '+'.
@@ -142,6 +486,65 @@ where
     })
 }
 
+/// Order of the demo request with the given id and that many copies of its item.
+/// It's shared by tests and benchmarks, so it's not a part of the API.
+#[doc(hidden)]
+pub fn demo_order(order_uid: &str, items: usize) -> Order {
+    let item = Item {
+        id: None,
+        chrt_id: 9934930,
+        track_number: "WBILMTESTTRACK".to_owned(),
+        price: 453,
+        rid: "ab4219087a764ae0btest".to_owned(),
+        name: "Mascaras".to_owned(),
+        sale: Percent::try_from(30).unwrap(),
+        size: "0".to_owned(),
+        total_price: 317,
+        nm_id: 2389212,
+        brand: "Vivienne Sabo".to_owned(),
+        status: ItemStatus::Accepted,
+        status_history: vec![],
+    };
+
+    Order {
+        order_uid: order_uid.to_owned(),
+        track_number: "WBILMTESTTRACK".to_owned(),
+        entry: "WBIL".to_owned(),
+        delivery: Delivery {
+            id: None,
+            name: "Test Testov".to_owned(),
+            phone: "+9720000000".to_owned(),
+            zip: "2639809".to_owned(),
+            city: "Kiryat Mozkin".to_owned(),
+            address: "Ploshad Mira 15".to_owned(),
+            region: "Kraiot".to_owned(),
+            email: "test@gmail.com".to_owned(),
+        },
+        payment: Payment {
+            transaction: order_uid.to_owned(),
+            request_id: String::new(),
+            currency: Currency::USD,
+            provider: "wbpay".to_owned(),
+            amount: 1817,
+            payment_dt: 1637907727,
+            bank: "alpha".to_owned(),
+            delivery_cost: 1500,
+            goods_total: 317,
+            custom_fee: Percent::try_from(0).unwrap(),
+        },
+        items: vec![item; items],
+        locale: Locale::EN,
+        internal_signature: String::new(),
+        customer_id: "test".to_owned(),
+        delivery_service: "meest".to_owned(),
+        shardkey: "9".to_owned(),
+        sm_id: 99,
+        date_created: "2021-11-26T06:22:19Z".parse().unwrap(),
+        oof_shard: "1".to_owned(),
+        status: OrderStatus::Created,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -149,7 +552,6 @@ mod tests {
     use std::fmt::Debug;
 
     use serde::de::DeserializeOwned;
-    use serde_json::{json, value::Value};
 
     fn test_serde<T>(value_from: Value, target: T, value_to: Value)
     where
@@ -162,6 +564,32 @@ mod tests {
         assert_eq!(serde_json::to_value(&target).unwrap(), value_to);
     }
 
+    #[test]
+    fn diff_of_orders() {
+        let before = serde_json::to_value(demo_order("a", 0)).unwrap();
+        let mut order = demo_order("a", 0);
+        order.delivery.city = "Kazan".to_owned();
+        order.status = OrderStatus::Paid;
+        let after = serde_json::to_value(order).unwrap();
+
+        assert_eq!(
+            Value::Object(diff(&before, &after)),
+            json!({
+                "/delivery/city": { "before": "Kiryat Mozkin", "after": "Kazan" },
+                "/status": { "before": "created", "after": "paid" },
+            })
+        );
+        assert!(diff(&after, &after).is_empty());
+        assert_eq!(
+            Value::Object(diff(&Value::Null, &after)),
+            json!({ "": { "before": null, "after": after } })
+        );
+        assert_eq!(
+            Value::Object(diff(&json!({ "a/b": [1] }), &json!({ "a/b": [1, 2] }))),
+            json!({ "/a~1b/1": { "before": null, "after": 2 } })
+        );
+    }
+
     #[test]
     fn serde_locale() {
         test_serde(json!("en"), Locale::EN, json!("en"));
@@ -177,14 +605,125 @@ mod tests {
 
     #[test]
     fn serde_item_status() {
-        test_serde(json!(202), ItemStatus::StatusCode, json!(202));
+        test_serde(json!(202), ItemStatus::Accepted, json!(202));
+        test_serde(json!(203), ItemStatus::Assembled, json!(203));
+        test_serde(json!(204), ItemStatus::Shipped, json!(204));
+        test_serde(json!(205), ItemStatus::Delivered, json!(205));
+        test_serde(json!(206), ItemStatus::Returned, json!(206));
+        test_serde(json!(207), ItemStatus::Cancelled, json!(207));
 
         assert!(serde_json::from_value::<ItemStatus>(json!("202")).is_err());
         assert!(serde_json::from_value::<ItemStatus>(json!(0)).is_err());
+        assert!(serde_json::from_value::<ItemStatus>(json!(208)).is_err());
         assert!(serde_json::from_value::<ItemStatus>(json!(-1)).is_err());
         assert!(serde_json::from_value::<ItemStatus>(json!(u16::MAX as u32 + 1)).is_err());
     }
 
+    #[test]
+    fn item_status_transitions() {
+        use ItemStatus::*;
+
+        assert!(Accepted.can_become(Assembled));
+        assert!(Assembled.can_become(Shipped));
+        assert!(Shipped.can_become(Delivered));
+        assert!(Delivered.can_become(Returned));
+        assert!(Accepted.can_become(Cancelled));
+
+        assert!(!Accepted.can_become(Accepted));
+        assert!(!Accepted.can_become(Delivered));
+        assert!(!Shipped.can_become(Cancelled));
+        assert!(!Cancelled.can_become(Accepted));
+        assert!(!Returned.can_become(Delivered));
+    }
+
+    #[test]
+    fn serde_order_status() {
+        test_serde(json!("created"), OrderStatus::Created, json!("created"));
+        test_serde(json!("paid"), OrderStatus::Paid, json!("paid"));
+        test_serde(
+            json!("in_delivery"),
+            OrderStatus::InDelivery,
+            json!("in_delivery"),
+        );
+        test_serde(
+            json!("completed"),
+            OrderStatus::Completed,
+            json!("completed"),
+        );
+        test_serde(
+            json!("cancelled"),
+            OrderStatus::Cancelled,
+            json!("cancelled"),
+        );
+
+        assert!(serde_json::from_value::<OrderStatus>(json!("Paid")).is_err());
+        assert!(serde_json::from_value::<OrderStatus>(json!("bad")).is_err());
+    }
+
+    #[test]
+    fn order_status_transitions() {
+        use OrderStatus::*;
+
+        assert!(Created.can_become(Paid));
+        assert!(Paid.can_become(InDelivery));
+        assert!(InDelivery.can_become(Completed));
+        assert!(InDelivery.can_become(Cancelled));
+
+        assert!(!Created.can_become(Created));
+        assert!(!Created.can_become(Completed));
+        assert!(!Completed.can_become(Cancelled));
+        assert!(!Cancelled.can_become(Paid));
+    }
+
+    fn item(chrt_id: i32, status: ItemStatus) -> Item {
+        Item {
+            id: None,
+            chrt_id,
+            track_number: "WBILMTESTTRACK".to_owned(),
+            price: 453,
+            rid: "ab4219087a764ae0btest".to_owned(),
+            name: "Mascaras".to_owned(),
+            sale: Percent::try_from(30).unwrap(),
+            size: "0".to_owned(),
+            total_price: 317,
+            nm_id: 2389212,
+            brand: "Vivienne Sabo".to_owned(),
+            status,
+            status_history: vec![],
+        }
+    }
+
+    #[test]
+    fn reset_statuses_of_new_order() {
+        let mut order = demo_order("a", 0);
+        order.status = OrderStatus::Completed;
+        order.items = vec![
+            item(1, ItemStatus::Delivered),
+            item(2, ItemStatus::Cancelled),
+        ];
+        order.reset_statuses();
+
+        assert_eq!(order.status, OrderStatus::Created);
+        assert_eq!(order.items[0].status, ItemStatus::Accepted);
+        assert_eq!(order.items[1].status, ItemStatus::Accepted);
+    }
+
+    #[test]
+    fn keep_statuses_of_stored_order() {
+        let mut stored = demo_order("a", 0);
+        stored.status = OrderStatus::Paid;
+        stored.items = vec![item(1, ItemStatus::Assembled)];
+
+        let mut order = demo_order("a", 0);
+        order.status = OrderStatus::Cancelled;
+        order.items = vec![item(1, ItemStatus::Cancelled), item(2, ItemStatus::Shipped)];
+        order.keep_statuses(&stored);
+
+        assert_eq!(order.status, OrderStatus::Paid);
+        assert_eq!(order.items[0].status, ItemStatus::Assembled);
+        assert_eq!(order.items[1].status, ItemStatus::Accepted);
+    }
+
     #[test]
     fn serde_currency() {
         test_serde(json!("USD"), Currency::USD, json!("USD"));
@@ -298,7 +837,8 @@ mod tests {
                     total_price: 317,
                     nm_id: 2389212,
                     brand: "Vivienne Sabo".to_owned(),
-                    status: ItemStatus::StatusCode,
+                    status: ItemStatus::Accepted,
+                    status_history: vec![],
                 }],
                 locale: Locale::EN,
                 internal_signature: String::new(),
@@ -308,6 +848,7 @@ mod tests {
                 sm_id: 99,
                 date_created: "2021-11-26T06:22:19Z".parse().unwrap(),
                 oof_shard: "1".to_owned(),
+                status: OrderStatus::Created,
             },
             json!({
                 "order_uid": "b563feb7b2b84b6test",
@@ -346,7 +887,8 @@ mod tests {
                         "total_price": 317,
                         "nm_id": 2389212,
                         "brand": "Vivienne Sabo",
-                        "status": 202
+                        "status": 202,
+                        "status_history": []
                     }
                 ],
                 "locale": "en",
@@ -356,7 +898,8 @@ mod tests {
                 "shardkey": "9",
                 "sm_id": 99,
                 "date_created": "2021-11-26T06:22:19Z",
-                "oof_shard": "1"
+                "oof_shard": "1",
+                "status": "created"
             }),
         );
     }
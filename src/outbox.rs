@@ -0,0 +1,339 @@
+use std::{path::PathBuf, time::Duration};
+
+use anyhow::{anyhow, bail, Context};
+use async_nats::jetstream;
+use futures_util::future::join_all;
+use reqwest::Url;
+use tokio::{
+    fs::{File, OpenOptions},
+    io::AsyncWriteExt,
+    sync::Mutex,
+};
+use tracing::{debug, trace, warn};
+
+use crate::{error::Error, model::OrderEvent, state::StoreEvent};
+
+/// How long claimed events aren't given to other relays, it covers publishing of a batch.
+pub(crate) const LEASE: Duration = Duration::from_secs(60);
+
+/// Publishing of a single event is abandoned after that.
+pub(crate) const PUBLISH_TIMEOUT: Duration = Duration::from_secs(10);
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Delay before the next attempt to publish an event which failed `attempts` times before.
+pub(crate) fn backoff(attempts: i32) -> Duration {
+    let factor = 2u32.saturating_pow(attempts.clamp(0, 31) as u32);
+    MIN_BACKOFF.saturating_mul(factor).min(MAX_BACKOFF)
+}
+
+/// Where events are published.
+#[derive(Debug)]
+pub enum Sink {
+    /// `POST` an event as JSON, it's delivered once any 2xx status is returned.
+    Webhook { client: reqwest::Client, url: Url },
+    /// Publish an event to JetStream, which acknowledges it and drops duplicates by event id.
+    Nats {
+        jetstream: jetstream::Context,
+        subject: String,
+    },
+    /// Append an event to NDJSON file.
+    File(Mutex<File>),
+}
+
+impl Sink {
+    /// Configure the sink by its URL:
+    /// `http(s)://...` for webhook, `nats://host:port/subject` or `file:///path/to/file`.
+    pub async fn try_new(url: &str) -> anyhow::Result<Self> {
+        debug!(sink = url, "configure");
+
+        let url = Url::parse(url).with_context(|| format!("invalid sink url '{}'", url))?;
+        Ok(match url.scheme() {
+            "http" | "https" => Sink::Webhook {
+                client: reqwest::Client::builder()
+                    .timeout(PUBLISH_TIMEOUT)
+                    .build()?,
+                url,
+            },
+            "nats" => {
+                let subject = url.path().trim_start_matches('/').to_owned();
+                if subject.is_empty() {
+                    bail!("nats sink url must have a subject as its path");
+                }
+                let server = format!(
+                    "nats://{}:{}",
+                    url.host_str().unwrap_or("localhost"),
+                    url.port().unwrap_or(4222)
+                );
+                let client = async_nats::connect(server).await?;
+                Sink::Nats {
+                    jetstream: jetstream::new(client),
+                    subject,
+                }
+            }
+            "file" => {
+                let path = url
+                    .to_file_path()
+                    .map_err(|_| anyhow!("invalid file sink path '{}'", url))?;
+                Sink::file(path).await?
+            }
+            scheme => bail!("unsupported sink scheme '{}'", scheme),
+        })
+    }
+
+    async fn file(path: PathBuf) -> std::io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+
+        Ok(Sink::File(Mutex::new(file)))
+    }
+
+    async fn publish(&self, event: &OrderEvent) -> anyhow::Result<()> {
+        let body = serde_json::to_vec(event)?;
+
+        match self {
+            Sink::Webhook { client, url } => {
+                client
+                    .post(url.clone())
+                    .header(reqwest::header::CONTENT_TYPE, "application/json")
+                    .body(body)
+                    .send()
+                    .await?
+                    .error_for_status()?;
+            }
+            Sink::Nats { jetstream, subject } => {
+                let mut headers = async_nats::HeaderMap::new();
+                headers.insert("Nats-Msg-Id", event.id.to_string().as_str());
+                let ack = jetstream
+                    .publish_with_headers(subject.clone(), headers, body.into())
+                    .await?;
+                tokio::time::timeout(PUBLISH_TIMEOUT, ack).await??;
+            }
+            Sink::File(file) => {
+                let mut file = file.lock().await;
+                file.write_all(&body).await?;
+                file.write_all(b"\n").await?;
+                file.sync_data().await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Publishes events of the outbox to the sink.
+/// An event is published at least once: it's published again if the relay fails to mark it,
+/// so consumers should tell duplicates by event id. Events might be published out of order.
+pub struct Relay<E> {
+    events: E,
+    sink: Sink,
+    batch_size: i64,
+    interval: Duration,
+}
+
+impl<E> Relay<E>
+where
+    E: StoreEvent,
+{
+    pub const DEFAULT_BATCH_SIZE: i64 = 100;
+    pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(1);
+
+    pub fn new(events: E, sink: Sink) -> Self {
+        Self {
+            events,
+            sink,
+            batch_size: Self::DEFAULT_BATCH_SIZE,
+            interval: Self::DEFAULT_INTERVAL,
+        }
+    }
+
+    pub fn with_batch_size(mut self, batch_size: i64) -> Self {
+        self.batch_size = batch_size;
+        self
+    }
+
+    /// How long to wait for new events once there are no pending ones.
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Relay events until the task is dropped.
+    pub async fn run(self) {
+        debug!(sink = ?self.sink, batch_size = self.batch_size, "run relay");
+
+        loop {
+            match self.relay_batch().await {
+                Ok(0) => tokio::time::sleep(self.interval).await,
+                Ok(_) => {}
+                Err(e) => {
+                    warn!("failed to relay events: {}", e);
+                    tokio::time::sleep(self.interval).await;
+                }
+            }
+        }
+    }
+
+    /// Publish a batch of pending events, returns how many of them were claimed.
+    pub async fn relay_batch(&self) -> Result<usize, Error> {
+        let events = self.events.claim_events(self.batch_size, LEASE).await?;
+
+        let results = join_all(events.iter().map(|event| self.sink.publish(event))).await;
+        for (event, result) in events.iter().zip(results) {
+            match result {
+                Ok(()) => {
+                    trace!(event_id = event.id, "event is published");
+                    self.events.complete_event(event.id).await?;
+                }
+                Err(e) => {
+                    let delay = backoff(event.attempts);
+                    warn!(
+                        event_id = event.id,
+                        ?delay,
+                        "failed to publish event: {:#}",
+                        e
+                    );
+                    self.events
+                        .retry_event(event.id, &format!("{:#}", e), delay)
+                        .await?;
+                }
+            }
+        }
+
+        Ok(events.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use axum::{http::StatusCode, routing::post, Router};
+    use serde_json::value::RawValue;
+
+    use crate::{mock::MockStore, model::EventType};
+
+    /// Outbox with a single event, which records what happens to it.
+    #[derive(Default)]
+    struct MockEvents {
+        claimed: std::sync::Mutex<bool>,
+        completed: std::sync::Mutex<Vec<i64>>,
+        retried: std::sync::Mutex<Vec<(i64, Duration)>>,
+    }
+
+    impl MockStore for MockEvents {
+        async fn claim_events(&self, _: i64, _: Duration) -> Result<Vec<OrderEvent>, Error> {
+            let mut claimed = self.claimed.lock().unwrap();
+            if *claimed {
+                return Ok(vec![]);
+            }
+            *claimed = true;
+
+            Ok(vec![OrderEvent {
+                id: 7,
+                event_type: EventType::OrderCreated,
+                order_id: "a".to_owned(),
+                created_at: "2021-11-26T06:22:19Z".parse().unwrap(),
+                data: RawValue::from_string(r#"{"order_uid":"a"}"#.to_owned()).unwrap(),
+                attempts: 2,
+            }])
+        }
+
+        async fn complete_event(&self, event_id: i64) -> Result<(), Error> {
+            self.completed.lock().unwrap().push(event_id);
+            Ok(())
+        }
+
+        async fn retry_event(&self, event_id: i64, _: &str, delay: Duration) -> Result<(), Error> {
+            self.retried.lock().unwrap().push((event_id, delay));
+            Ok(())
+        }
+    }
+
+    /// Local stand-in of a webhook receiver, which fails the first `failures` requests.
+    async fn webhook(failures: usize) -> (String, Arc<AtomicUsize>) {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let app = Router::new().route(
+            "/events",
+            post(move |body: String| async move {
+                assert!(body.contains(r#""type":"order_created""#));
+                if counter.fetch_add(1, Ordering::SeqCst) < failures {
+                    StatusCode::SERVICE_UNAVAILABLE
+                } else {
+                    StatusCode::NO_CONTENT
+                }
+            }),
+        );
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/events", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        (url, requests)
+    }
+
+    #[test]
+    fn backoff_grows_up_to_limit() {
+        assert_eq!(backoff(0), Duration::from_secs(1));
+        assert_eq!(backoff(3), Duration::from_secs(8));
+        assert_eq!(backoff(100), MAX_BACKOFF);
+    }
+
+    #[tokio::test]
+    async fn complete_published_event() {
+        let (url, requests) = webhook(0).await;
+        let relay = Relay::new(MockEvents::default(), Sink::try_new(&url).await.unwrap());
+
+        assert_eq!(relay.relay_batch().await.unwrap(), 1);
+        assert_eq!(relay.relay_batch().await.unwrap(), 0);
+
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        assert_eq!(*relay.events.completed.lock().unwrap(), [7]);
+        assert!(relay.events.retried.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn retry_rejected_event() {
+        let (url, requests) = webhook(1).await;
+        let relay = Relay::new(MockEvents::default(), Sink::try_new(&url).await.unwrap());
+
+        assert_eq!(relay.relay_batch().await.unwrap(), 1);
+
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        assert!(relay.events.completed.lock().unwrap().is_empty());
+        assert_eq!(
+            *relay.events.retried.lock().unwrap(),
+            [(7, Duration::from_secs(4))]
+        );
+    }
+
+    #[tokio::test]
+    async fn append_event_to_file() {
+        let path = std::env::temp_dir().join(format!("outbox-{}.ndjson", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let url = Url::from_file_path(&path).unwrap();
+        let relay = Relay::new(
+            MockEvents::default(),
+            Sink::try_new(url.as_str()).await.unwrap(),
+        );
+        assert_eq!(relay.relay_batch().await.unwrap(), 1);
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let event: OrderEvent = serde_json::from_str(content.trim_end()).unwrap();
+        assert_eq!(event.id, 7);
+        assert_eq!(event.data.get(), r#"{"order_uid":"a"}"#);
+    }
+}
@@ -1,24 +1,59 @@
-use std::str::FromStr;
+use std::{
+    collections::{HashMap, HashSet},
+    pin::pin,
+    str::FromStr,
+    time::Duration,
+};
 
 use bb8::Pool;
 use bb8_postgres::PostgresConnectionManager;
-use postgres_types::ToSql;
-use tokio::try_join;
-use tokio_postgres::{Config, NoTls, Transaction};
-use tracing::debug;
+use postgres_types::{Json, ToSql, Type};
+use serde_json::{value::RawValue, Map, Value};
+use futures_util::{stream, StreamExt};
+use tokio::{
+    join,
+    sync::{broadcast, mpsc},
+    try_join,
+};
+use tokio_postgres::{
+    binary_copy::BinaryCopyInWriter, error::SqlState, AsyncMessage, Config, GenericClient, NoTls,
+    Transaction,
+};
+use tracing::{debug, warn};
 
 use crate::{
-    dto::OrderRepoDto,
     error::Error,
-    model::{Delivery, Item, Order, Payment},
-    state::StoreOrder,
+    model::{
+        diff, Actor, ApiKey, ApiScope, AttemptOutcome, AuditEntry, AuditOperation, BatchMode, CreateOutcome, CreateStatus, Delivery, EventType, Item, ItemStatus, Order,
+        OrderEvent, OrderPayload, OrderStatus, Payment, RejectedOrder, RejectionSource, Webhook,
+        WebhookAttempt, WebhookDelivery,
+    },
+    state::{StoreApiKey, StoreEvent, StoreOrder, StoreRejected, StoreWebhook},
 };
 
 type PostgresConnectionPool = Pool<PostgresConnectionManager<NoTls>>;
 
+const EVENTS_CHANNEL: &str = "order_events";
+
+/// Rows removed by a single statement of pruning.
+const PRUNE_BATCH_SIZE: i64 = 1000;
+
+/// Longer payloads of rejected orders are truncated, so clients can't fill the table quickly.
+const MAX_REJECTED_PAYLOAD: usize = 64 * 1024;
+
 #[derive(Clone)]
 pub struct PostgresRepo {
     pool: PostgresConnectionPool,
+    config: Config,
+    storage: Storage,
+}
+
+/// How orders are written, they are read in the same way anyway.
+#[derive(Clone, Copy, Debug)]
+struct Storage {
+    copy_threshold: usize,
+    /// Reuse identical deliveries and keep product attributes of items in `products`.
+    normalized: bool,
 }
 
 impl PostgresRepo {
@@ -26,20 +61,518 @@ impl PostgresRepo {
         debug!(repo = "postgres", "configure with params: {}", params);
 
         let config = Config::from_str(params)?;
-        let manager = PostgresConnectionManager::new(config, NoTls);
+        let manager = PostgresConnectionManager::new(config.clone(), NoTls);
         let pool = Pool::builder().build(manager).await?;
 
-        Ok(Self { pool })
+        Ok(Self {
+            pool,
+            config,
+            storage: Storage {
+                copy_threshold: Self::DEFAULT_COPY_THRESHOLD,
+                normalized: false,
+            },
+        })
     }
-}
 
-impl StoreOrder for PostgresRepo {
-    async fn get_order(&self, order_id: &str) -> Result<Option<Order>, Error> {
-        debug!(repo = "postgres", "get order by order_id: {}", order_id);
+    /// Items of an order are written with `COPY` if there are at least that many of them.
+    pub const DEFAULT_COPY_THRESHOLD: usize = 100;
+
+    /// Items below the threshold are inserted with 11 parameters each,
+    /// and the client sends no more than 32767 parameters with a statement.
+    pub const MAX_COPY_THRESHOLD: usize = i16::MAX as usize / 11 + 1;
+
+    pub fn with_copy_threshold(mut self, copy_threshold: usize) -> Self {
+        debug!(repo = "postgres", copy_threshold, "configure");
+
+        self.storage.copy_threshold = copy_threshold;
+        self
+    }
+
+    /// Deliveries are reused by their content and items refer to shared products.
+    /// Orders written before are left as they are.
+    pub fn with_normalized(mut self, normalized: bool) -> Self {
+        debug!(repo = "postgres", normalized, "configure");
+
+        self.storage.normalized = normalized;
+        self
+    }
+
+    /// Broadcast events committed by any instance, they are told about with `NOTIFY`.
+    /// Subscription is restored on failure, events committed meanwhile are broadcast then
+    /// unless their ids are lower than the latest one broadcast before.
+    pub fn listen_events(&self, sender: broadcast::Sender<OrderEvent>) {
+        let config = self.config.clone();
+
+        tokio::spawn(async move {
+            // the latest event broadcast so far, it's kept between subscriptions
+            let mut last_id = None;
+            loop {
+                if let Err(e) = listen(&config, &sender, &mut last_id).await {
+                    warn!(repo = "postgres", "subscription to events failed: {}", e);
+                }
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        });
+
+        async fn listen(
+            config: &Config,
+            sender: &broadcast::Sender<OrderEvent>,
+            last_id: &mut Option<i64>,
+        ) -> Result<(), Error> {
+            let (client, mut connection) = config.connect(NoTls).await?;
+            let (ids_sender, mut ids) = mpsc::unbounded_channel();
+
+            // connection is driven by polling its messages
+            let receive = async move {
+                let mut messages = stream::poll_fn(|cx| connection.poll_message(cx));
+                while let Some(message) = messages.next().await {
+                    if let AsyncMessage::Notification(notification) = message? {
+                        match notification.payload().parse::<i64>() {
+                            Ok(id) => _ = ids_sender.send(id),
+                            Err(e) => warn!(repo = "postgres", "invalid event id: {}", e),
+                        }
+                    }
+                }
+                Err::<(), _>(anyhow::anyhow!("connection listening to events is closed").into())
+            };
+
+            let broadcast = async {
+                client
+                    .batch_execute(&format!("LISTEN {}", EVENTS_CHANNEL))
+                    .await?;
+                debug!(repo = "postgres", "listen to {}", EVENTS_CHANNEL);
+
+                // events committed since the previous subscription might be notified as well,
+                // so they are told apart to be broadcast once
+                let mut replayed = HashSet::new();
+                match *last_id {
+                    Some(after_id) => {
+                        let rows = client
+                            .query(
+                                "
+                                    SELECT id, event_type, order_id, created_at, data, attempts
+                                    FROM outbox
+                                    WHERE id > $1
+                                    ORDER BY id
+                                ",
+                                &[&after_id],
+                            )
+                            .await?;
+                        debug!(repo = "postgres", after_id, "replay {} events", rows.len());
+                        for row in &rows {
+                            let event = event_from_row(row)?;
+                            replayed.insert(event.id);
+                            *last_id = Some(event.id);
+                            _ = sender.send(event);
+                        }
+                    }
+                    None => {
+                        let row = client
+                            .query_one("SELECT coalesce(max(id), 0) FROM outbox", &[])
+                            .await?;
+                        *last_id = Some(row.try_get(0)?);
+                    }
+                }
+
+                while let Some(id) = ids.recv().await {
+                    if replayed.remove(&id) {
+                        continue;
+                    }
+                    // events of a transaction are notified at once, so they are fetched together
+                    let mut event_ids = vec![id];
+                    while let Ok(id) = ids.try_recv() {
+                        if !replayed.remove(&id) {
+                            event_ids.push(id);
+                        }
+                    }
+
+                    let rows = client
+                        .query(
+                            "
+                                SELECT id, event_type, order_id, created_at, data, attempts
+                                FROM outbox
+                                WHERE id = ANY($1)
+                                ORDER BY id
+                            ",
+                            &[&event_ids],
+                        )
+                        .await?;
+                    for row in &rows {
+                        let event = event_from_row(row)?;
+                        *last_id = (*last_id).max(Some(event.id));
+                        // nobody might be subscribed at the moment
+                        _ = sender.send(event);
+                    }
+                }
+                Ok::<_, Error>(())
+            };
+
+            try_join!(receive, broadcast)?;
+
+            Ok(())
+        }
+    }
+
+    /// Remove published events created more than `retention` ago along with their webhook
+    /// deliveries. Events which aren't published yet or are still delivered to webhooks are kept.
+    /// Returns how many events are removed.
+    pub async fn prune_events(&self, retention: Duration) -> Result<u64, Error> {
+        debug!(repo = "postgres", ?retention, "prune events");
+
+        let conn = self.pool.get().await?;
+        let mut pruned = 0;
+        // removed in batches, so other writers of the outbox aren't blocked for long
+        loop {
+            let removed = conn
+                .execute(
+                    "
+                        WITH expired AS (
+                            SELECT id
+                            FROM outbox o
+                            WHERE created_at < now() - make_interval(secs => $1)
+                                AND o.published_at IS NOT NULL
+                                AND NOT EXISTS (
+                                    SELECT 1
+                                    FROM webhook_deliveries d
+                                    WHERE d.event_id = o.id
+                                        AND d.delivered_at IS NULL
+                                        AND d.failed_at IS NULL
+                                )
+                            ORDER BY id
+                            LIMIT $2
+                            FOR UPDATE SKIP LOCKED
+                        ), deliveries AS (
+                            DELETE FROM webhook_deliveries
+                            WHERE event_id IN (SELECT id FROM expired)
+                        )
+                        DELETE FROM outbox
+                        WHERE id IN (SELECT id FROM expired)
+                    ",
+                    &[&retention.as_secs_f64(), &PRUNE_BATCH_SIZE],
+                )
+                .await?;
+
+            pruned += removed;
+            if removed < PRUNE_BATCH_SIZE as u64 {
+                return Ok(pruned);
+            }
+        }
+    }
+
+    /// Remove orders rejected more than `retention` ago.
+    /// Returns how many of them are removed.
+    pub async fn prune_rejected_orders(&self, retention: Duration) -> Result<u64, Error> {
+        debug!(repo = "postgres", ?retention, "prune rejected orders");
+
+        let conn = self.pool.get().await?;
+        let mut pruned = 0;
+        loop {
+            let removed = conn
+                .execute(
+                    "
+                        DELETE FROM rejected_orders
+                        WHERE id IN (
+                            SELECT id
+                            FROM rejected_orders
+                            WHERE rejected_at < now() - make_interval(secs => $1)
+                            ORDER BY id
+                            LIMIT $2
+                            FOR UPDATE SKIP LOCKED
+                        )
+                    ",
+                    &[&retention.as_secs_f64(), &PRUNE_BATCH_SIZE],
+                )
+                .await?;
+
+            pruned += removed;
+            if removed < PRUNE_BATCH_SIZE as u64 {
+                return Ok(pruned);
+            }
+        }
+    }
+
+    /// Store the hash of a new key of the client named `name`,
+    /// the name is taken until the key is revoked.
+    pub async fn create_api_key(
+        &self,
+        name: &str,
+        key_hash: &str,
+        scopes: &[ApiScope],
+        rate_limit: Option<i32>,
+    ) -> Result<ApiKey, Error> {
+        debug!(repo = "postgres", ?scopes, rate_limit, "create api key: {}", name);
+
+        let row = self
+            .pool
+            .get()
+            .await?
+            .query_one(
+                "
+                    INSERT INTO api_keys (name, key_hash, scopes, rate_limit)
+                    VALUES ($1, $2, $3, $4)
+                    RETURNING id, name, scopes, rate_limit, created_at, revoked_at
+                ",
+                &[&name, &key_hash, &scopes, &rate_limit],
+            )
+            .await?;
+
+        api_key_from_row(&row)
+    }
+
+    /// Revoke the key of the client, it's kept for the record.
+    /// Returns `false` if there is no such key.
+    pub async fn revoke_api_key(&self, name: &str) -> Result<bool, Error> {
+        debug!(repo = "postgres", "revoke api key: {}", name);
+
+        let revoked = self
+            .pool
+            .get()
+            .await?
+            .execute(
+                "UPDATE api_keys SET revoked_at = now() WHERE name = $1 AND revoked_at IS NULL",
+                &[&name],
+            )
+            .await?;
+
+        Ok(revoked > 0)
+    }
+
+    /// Get all keys including revoked ones, the latest go first.
+    pub async fn get_api_keys(&self) -> Result<Vec<ApiKey>, Error> {
+        debug!(repo = "postgres", "get api keys");
+
+        self.pool
+            .get()
+            .await?
+            .query(
+                "
+                    SELECT id, name, scopes, rate_limit, created_at, revoked_at
+                    FROM api_keys
+                    ORDER BY id DESC
+                ",
+                &[],
+            )
+            .await?
+            .iter()
+            .map(api_key_from_row)
+            .collect()
+    }
+
+    /// Create many orders along with their payloads at once with `COPY`, e.g. to load a dump.
+    /// Either all orders are created or none of them.
+    pub async fn import_orders(
+        &self,
+        mut received: Vec<(Order, Box<RawValue>)>,
+        actor: &Actor,
+    ) -> Result<(), Error> {
+        debug!(repo = "postgres", "import {} orders", received.len());
+
+        for (order, _) in &mut received {
+            order.reset_statuses();
+        }
+
+        let mut conn = self.pool.get().await?;
+        let trx = conn.transaction().await?;
+
+        // shared rows have to be looked up, so orders are written one by one
+        if self.storage.normalized {
+            for (order, payload) in &received {
+                insert_order(&trx, order, payload, self.storage, actor).await?;
+            }
+            return Ok(trx.commit().await?);
+        }
+
+        let orders: Vec<_> = received.iter().map(|(order, _)| order).collect();
+        let orders = orders.as_slice();
+
+        let delivery_ids: Vec<i32> = reserve_ids(&trx, "deliveries_id_seq", orders.len()).await?;
+        let deliveries: Vec<_> = delivery_ids
+            .iter()
+            .zip(orders)
+            .map(|(id, order)| {
+                let delivery = &order.delivery;
+                vec![
+                    id as &(dyn ToSql + Sync),
+                    &delivery.name,
+                    &delivery.phone,
+                    &delivery.zip,
+                    &delivery.city,
+                    &delivery.address,
+                    &delivery.region,
+                    &delivery.email,
+                ]
+            })
+            .collect();
+        copy_in(
+            &trx,
+            "deliveries",
+            "id, name, phone, zip, city, address, region, email",
+            &deliveries,
+        )
+        .await?;
+
+        let payments: Vec<_> = orders
+            .iter()
+            .map(|order| {
+                let payment = &order.payment;
+                vec![
+                    &payment.transaction as &(dyn ToSql + Sync),
+                    &payment.request_id,
+                    &payment.currency,
+                    &payment.provider,
+                    &payment.amount,
+                    &payment.payment_dt,
+                    &payment.bank,
+                    &payment.delivery_cost,
+                    &payment.goods_total,
+                    &payment.custom_fee,
+                ]
+            })
+            .collect();
+        copy_in(
+            &trx,
+            "payments",
+            "
+                transaction
+                , request_id
+                , currency
+                , provider
+                , amount
+                , payment_dt
+                , bank
+                , delivery_cost
+                , goods_total
+                , custom_fee
+            ",
+            &payments,
+        )
+        .await?;
+
+        let order_rows: Vec<_> = delivery_ids
+            .iter()
+            .zip(orders)
+            .map(|(delivery_id, order)| {
+                vec![
+                    &order.order_uid as &(dyn ToSql + Sync),
+                    &order.track_number,
+                    &order.entry,
+                    delivery_id,
+                    &order.payment.transaction,
+                    &order.locale,
+                    &order.internal_signature,
+                    &order.customer_id,
+                    &order.delivery_service,
+                    &order.shardkey,
+                    &order.sm_id,
+                    &order.date_created,
+                    &order.oof_shard,
+                    &order.status,
+                ]
+            })
+            .collect();
+        copy_in(
+            &trx,
+            "orders",
+            "
+                order_uid
+                , track_number
+                , entry
+                , delivery_id
+                , payment_id
+                , locale
+                , internal_signature
+                , customer_id
+                , delivery_service
+                , shardkey
+                , sm_id
+                , date_created
+                , oof_shard
+                , status
+            ",
+            &order_rows,
+        )
+        .await?;
+
+        let payloads: Vec<_> = received
+            .iter()
+            .map(|(order, payload)| (&order.order_uid, Json(payload.as_ref())))
+            .collect();
+        let payload_rows: Vec<_> = payloads
+            .iter()
+            .map(|(order_id, payload)| vec![order_id as &(dyn ToSql + Sync), payload])
+            .collect();
+        copy_in(&trx, "order_payloads", "order_id, payload", &payload_rows).await?;
+
+        let event_ids: Vec<i64> = reserve_ids(&trx, "outbox_id_seq", orders.len()).await?;
+        let events: Vec<_> = event_ids
+            .iter()
+            .zip(orders)
+            .map(|(id, order)| (id, &order.order_uid, Json(*order)))
+            .collect();
+        let event_rows: Vec<_> = events
+            .iter()
+            .map(|(id, order_id, data)| {
+                vec![id as &(dyn ToSql + Sync), &EventType::OrderCreated, order_id, data]
+            })
+            .collect();
+        copy_in(&trx, "outbox", "id, event_type, order_id, data", &event_rows).await?;
+        insert_webhook_deliveries(&trx, EventType::OrderCreated, &event_ids).await?;
+        notify_events(&trx, &event_ids).await?;
+
+        let items = orders.iter().flat_map(|order| &order.items);
+        let item_ids: Vec<i32> = reserve_ids(&trx, "items_id_seq", items.clone().count()).await?;
+        copy_items(&trx, &item_ids, items).await?;
+
+        let mut links = Vec::with_capacity(item_ids.len());
+        let mut ids = item_ids.iter();
+        for order in orders {
+            for item_id in ids.by_ref().take(order.items.len()) {
+                links.push(vec![&order.order_uid as &(dyn ToSql + Sync), item_id]);
+            }
+        }
+        copy_in(&trx, "items_to_order", "order_id, item_id", &links).await?;
+
+        insert_into_item_status_history(&trx, &item_ids).await?;
+
+        let diffs = orders
+            .iter()
+            .map(|order| Ok((&order.order_uid, Json(audit_diff(None, Some(order))?))))
+            .collect::<Result<Vec<_>, Error>>()?;
+        let audit_rows: Vec<_> = diffs
+            .iter()
+            .map(|(order_id, diff)| {
+                vec![
+                    order_id as &(dyn ToSql + Sync),
+                    &AuditOperation::Create,
+                    &actor.name,
+                    &actor.request_id,
+                    diff,
+                ]
+            })
+            .collect();
+        copy_in(
+            &trx,
+            "order_audit",
+            "order_id, operation, actor, request_id, diff",
+            &audit_rows,
+        )
+        .await?;
+
+        trx.commit().await?;
+
+        Ok(())
+    }
+
+    /// Get an order with a query per its part, as it was done before the single query.
+    /// It's kept to compare both in benchmarks only.
+    #[cfg(feature = "bench")]
+    #[doc(hidden)]
+    pub async fn get_order_split(&self, order_id: &str) -> Result<Option<Order>, Error> {
+        debug!(repo = "postgres", "get order by order_id with split queries: {}", order_id);
 
         let conn = self.pool.get().await.map_err(Error::PgConnFailed)?;
 
-        let order_dto: OrderRepoDto = {
+        let order_dto: crate::dto::OrderRepoDto = {
             let maybe_row = conn
                 .query_opt("SELECT * FROM orders WHERE order_uid = $1", &[&order_id])
                 .await?;
@@ -68,27 +601,7 @@ impl StoreOrder for PostgresRepo {
             .try_into().map_err(Error::Other)
         };
 
-        let get_items = async {
-            conn.query(
-                "
-                    SELECT * 
-                    FROM items 
-                    WHERE id IN (
-                        SELECT item_id 
-                        FROM items_to_order 
-                        WHERE order_id = $1
-                    )
-                ",
-                &[&order_id],
-            )
-            .await?
-            .into_iter()
-            .map(Item::try_from)
-            .try_fold(Vec::default(), |mut items, result_item| {
-                items.push(result_item?);
-                Ok::<_, Error>(items)
-            })
-        };
+        let get_items = select_items(&*conn, order_id);
 
         let (delivery, payment, items) = try_join!(
             get_delivery, 
@@ -111,8 +624,115 @@ impl StoreOrder for PostgresRepo {
             sm_id: order_dto.sm_id,
             date_created: order_dto.date_created,
             oof_shard: order_dto.oof_shard,
+            status: order_dto.status,
         }))
     }
+}
+
+impl StoreOrder for PostgresRepo {
+    async fn get_order(&self, order_id: &str) -> Result<Option<Order>, Error> {
+        debug!(repo = "postgres", "get order by order_id: {}", order_id);
+
+        let conn = self.pool.get().await.map_err(Error::PgConnFailed)?;
+
+        Ok(select_orders(
+            &*conn,
+            "WHERE o.order_uid = $1",
+            &[(&order_id, Type::VARCHAR)],
+        )
+        .await?
+        .pop())
+    }
+
+    async fn get_orders(&self, order_ids: &[String]) -> Result<Vec<Order>, Error> {
+        debug!(repo = "postgres", "get orders by order_ids: {:?}", order_ids);
+
+        select_orders(
+            &*self.pool.get().await?,
+            "WHERE o.order_uid = ANY($1)",
+            &[(&order_ids, Type::VARCHAR_ARRAY)],
+        )
+        .await
+    }
+
+    async fn get_order_by_track_number(&self, track_number: &str) -> Result<Option<Order>, Error> {
+        debug!(repo = "postgres", "get order by track_number: {}", track_number);
+
+        // track number might be reused, the latest order is the relevant one
+        Ok(select_orders(
+            &*self.pool.get().await?,
+            "
+                WHERE o.track_number = $1
+                ORDER BY o.date_created DESC
+                LIMIT 1
+            ",
+            &[(&track_number, Type::VARCHAR)],
+        )
+        .await?
+        .pop())
+    }
+
+    async fn get_order_by_transaction(&self, transaction: &str) -> Result<Option<Order>, Error> {
+        debug!(repo = "postgres", "get order by payment transaction: {}", transaction);
+
+        Ok(select_orders(
+            &*self.pool.get().await?,
+            "WHERE o.payment_id = $1",
+            &[(&transaction, Type::VARCHAR)],
+        )
+        .await?
+        .pop())
+    }
+
+    async fn get_orders_by_customer(
+        &self,
+        customer_id: &str,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<Order>, Error> {
+        debug!(
+            repo = "postgres",
+            limit, offset, "get orders by customer_id: {}", customer_id
+        );
+
+        select_orders(
+            &*self.pool.get().await?,
+            "
+                WHERE o.customer_id = $1
+                ORDER BY o.date_created DESC, o.order_uid
+                LIMIT $2
+                OFFSET $3
+            ",
+            &[
+                (&customer_id, Type::VARCHAR),
+                (&limit, Type::INT8),
+                (&offset, Type::INT8),
+            ],
+        )
+        .await
+    }
+
+    async fn get_order_payload(&self, order_id: &str) -> Result<Option<OrderPayload>, Error> {
+        debug!(repo = "postgres", "get order payload by order_id: {}", order_id);
+
+        let maybe_row = self
+            .pool
+            .get()
+            .await?
+            .query_opt(
+                "SELECT received_at, payload FROM order_payloads WHERE order_id = $1",
+                &[&order_id],
+            )
+            .await?;
+
+        Ok(match maybe_row {
+            Some(row) => Some(OrderPayload {
+                received_at: row.try_get(0)?,
+                payload: row.try_get::<_, Json<Box<RawValue>>>(1)?.0,
+            }),
+            None => None,
+        })
+    }
 
     async fn get_items(&self, order_id: &str) -> Result<Option<Vec<Item>>, Error> {
         debug!(repo = "postgres", "get order items by order_id: {}", order_id);
@@ -126,28 +746,7 @@ impl StoreOrder for PostgresRepo {
                 .get::<usize, i64>(0))
         };
 
-        let get_items = async { 
-            conn
-                .query(
-                    "
-                        SELECT * 
-                        FROM items 
-                        WHERE id IN (
-                            SELECT item_id
-                            FROM items_to_order
-                            WHERE order_id = $1 
-                        )
-                    ",
-                    &[&order_id],
-                )
-                .await?
-                .into_iter()
-                .map(Item::try_from)
-                .try_fold(Vec::default(), |mut items, result_item| {
-                    items.push(result_item?);
-                    Ok(items)
-                })
-        };
+        let get_items = select_items(&*conn, order_id);
 
         let (count, items) = try_join!(order_count, get_items)?;
 
@@ -212,272 +811,1947 @@ impl StoreOrder for PostgresRepo {
         }
     }
 
-    async fn create_order(&self, order: Order) -> Result<(), Error> {
-        debug!(repo = "postgres", "create order: {:?}", order);
+    async fn get_order_audit(
+        &self,
+        order_id: &str,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<AuditEntry>, Error> {
+        debug!(repo = "postgres", limit, offset, "get audit of order by order_id: {}", order_id);
+
+        self.pool
+            .get()
+            .await?
+            .query(
+                "
+                    SELECT id, order_id, operation, actor, request_id, diff, recorded_at
+                    FROM order_audit
+                    WHERE order_id = $1
+                    ORDER BY id DESC
+                    LIMIT $2 OFFSET $3
+                ",
+                &[&order_id, &limit, &offset],
+            )
+            .await?
+            .into_iter()
+            .map(|row| {
+                Ok(AuditEntry {
+                    id: row.try_get(0)?,
+                    order_id: row.try_get(1)?,
+                    operation: row.try_get(2)?,
+                    actor: row.try_get(3)?,
+                    request_id: row.try_get(4)?,
+                    diff: row.try_get::<_, Json<Box<RawValue>>>(5)?.0,
+                    recorded_at: row.try_get(6)?,
+                })
+            })
+            .collect()
+    }
+
+    async fn create_order(
+        &self,
+        mut order: Order,
+        payload: Box<RawValue>,
+        actor: &Actor,
+    ) -> Result<(), Error> {
+        debug!(repo = "postgres", ?actor, "create order: {:?}", order);
+
+        order.reset_statuses();
 
         let mut conn = self.pool.get().await?;
         let trx = conn.transaction().await?;
 
-        let (delivery_id, payment_id, item_ids) = try_join!(
-            select_delivery_id(&trx, &order.delivery), 
-            select_payment_id(&trx, &order.payment), 
-            select_item_ids(&trx, &order.items)
-        )?;
-
-        try_join!(
-            insert_into_orders(&trx, &order, &delivery_id, &payment_id), 
-            insert_into_items_to_order(&trx, &order.order_uid, &item_ids)
-        )?;
+        insert_order(&trx, &order, &payload, self.storage, actor).await?;
 
         trx.commit().await?;
 
-        return Ok(());
+        Ok(())
+    }
 
-        //
-        // implementation
-        //
+    async fn create_orders(
+        &self,
+        mut orders: Vec<(Order, Box<RawValue>)>,
+        mode: BatchMode,
+        actor: &Actor,
+    ) -> Result<Vec<CreateOutcome>, Error> {
+        debug!(repo = "postgres", ?mode, ?actor, "create {} orders", orders.len());
 
-        async fn select_delivery_id(
-            trx: &Transaction<'_>, 
-            delivery: &Delivery,
-        ) -> Result<impl ToSql + Sync, Error> 
-        {
-            Ok(trx.query_one(
-                "
-                    INSERT INTO deliveries (name, phone, zip, city, address, region, email)
-                    VALUES ($1, $2, $3, $4, $5, $6, $7) 
-                    RETURNING id
-                ",
-                &[
-                    &delivery.name,
-                    &delivery.phone,
-                    &delivery.zip,
-                    &delivery.city,
-                    &delivery.address,
-                    &delivery.region,
-                    &delivery.email,
-                ],
-            )
-            .await?
-            .get::<usize, i32>(0))
-        }
+        let mut conn = self.pool.get().await?;
+        let mut trx = conn.transaction().await?;
 
-        async fn select_payment_id(
-            trx: &Transaction<'_>, 
-            payment: &Payment
-        ) -> Result<impl ToSql + Sync, Error> 
-        {
-            Ok(trx.query_one(
-                "
-                    INSERT INTO payments 
-                        (transaction
-                        , request_id
-                        , currency
-                        , provider
-                        , amount
-                        , payment_dt
-                        , bank
-                        , delivery_cost
-                        , goods_total
-                        , custom_fee)
-                    VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10) 
-                    RETURNING transaction
-                ",
-                &[
-                    &payment.transaction,
-                    &payment.request_id,
-                    &payment.currency,
-                    &payment.provider,
-                    &payment.amount,
-                    &payment.payment_dt,
-                    &payment.bank,
-                    &payment.delivery_cost,
-                    &payment.goods_total,
-                    &payment.custom_fee,
-                ],
-            )
-            .await?
-            .get::<usize, String>(0))
+        // every order gets a savepoint anyway to tell about each of them
+        let mut outcomes = Vec::with_capacity(orders.len());
+        for (order, payload) in &mut orders {
+            order.reset_statuses();
+            let savepoint = trx.savepoint("batch_order").await?;
+            match insert_order(&savepoint, order, payload, self.storage, actor).await {
+                Ok(()) => {
+                    savepoint.commit().await?;
+                    outcomes.push(CreateOutcome::new(CreateStatus::Created));
+                }
+                Err(e) => {
+                    let Some(status) = rejected_status(&e) else {
+                        return Err(e);
+                    };
+                    debug!(repo = "postgres", ?status, "reject order {}: {}", order.order_uid, e);
+                    savepoint.rollback().await?;
+                    outcomes.push(CreateOutcome {
+                        status,
+                        error: Some(e.to_string()),
+                    });
+                }
+            }
         }
 
-        async fn select_item_ids(
-            trx: &Transaction<'_>, 
-            items: &[Item]
-        ) -> Result<Vec<impl ToSql + Sync>, Error> {
-            if items.is_empty() {
-                return Ok(Vec::<i32>::new());
+        let failed = outcomes
+            .iter()
+            .any(|outcome| outcome.status != CreateStatus::Created);
+        if mode == BatchMode::Atomic && failed {
+            trx.rollback().await?;
+            for outcome in &mut outcomes {
+                if outcome.status == CreateStatus::Created {
+                    outcome.status = CreateStatus::Aborted;
+                }
             }
+        } else {
+            trx.commit().await?;
+        }
 
-            #[rustfmt::skip]
-            let mut query = 
-                "
-                    WITH item_ids AS (
-                        INSERT INTO items
-                            (chrt_id
-                            , track_number
-                            , price
-                            , rid
-                            , name
-                            , sale
-                            , size
-                            , total_price
-                            , nm_id
-                            , brand
-                            , status)
-                        VALUES
-                "
-                .to_owned();
-
-            let params = (1..)
-                .step_by(11)
-                .zip(items.iter().enumerate().rev())
-                .try_fold(
-                    Vec::<&(dyn ToSql + Sync)>::with_capacity(items.len() * 11),
-                    |mut params, (param_id, (id, item))| {
-                        use std::fmt::Write;
-
-                        write!(
-                            &mut query, 
-                            "(${}, ${}, ${}, ${}, ${}, ${}, ${}, ${}, ${}, ${}, ${})", 
-                            param_id, 
-                            param_id + 1, 
-                            param_id + 2, 
-                            param_id + 3, 
-                            param_id + 4, 
-                            param_id + 5, 
-                            param_id + 6, 
-                            param_id + 7, 
-                            param_id + 8, 
-                            param_id + 9, 
-                            param_id + 10
-                        )?;
-                        if id > 0 {
-                            query.push(',');
-                        }
+        Ok(outcomes)
+    }
 
-                        params.extend_from_slice(&[
-                            &item.chrt_id,
-                            &item.track_number,
-                            &item.price,
-                            &item.rid,
-                            &item.name,
-                            &item.sale,
-                            &item.size,
-                            &item.total_price,
-                            &item.nm_id,
-                            &item.brand,
-                            &item.status,
-                        ]);
-
-                        Ok::<_, anyhow::Error>(params)
-                    },
-                )?;
+    async fn update_order(
+        &self,
+        mut order: Order,
+        payload: Box<RawValue>,
+        actor: &Actor,
+    ) -> Result<bool, Error> {
+        debug!(repo = "postgres", ?actor, "update order: {:?}", order);
+
+        let mut conn = self.pool.get().await?;
+        let trx = conn.transaction().await?;
+
+        let (delivery_id, payment_id): (i32, String) = {
+            let maybe_row = trx
+                .query_opt(
+                    "
+                        SELECT delivery_id, payment_id
+                        FROM orders
+                        WHERE order_uid = $1
+                        FOR UPDATE
+                    ",
+                    &[&order.order_uid],
+                )
+                .await?;
+
+            match maybe_row {
+                Some(row) => (row.try_get(0)?, row.try_get(1)?),
+                None => return Ok(false),
+            }
+        };
+        let before = select_order(&trx, &order.order_uid).await?;
+
+        // statuses are changed only by transitions
+        if let Some(before) = &before {
+            order.keep_statuses(before);
+        }
+
+        // items are replaced completely, since they have no natural key
+        delete_items(&trx, &order.order_uid).await?;
+        let item_ids = insert_items(&trx, &order.items, self.storage).await?;
+
+        // payment is keyed by transaction, which might be changed as well
+        let payment_changed = payment_id != order.payment.transaction;
+        if payment_changed {
+            select_payment_id(&trx, &order.payment).await?;
+        } else {
+            update_payment(&trx, &order.payment).await?;
+        }
+
+        let new_delivery_id = replace_delivery(&trx, delivery_id, &order.delivery, self.storage).await?;
+
+        try_join!(
+            update_orders(&trx, &order, new_delivery_id),
+            insert_links(&trx, &order.order_uid, &item_ids, self.storage),
+            insert_into_item_status_history(&trx, &item_ids),
+            upsert_payload(&trx, &order.order_uid, &payload)
+        )?;
+
+        if payment_changed {
+            trx.execute("DELETE FROM payments WHERE transaction = $1", &[&payment_id])
+                .await?;
+        }
+        if new_delivery_id != delivery_id {
+            delete_unused_delivery(&trx, delivery_id).await?;
+        }
+
+        insert_change(
+            &trx,
+            EventType::OrderUpdated,
+            AuditOperation::Update,
+            &order.order_uid,
+            before.as_ref(),
+            actor,
+        )
+        .await?;
+
+        trx.commit().await?;
+
+        Ok(true)
+    }
+
+    async fn delete_order(&self, order_id: &str, actor: &Actor) -> Result<bool, Error> {
+        debug!(repo = "postgres", ?actor, "delete order by order_id: {}", order_id);
 
-            #[rustfmt::skip]
-            query.push_str(
+        let mut conn = self.pool.get().await?;
+        let trx = conn.transaction().await?;
+
+        // the order is locked, so it's deleted as it's read
+        let Some(before) = select_order_for_update(&trx, order_id).await? else {
+            return Ok(false);
+        };
+        delete_items(&trx, order_id).await?;
+
+        let row = trx
+            .query_one(
                 "
-                        RETURNING id
-                    )
-                    SELECT id
-                    FROM item_ids
+                    DELETE FROM orders
+                    WHERE order_uid = $1
+                    RETURNING delivery_id, payment_id
+                ",
+                &[&order_id],
+            )
+            .await?;
+
+        let (delivery_id, payment_id): (i32, String) = (row.try_get(0)?, row.try_get(1)?);
+        delete_unused_delivery(&trx, delivery_id).await?;
+        trx.execute("DELETE FROM payments WHERE transaction = $1", &[&payment_id])
+            .await?;
+        try_join!(
+            insert_event(&trx, EventType::OrderDeleted, &before),
+            insert_audit(&trx, AuditOperation::Delete, order_id, Some(&before), None, actor)
+        )?;
+
+        trx.commit().await?;
+
+        Ok(true)
+    }
+
+    async fn update_order_status(
+        &self,
+        order_id: &str,
+        status: OrderStatus,
+        actor: &Actor,
+    ) -> Result<Option<OrderStatus>, Error> {
+        debug!(
+            repo = "postgres",
+            ?status,
+            ?actor,
+            "update status of order by order_id: {}", order_id
+        );
+
+        let mut conn = self.pool.get().await?;
+        let trx = conn.transaction().await?;
+
+        let maybe_row = trx
+            .query_opt(
+                "SELECT status FROM orders WHERE order_uid = $1 FOR UPDATE",
+                &[&order_id],
+            )
+            .await?;
+
+        let Some(row) = maybe_row else {
+            return Ok(None);
+        };
+
+        let current: OrderStatus = row.try_get("status")?;
+        if !current.can_become(status) {
+            return Err(Error::Conflict(format!(
+                "order status cannot be changed from {:?} to {:?}",
+                current, status
+            )));
+        }
+
+        let before = select_order(&trx, order_id).await?;
+        trx.execute(
+            "UPDATE orders SET status = $2 WHERE order_uid = $1",
+            &[&order_id, &status],
+        )
+        .await?;
+        insert_change(
+            &trx,
+            EventType::OrderStatusChanged,
+            AuditOperation::StatusChange,
+            order_id,
+            before.as_ref(),
+            actor,
+        )
+        .await?;
+
+        trx.commit().await?;
+
+        Ok(Some(status))
+    }
+
+    async fn update_item_status(
+        &self,
+        order_id: &str,
+        chrt_id: i32,
+        status: ItemStatus,
+        actor: &Actor,
+    ) -> Result<Option<Vec<Item>>, Error> {
+        debug!(
+            repo = "postgres",
+            ?status,
+            ?actor,
+            "update status of items by order_id: {}, chrt_id: {}", order_id, chrt_id
+        );
+
+        let mut conn = self.pool.get().await?;
+        let trx = conn.transaction().await?;
+
+        let rows = trx
+            .query(
                 "
-            );
+                    SELECT id, status
+                    FROM items
+                    WHERE chrt_id = $2
+                        AND id IN (
+                            SELECT item_id
+                            FROM items_to_order
+                            WHERE order_id = $1
+                        )
+                    FOR UPDATE
+                ",
+                &[&order_id, &chrt_id],
+            )
+            .await?;
 
-            Ok(trx
-                .query(&query, &params)
+        if rows.is_empty() {
+            return Ok(None);
+        }
+
+        let mut item_ids = Vec::with_capacity(rows.len());
+        for row in rows {
+            let current: ItemStatus = row.try_get("status")?;
+            if !current.can_become(status) {
+                return Err(Error::Conflict(format!(
+                    "item status cannot be changed from {:?} to {:?}",
+                    current, status
+                )));
+            }
+            item_ids.push(row.try_get::<_, i32>("id")?);
+        }
+
+        let before = select_order(&trx, order_id).await?;
+        trx.execute(
+            "UPDATE items SET status = $1 WHERE id = ANY($2)",
+            &[&status, &item_ids],
+        )
+        .await?;
+        insert_into_item_status_history(&trx, &item_ids).await?;
+        insert_change(
+            &trx,
+            EventType::ItemStatusChanged,
+            AuditOperation::ItemStatusChange,
+            order_id,
+            before.as_ref(),
+            actor,
+        )
+        .await?;
+
+        let items = select_items(&trx, order_id)
+            .await?
+            .into_iter()
+            .filter(|item| item.chrt_id == chrt_id)
+            .collect();
+
+        trx.commit().await?;
+
+        Ok(Some(items))
+    }
+}
+
+impl StoreEvent for PostgresRepo {
+    async fn claim_events(&self, limit: i64, lease: Duration) -> Result<Vec<OrderEvent>, Error> {
+        let rows = self
+            .pool
+            .get()
+            .await?
+            .query(
+                "
+                    UPDATE outbox o
+                    SET next_attempt_at = now() + make_interval(secs => $2)
+                    FROM (
+                        SELECT id
+                        FROM outbox
+                        WHERE published_at IS NULL AND next_attempt_at <= now()
+                        ORDER BY id
+                        LIMIT $1
+                        FOR UPDATE SKIP LOCKED
+                    ) pending
+                    WHERE o.id = pending.id
+                    RETURNING o.id, o.event_type, o.order_id, o.created_at, o.data, o.attempts
+                ",
+                &[&limit, &lease.as_secs_f64()],
+            )
+            .await?;
+
+        let mut events = rows
+            .iter()
+            .map(event_from_row)
+            .collect::<Result<Vec<_>, _>>()?;
+        // returned rows have no particular order
+        events.sort_unstable_by_key(|event| event.id);
+
+        if !events.is_empty() {
+            debug!(repo = "postgres", "claim {} events", events.len());
+        }
+
+        Ok(events)
+    }
+
+    async fn get_events(
+        &self,
+        event_type: EventType,
+        after_id: i64,
+        limit: i64,
+    ) -> Result<Vec<OrderEvent>, Error> {
+        debug!(repo = "postgres", ?event_type, limit, "get events after id: {}", after_id);
+
+        self.pool
+            .get()
+            .await?
+            .query(
+                "
+                    SELECT id, event_type, order_id, created_at, data, attempts
+                    FROM outbox
+                    WHERE event_type = $1 AND id > $2
+                    ORDER BY id
+                    LIMIT $3
+                ",
+                &[&event_type, &after_id, &limit],
+            )
+            .await?
+            .iter()
+            .map(event_from_row)
+            .collect()
+    }
+
+    async fn complete_event(&self, event_id: i64) -> Result<(), Error> {
+        debug!(repo = "postgres", "complete event: {}", event_id);
+
+        self.pool
+            .get()
+            .await?
+            .execute(
+                "UPDATE outbox SET published_at = now(), last_error = NULL WHERE id = $1",
+                &[&event_id],
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn retry_event(&self, event_id: i64, error: &str, delay: Duration) -> Result<(), Error> {
+        debug!(repo = "postgres", ?delay, "retry event: {}", event_id);
+
+        self.pool
+            .get()
+            .await?
+            .execute(
+                "
+                    UPDATE outbox
+                    SET attempts = attempts + 1
+                        , last_error = $2
+                        , next_attempt_at = now() + make_interval(secs => $3)
+                    WHERE id = $1
+                ",
+                &[&event_id, &error, &delay.as_secs_f64()],
+            )
+            .await?;
+
+        Ok(())
+    }
+}
+
+impl StoreWebhook for PostgresRepo {
+    async fn create_webhook(
+        &self,
+        url: &str,
+        event_types: &[EventType],
+        secret: &str,
+    ) -> Result<Webhook, Error> {
+        debug!(repo = "postgres", ?event_types, "create webhook: {}", url);
+
+        let row = self
+            .pool
+            .get()
+            .await?
+            .query_one(
+                "
+                    INSERT INTO webhooks (url, event_types, secret)
+                    VALUES ($1, $2, $3)
+                    RETURNING id, url, event_types, created_at
+                ",
+                &[&url, &event_types, &secret],
+            )
+            .await?;
+
+        Ok(Webhook {
+            secret: Some(secret.to_owned()),
+            ..webhook_from_row(&row)?
+        })
+    }
+
+    async fn get_webhooks(&self) -> Result<Vec<Webhook>, Error> {
+        debug!(repo = "postgres", "get webhooks");
+
+        self.pool
+            .get()
+            .await?
+            .query(
+                "SELECT id, url, event_types, created_at FROM webhooks ORDER BY id",
+                &[],
+            )
+            .await?
+            .iter()
+            .map(webhook_from_row)
+            .collect()
+    }
+
+    async fn get_webhook(&self, id: i32) -> Result<Option<Webhook>, Error> {
+        debug!(repo = "postgres", "get webhook by id: {}", id);
+
+        self.pool
+            .get()
+            .await?
+            .query_opt(
+                "SELECT id, url, event_types, created_at FROM webhooks WHERE id = $1",
+                &[&id],
+            )
+            .await?
+            .as_ref()
+            .map(webhook_from_row)
+            .transpose()
+    }
+
+    async fn delete_webhook(&self, id: i32) -> Result<bool, Error> {
+        debug!(repo = "postgres", "delete webhook by id: {}", id);
+
+        let deleted = self
+            .pool
+            .get()
+            .await?
+            .execute("DELETE FROM webhooks WHERE id = $1", &[&id])
+            .await?;
+
+        Ok(deleted > 0)
+    }
+
+    async fn get_webhook_attempts(
+        &self,
+        id: i32,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Option<Vec<WebhookAttempt>>, Error> {
+        debug!(repo = "postgres", limit, offset, "get attempts of webhook by id: {}", id);
+
+        let conn = self.pool.get().await?;
+
+        let webhook_count = async {
+            Ok::<_, Error>(conn
+                .query_one("SELECT count(*) FROM webhooks WHERE id = $1", &[&id])
                 .await?
-                .into_iter()
-                .map(|row| row.get(0))
-                .collect())
+                .get::<usize, i64>(0))
+        };
+
+        let select_attempts = async {
+            Ok::<_, Error>(conn
+                .query(
+                    "
+                        SELECT a.delivery_id, d.event_id, e.event_type, a.attempted_at, a.status_code, a.error
+                        FROM webhook_attempts a
+                        JOIN webhook_deliveries d ON d.id = a.delivery_id
+                        JOIN outbox e ON e.id = d.event_id
+                        WHERE d.webhook_id = $1
+                        ORDER BY a.id DESC
+                        LIMIT $2 OFFSET $3
+                    ",
+                    &[&id, &limit, &offset],
+                )
+                .await?)
+        };
+
+        let (count, rows) = try_join!(webhook_count, select_attempts)?;
+        if count == 0 {
+            return Ok(None);
         }
 
-        async fn insert_into_orders(
-            trx: &Transaction<'_>, 
-            order: &Order, 
-            delivery_id: impl ToSql + Sync, 
-            payment_id: impl ToSql + Sync
-        ) -> Result<(), Error> 
-        {
-            trx.execute(
+        rows.into_iter()
+            .map(|row| {
+                Ok(WebhookAttempt {
+                    delivery_id: row.try_get(0)?,
+                    event_id: row.try_get(1)?,
+                    event_type: row.try_get(2)?,
+                    attempted_at: row.try_get(3)?,
+                    status_code: row.try_get(4)?,
+                    error: row.try_get(5)?,
+                })
+            })
+            .collect::<Result<_, _>>()
+            .map(Some)
+    }
+
+    async fn claim_deliveries(
+        &self,
+        limit: i64,
+        lease: Duration,
+    ) -> Result<Vec<WebhookDelivery>, Error> {
+        let rows = self
+            .pool
+            .get()
+            .await?
+            .query(
                 "
-                    INSERT INTO orders
-                        (order_uid
-                        , track_number
-                        , entry
-                        , delivery_id
-                        , payment_id
-                        , locale
-                        , internal_signature
-                        , customer_id
-                        , delivery_service
-                        , shardkey
-                        , sm_id
-                        , date_created
-                        , oof_shard)
-                    VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)
+                    WITH claimed AS (
+                        UPDATE webhook_deliveries d
+                        SET next_attempt_at = now() + make_interval(secs => $2)
+                        FROM (
+                            SELECT id
+                            FROM webhook_deliveries
+                            WHERE delivered_at IS NULL
+                                AND failed_at IS NULL
+                                AND next_attempt_at <= now()
+                            ORDER BY id
+                            LIMIT $1
+                            FOR UPDATE SKIP LOCKED
+                        ) pending
+                        WHERE d.id = pending.id
+                        RETURNING d.id, d.webhook_id, d.event_id, d.attempts
+                    )
+                    SELECT c.id, w.url, w.secret, c.attempts
+                        , e.id, e.event_type, e.order_id, e.created_at, e.data, e.attempts
+                    FROM claimed c
+                    JOIN webhooks w ON w.id = c.webhook_id
+                    JOIN outbox e ON e.id = c.event_id
+                    ORDER BY c.id
                 ",
-                &[
-                    &order.order_uid,
-                    &order.track_number,
-                    &order.entry,
-                    &delivery_id,
-                    &payment_id,
-                    &order.locale,
-                    &order.internal_signature,
-                    &order.customer_id,
-                    &order.delivery_service,
-                    &order.shardkey,
-                    &order.sm_id,
-                    &order.date_created,
-                    &order.oof_shard,
-                ],
+                &[&limit, &lease.as_secs_f64()],
             )
             .await?;
 
-            Ok(())
+        if !rows.is_empty() {
+            debug!(repo = "postgres", "claim {} webhook deliveries", rows.len());
         }
 
-        async fn insert_into_items_to_order(
-            trx: &Transaction<'_>, 
-            order_id: impl ToSql + Sync, 
-            item_ids: &[impl ToSql + Sync]
-        ) -> Result<(), Error> 
-        {
-            if item_ids.is_empty() {
-                return Ok(());
+        rows.into_iter()
+            .map(|row| {
+                Ok(WebhookDelivery {
+                    id: row.try_get(0)?,
+                    url: row.try_get(1)?,
+                    secret: row.try_get(2)?,
+                    attempts: row.try_get(3)?,
+                    event: OrderEvent {
+                        id: row.try_get(4)?,
+                        event_type: row.try_get(5)?,
+                        order_id: row.try_get(6)?,
+                        created_at: row.try_get(7)?,
+                        data: row.try_get::<_, Json<Box<RawValue>>>(8)?.0,
+                        attempts: row.try_get(9)?,
+                    },
+                })
+            })
+            .collect()
+    }
+
+    async fn record_attempt(&self, delivery_id: i64, outcome: &AttemptOutcome) -> Result<(), Error> {
+        debug!(repo = "postgres", ?outcome, "record attempt of webhook delivery: {}", delivery_id);
+
+        let mut conn = self.pool.get().await?;
+        let trx = conn.transaction().await?;
+
+        let (status_code, error) = match outcome {
+            AttemptOutcome::Delivered { status_code } => (Some(*status_code), None),
+            AttemptOutcome::Failed { status_code, error, .. } => (*status_code, Some(error)),
+        };
+        let log_attempt = async {
+            trx.execute(
+                "
+                    INSERT INTO webhook_attempts (delivery_id, status_code, error)
+                    VALUES ($1, $2, $3)
+                ",
+                &[&delivery_id, &status_code, &error],
+            )
+            .await
+        };
+
+        let update_delivery = async {
+            match outcome {
+                AttemptOutcome::Delivered { .. } => {
+                    trx.execute(
+                        "UPDATE webhook_deliveries SET delivered_at = now() WHERE id = $1",
+                        &[&delivery_id],
+                    )
+                    .await
+                }
+                AttemptOutcome::Failed { retry_after: Some(delay), .. } => {
+                    trx.execute(
+                        "
+                            UPDATE webhook_deliveries
+                            SET attempts = attempts + 1
+                                , next_attempt_at = now() + make_interval(secs => $2)
+                            WHERE id = $1
+                        ",
+                        &[&delivery_id, &delay.as_secs_f64()],
+                    )
+                    .await
+                }
+                AttemptOutcome::Failed { retry_after: None, .. } => {
+                    trx.execute(
+                        "
+                            UPDATE webhook_deliveries
+                            SET attempts = attempts + 1, failed_at = now()
+                            WHERE id = $1
+                        ",
+                        &[&delivery_id],
+                    )
+                    .await
+                }
             }
+        };
+
+        try_join!(log_attempt, update_delivery)?;
 
-            #[rustfmt::skip]
-            let mut query = 
+        trx.commit().await?;
+
+        Ok(())
+    }
+}
+
+impl StoreRejected for PostgresRepo {
+    async fn reject_order(
+        &self,
+        source: RejectionSource,
+        payload: &str,
+        error: &str,
+    ) -> Result<(), Error> {
+        debug!(repo = "postgres", ?source, "reject order: {}", error);
+
+        let truncated = truncate(payload, MAX_REJECTED_PAYLOAD);
+        let error = if truncated.len() < payload.len() {
+            format!("{} (payload of {} bytes is truncated)", error, payload.len())
+        } else {
+            error.to_owned()
+        };
+        let payload = truncated;
+
+        self.pool
+            .get()
+            .await?
+            .execute(
+                "INSERT INTO rejected_orders (source, payload, error) VALUES ($1, $2, $3)",
+                &[&source, &payload, &error],
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn get_rejected_orders(&self, limit: i64, offset: i64) -> Result<Vec<RejectedOrder>, Error> {
+        debug!(repo = "postgres", limit, offset, "get rejected orders");
+
+        self.pool
+            .get()
+            .await?
+            .query(
                 "
-                    INSERT INTO items_to_order (order_id, item_id)
-                    VALUES
+                    SELECT id, source, error, rejected_at, NULL::text AS payload
+                    FROM rejected_orders
+                    ORDER BY id DESC
+                    LIMIT $1 OFFSET $2
+                ",
+                &[&limit, &offset],
+            )
+            .await?
+            .iter()
+            .map(rejected_order_from_row)
+            .collect()
+    }
+
+    async fn get_rejected_order(&self, id: i64) -> Result<Option<RejectedOrder>, Error> {
+        debug!(repo = "postgres", "get rejected order by id: {}", id);
+
+        self.pool
+            .get()
+            .await?
+            .query_opt(
+                "SELECT id, source, error, rejected_at, payload FROM rejected_orders WHERE id = $1",
+                &[&id],
+            )
+            .await?
+            .as_ref()
+            .map(rejected_order_from_row)
+            .transpose()
+    }
+
+    async fn replay_rejected_order(
+        &self,
+        id: i64,
+        mut order: Order,
+        payload: Box<RawValue>,
+        actor: &Actor,
+    ) -> Result<bool, Error> {
+        debug!(repo = "postgres", ?actor, "replay rejected order by id: {}", id);
+
+        order.reset_statuses();
+
+        let mut conn = self.pool.get().await?;
+        let trx = conn.transaction().await?;
+
+        let deleted = trx
+            .execute("DELETE FROM rejected_orders WHERE id = $1", &[&id])
+            .await?;
+        if deleted == 0 {
+            return Ok(false);
+        }
+
+        insert_order(&trx, &order, &payload, self.storage, actor).await?;
+
+        trx.commit().await?;
+
+        Ok(true)
+    }
+
+    async fn delete_rejected_order(&self, id: i64) -> Result<bool, Error> {
+        debug!(repo = "postgres", "delete rejected order by id: {}", id);
+
+        let deleted = self
+            .pool
+            .get()
+            .await?
+            .execute("DELETE FROM rejected_orders WHERE id = $1", &[&id])
+            .await?;
+
+        Ok(deleted > 0)
+    }
+}
+
+impl StoreApiKey for PostgresRepo {
+    async fn get_api_key(&self, key_hash: &str) -> Result<Option<ApiKey>, Error> {
+        self.pool
+            .get()
+            .await?
+            .query_opt(
                 "
-                .to_owned();
-
-            let params = (1..)
-                .step_by(2)
-                .zip(item_ids.iter().enumerate().rev())
-                .try_fold(
-                    Vec::<&(dyn ToSql + Sync)>::with_capacity(item_ids.len() * 2),
-                    |mut params, (param_id, (id, item_id))| {
-                        use std::fmt::Write;
-
-                        write!(&mut query, "(${}, ${})", param_id, param_id + 1)?;
-                        if id > 0 {
-                            query.push(',');
-                        }
+                    SELECT id, name, scopes, rate_limit, created_at, revoked_at
+                    FROM api_keys
+                    WHERE key_hash = $1 AND revoked_at IS NULL
+                ",
+                &[&key_hash],
+            )
+            .await?
+            .as_ref()
+            .map(api_key_from_row)
+            .transpose()
+    }
+}
 
-                        params.extend_from_slice(&[&order_id, item_id]);
+//
+// implementation
+//
 
-                        Ok::<_, anyhow::Error>(params)
-                    },
-                )?;
+fn api_key_from_row(row: &tokio_postgres::Row) -> Result<ApiKey, Error> {
+    Ok(ApiKey {
+        id: row.try_get("id")?,
+        name: row.try_get("name")?,
+        scopes: row.try_get("scopes")?,
+        rate_limit: row.try_get("rate_limit")?,
+        created_at: row.try_get("created_at")?,
+        revoked_at: row.try_get("revoked_at")?,
+    })
+}
 
-            trx.execute(&query, &params).await?;
+fn rejected_order_from_row(row: &tokio_postgres::Row) -> Result<RejectedOrder, Error> {
+    Ok(RejectedOrder {
+        id: row.try_get("id")?,
+        source: row.try_get("source")?,
+        error: row.try_get("error")?,
+        rejected_at: row.try_get("rejected_at")?,
+        payload: row.try_get("payload")?,
+    })
+}
 
-            Ok(())
+fn event_from_row(row: &tokio_postgres::Row) -> Result<OrderEvent, Error> {
+    Ok(OrderEvent {
+        id: row.try_get("id")?,
+        event_type: row.try_get("event_type")?,
+        order_id: row.try_get("order_id")?,
+        created_at: row.try_get("created_at")?,
+        data: row.try_get::<_, Json<Box<RawValue>>>("data")?.0,
+        attempts: row.try_get("attempts")?,
+    })
+}
+
+fn webhook_from_row(row: &tokio_postgres::Row) -> Result<Webhook, Error> {
+    Ok(Webhook {
+        id: row.try_get("id")?,
+        url: row.try_get("url")?,
+        event_types: row.try_get("event_types")?,
+        secret: None,
+        created_at: row.try_get("created_at")?,
+    })
+}
+
+/// Get orders along with their delivery, payment and items in a single round trip.
+/// The statement isn't prepared, so `filter` is expected to be static
+/// and `params` have to be typed explicitly.
+async fn select_orders(
+    conn: &impl GenericClient,
+    filter: &str,
+    params: &[(&(dyn ToSql + Sync), Type)],
+) -> Result<Vec<Order>, Error> {
+    let query = format!(
+        "
+            SELECT o.*
+                , d AS delivery
+                , p AS payment
+                , ARRAY(
+                    SELECT i
+                    FROM item_details i
+                    JOIN items_to_order l ON l.item_id = i.id
+                    WHERE l.order_id = o.order_uid
+                    ORDER BY i.id
+                ) AS items
+                , ARRAY(
+                    SELECT h
+                    FROM item_status_history h
+                    JOIN items_to_order l ON l.item_id = h.item_id
+                    WHERE l.order_id = o.order_uid
+                    ORDER BY h.id
+                ) AS status_history
+            FROM orders o
+            JOIN deliveries d ON d.id = o.delivery_id
+            JOIN payments p ON p.transaction = o.payment_id
+            {}
+        ",
+        filter
+    );
+
+    Ok(conn
+        .query_typed(&query, params)
+        .await?
+        .into_iter()
+        .map(Order::try_from)
+        .collect::<Result<Vec<_>, _>>()?)
+}
+
+/// Insert the order along with its delivery, payment, items and payload.
+async fn insert_order(
+    trx: &Transaction<'_>,
+    order: &Order,
+    payload: &RawValue,
+    storage: Storage,
+    actor: &Actor,
+) -> Result<(), Error> {
+    let (delivery_id, payment_id, item_ids) = match join!(
+        insert_delivery(trx, &order.delivery, storage), 
+        select_payment_id(trx, &order.payment), 
+        insert_items(trx, &order.items, storage)
+    ) {
+        (Ok(delivery_id), Ok(payment_id), Ok(item_ids)) => (delivery_id, payment_id, item_ids),
+        (delivery, payment, items) => {
+            return Err(cause([delivery.err(), payment.err(), items.err()]));
+        }
+    };
+
+    match join!(
+        insert_into_orders(trx, order, &delivery_id, &payment_id), 
+        insert_links(trx, &order.order_uid, &item_ids, storage),
+        insert_into_item_status_history(trx, &item_ids),
+        upsert_payload(trx, &order.order_uid, payload),
+        insert_event(trx, EventType::OrderCreated, order),
+        insert_audit(trx, AuditOperation::Create, &order.order_uid, None, Some(order), actor)
+    ) {
+        (Ok(()), Ok(()), Ok(()), Ok(()), Ok(()), Ok(())) => Ok(()),
+        (orders, links, history, payload, event, audit) => Err(cause([
+            orders.err(),
+            links.err(),
+            history.err(),
+            payload.err(),
+            event.err(),
+            audit.err(),
+        ])),
+    }
+}
+
+/// Put the event into outbox, it's published once the transaction is committed.
+/// It's delivered to webhooks subscribed to events of its type and listeners as well.
+async fn insert_event(
+    trx: &Transaction<'_>,
+    event_type: EventType,
+    order: &Order,
+) -> Result<(), Error> {
+    trx.query(
+        "
+            WITH event AS (
+                INSERT INTO outbox (event_type, order_id, data)
+                VALUES ($1, $2, $3)
+                RETURNING id
+            ), deliveries AS (
+                INSERT INTO webhook_deliveries (webhook_id, event_id)
+                SELECT w.id, event.id
+                FROM event
+                JOIN webhooks w ON $1 = ANY(w.event_types)
+            )
+            SELECT pg_notify($4, id::text) FROM event
+        ",
+        &[&event_type, &order.order_uid, &Json(order), &EVENTS_CHANNEL],
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Tell listeners about new events, they are notified once the transaction is committed.
+async fn notify_events(trx: &Transaction<'_>, event_ids: &[i64]) -> Result<(), Error> {
+    trx.query(
+        "SELECT pg_notify($1, id::text) FROM unnest($2::int8[]) AS id",
+        &[&EVENTS_CHANNEL, &event_ids],
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Deliver events of the same type to subscribed webhooks.
+async fn insert_webhook_deliveries(
+    trx: &Transaction<'_>,
+    event_type: EventType,
+    event_ids: &[i64],
+) -> Result<(), Error> {
+    trx.execute(
+        "
+            INSERT INTO webhook_deliveries (webhook_id, event_id)
+            SELECT w.id, event_id
+            FROM webhooks w, unnest($2::int8[]) AS event_id
+            WHERE $1 = ANY(w.event_types)
+        ",
+        &[&event_type, &event_ids],
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Put an event about the changed order into outbox and append the change to the audit log.
+async fn insert_change(
+    trx: &Transaction<'_>,
+    event_type: EventType,
+    operation: AuditOperation,
+    order_id: &str,
+    before: Option<&Order>,
+    actor: &Actor,
+) -> Result<(), Error> {
+    let order = select_order(trx, order_id)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("changed order '{}' is missing", order_id))?;
+
+    try_join!(
+        insert_event(trx, event_type, &order),
+        insert_audit(trx, operation, order_id, before, Some(&order), actor)
+    )?;
+
+    Ok(())
+}
+
+async fn select_order(conn: &impl GenericClient, order_id: &str) -> Result<Option<Order>, Error> {
+    Ok(select_orders(conn, "WHERE o.order_uid = $1", &[(&order_id, Type::VARCHAR)])
+        .await?
+        .pop())
+}
+
+/// Lock the order until the end of the transaction and read it.
+async fn select_order_for_update(
+    trx: &Transaction<'_>,
+    order_id: &str,
+) -> Result<Option<Order>, Error> {
+    let locked = trx
+        .query_opt(
+            "SELECT 1 FROM orders WHERE order_uid = $1 FOR UPDATE",
+            &[&order_id],
+        )
+        .await?;
+
+    match locked {
+        Some(_) => select_order(trx, order_id).await,
+        None => Ok(None),
+    }
+}
+
+/// Append the change of the order to the audit log, `None` is the order before creation
+/// or after deletion.
+async fn insert_audit(
+    trx: &Transaction<'_>,
+    operation: AuditOperation,
+    order_id: &str,
+    before: Option<&Order>,
+    after: Option<&Order>,
+    actor: &Actor,
+) -> Result<(), Error> {
+    trx.execute(
+        "
+            INSERT INTO order_audit (order_id, operation, actor, request_id, diff)
+            VALUES ($1, $2, $3, $4, $5)
+        ",
+        &[
+            &order_id,
+            &operation,
+            &actor.name,
+            &actor.request_id,
+            &Json(audit_diff(before, after)?),
+        ],
+    )
+    .await?;
+
+    Ok(())
+}
+
+fn audit_diff(before: Option<&Order>, after: Option<&Order>) -> Result<Map<String, Value>, Error> {
+    let value = |order: Option<&Order>| {
+        order
+            .map(serde_json::to_value)
+            .transpose()
+            .map(Option::unwrap_or_default)
+            .map_err(anyhow::Error::from)
+    };
+
+    Ok(diff(&value(before)?, &value(after)?))
+}
+
+/// Keep the payload the order was received as, a replaced order gets a new one.
+async fn upsert_payload(
+    trx: &Transaction<'_>,
+    order_id: &str,
+    payload: &RawValue,
+) -> Result<(), Error> {
+    trx.execute(
+        "
+            INSERT INTO order_payloads (order_id, payload)
+            VALUES ($1, $2)
+            ON CONFLICT (order_id) DO UPDATE
+            SET payload = EXCLUDED.payload, received_at = now()
+        ",
+        &[&order_id, &Json(payload)],
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Insert a new delivery, or reuse an identical one in normalized storage.
+async fn insert_delivery(
+    trx: &Transaction<'_>,
+    delivery: &Delivery,
+    storage: Storage,
+) -> Result<i32, Error> {
+    if storage.normalized {
+        upsert_delivery(trx, delivery).await
+    } else {
+        select_delivery_id(trx, delivery).await
+    }
+}
+
+/// Write the changed delivery of an order, returns its new id if the row is replaced.
+/// Deliveries with content hash might be shared, so they are never changed in place.
+async fn replace_delivery(
+    trx: &Transaction<'_>,
+    delivery_id: i32,
+    delivery: &Delivery,
+    storage: Storage,
+) -> Result<i32, Error> {
+    if storage.normalized {
+        return upsert_delivery(trx, delivery).await;
+    }
+
+    if update_delivery(trx, delivery_id, delivery).await? {
+        Ok(delivery_id)
+    } else {
+        select_delivery_id(trx, delivery).await
+    }
+}
+
+/// Get id of the delivery with the same content, it's inserted if there is none.
+async fn upsert_delivery(trx: &Transaction<'_>, delivery: &Delivery) -> Result<i32, Error> {
+    // no-op update lets the existing row be returned
+    Ok(trx
+        .query_one(
+            "
+                WITH delivery (name, phone, zip, city, address, region, email) AS (
+                    VALUES (
+                        $1::varchar,
+                        $2::varchar,
+                        $3::varchar,
+                        $4::varchar,
+                        $5::varchar,
+                        $6::varchar,
+                        $7::varchar
+                    )
+                )
+                INSERT INTO deliveries
+                    (name, phone, zip, city, address, region, email, content_hash)
+                SELECT *
+                    , sha256(convert_to(
+                        concat_ws(chr(31), name, phone, zip, city, address, region, email),
+                        'UTF8'
+                    ))
+                FROM delivery
+                ON CONFLICT (content_hash) DO UPDATE
+                SET content_hash = EXCLUDED.content_hash
+                RETURNING id
+            ",
+            &[
+                &delivery.name,
+                &delivery.phone,
+                &delivery.zip,
+                &delivery.city,
+                &delivery.address,
+                &delivery.region,
+                &delivery.email,
+            ],
+        )
+        .await?
+        .try_get(0)?)
+}
+
+/// Remove the delivery unless another order refers to it.
+async fn delete_unused_delivery(trx: &Transaction<'_>, delivery_id: i32) -> Result<(), Error> {
+    trx.execute(
+        "
+            DELETE FROM deliveries
+            WHERE id = $1
+                AND NOT EXISTS (SELECT 1 FROM orders WHERE delivery_id = $1)
+        ",
+        &[&delivery_id],
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Insert items with a single statement, or with `COPY` if there are at least `copy_threshold` of them:
+/// statement might have no more than 32767 parameters and it's slow to build for large orders.
+/// Normalized items are written via arrays, so they have no such limit.
+async fn insert_items(
+    trx: &Transaction<'_>,
+    items: &[Item],
+    storage: Storage,
+) -> Result<Vec<i32>, Error> {
+    if storage.normalized {
+        return insert_normalized_items(trx, items).await;
+    }
+    if items.len() < storage.copy_threshold {
+        return select_item_ids(trx, items).await;
+    }
+
+    let item_ids = reserve_ids(trx, "items_id_seq", items.len()).await?;
+    copy_items(trx, &item_ids, items).await?;
+
+    Ok(item_ids)
+}
+
+/// Link items to the order, in the same way as [`insert_items`] does.
+async fn insert_links(
+    trx: &Transaction<'_>,
+    order_id: &str,
+    item_ids: &[i32],
+    storage: Storage,
+) -> Result<(), Error> {
+    if item_ids.len() < storage.copy_threshold {
+        return insert_into_items_to_order(trx, order_id, item_ids).await;
+    }
+
+    let links: Vec<_> = item_ids
+        .iter()
+        .map(|item_id| vec![&order_id as &(dyn ToSql + Sync), item_id])
+        .collect();
+    copy_in(trx, "items_to_order", "order_id, item_id", &links).await?;
+
+    Ok(())
+}
+
+/// Insert items which refer to products instead of keeping their attributes.
+async fn insert_normalized_items(trx: &Transaction<'_>, items: &[Item]) -> Result<Vec<i32>, Error> {
+    if items.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let product_ids = upsert_products(trx, items).await?;
+    let product_id = |item: &Item| {
+        product_ids[&(item.nm_id, item.brand.clone(), item.name.clone(), item.size.clone())]
+    };
+
+    // ids are known in advance to keep them in the same order as items
+    let item_ids = reserve_ids(trx, "items_id_seq", items.len()).await?;
+    trx.execute(
+        "
+            INSERT INTO items
+                (id
+                , chrt_id
+                , track_number
+                , price
+                , rid
+                , sale
+                , total_price
+                , status
+                , product_id)
+            SELECT *
+            FROM unnest(
+                $1::int4[],
+                $2::int4[],
+                $3::varchar[],
+                $4::int4[],
+                $5::varchar[],
+                $6::int2[],
+                $7::int4[],
+                $8::item_status[],
+                $9::int4[]
+            )
+        ",
+        &[
+            &item_ids,
+            &items.iter().map(|item| item.chrt_id).collect::<Vec<_>>(),
+            &items.iter().map(|item| &item.track_number).collect::<Vec<_>>(),
+            &items.iter().map(|item| item.price).collect::<Vec<_>>(),
+            &items.iter().map(|item| &item.rid).collect::<Vec<_>>(),
+            &items.iter().map(|item| &item.sale).collect::<Vec<_>>(),
+            &items.iter().map(|item| item.total_price).collect::<Vec<_>>(),
+            &items.iter().map(|item| item.status).collect::<Vec<_>>(),
+            &items.iter().map(product_id).collect::<Vec<_>>(),
+        ],
+    )
+    .await?;
+
+    Ok(item_ids)
+}
+
+type ProductKey = (i32, String, String, String);
+
+/// Get ids of products of the items, missing ones are inserted.
+async fn upsert_products(
+    trx: &Transaction<'_>,
+    items: &[Item],
+) -> Result<HashMap<ProductKey, i32>, Error> {
+    // a row can't be upserted twice by the same statement
+    let products: HashSet<ProductKey> = items
+        .iter()
+        .map(|item| (item.nm_id, item.brand.clone(), item.name.clone(), item.size.clone()))
+        .collect();
+
+    let (mut nm_ids, mut brands, mut names, mut sizes) = (vec![], vec![], vec![], vec![]);
+    for (nm_id, brand, name, size) in &products {
+        nm_ids.push(nm_id);
+        brands.push(brand);
+        names.push(name);
+        sizes.push(size);
+    }
+
+    // no-op update lets existing rows be returned
+    trx.query(
+        "
+            INSERT INTO products (nm_id, brand, name, size)
+            SELECT *
+            FROM unnest($1::int4[], $2::varchar[], $3::varchar[], $4::varchar[])
+            ON CONFLICT (nm_id, brand, name, size) DO UPDATE
+            SET nm_id = EXCLUDED.nm_id
+            RETURNING id, nm_id, brand, name, size
+        ",
+        &[&nm_ids, &brands, &names, &sizes],
+    )
+    .await?
+    .into_iter()
+    .map(|row| {
+        let key = (row.try_get(1)?, row.try_get(2)?, row.try_get(3)?, row.try_get(4)?);
+        Ok((key, row.try_get(0)?))
+    })
+    .collect()
+}
+
+/// Take ids from the sequence in advance, since `COPY` can't return generated ones.
+async fn reserve_ids<T>(trx: &Transaction<'_>, sequence: &str, count: usize) -> Result<Vec<T>, Error>
+where
+    T: TryFrom<i64>,
+    T::Error: std::error::Error + Send + Sync + 'static,
+{
+    let count = i32::try_from(count).map_err(anyhow::Error::from)?;
+
+    trx.query(
+        &format!("SELECT nextval('{}') FROM generate_series(1, $1)", sequence),
+        &[&count],
+    )
+    .await?
+    .into_iter()
+    .map(|row| Ok(T::try_from(row.try_get::<_, i64>(0)?).map_err(anyhow::Error::from)?))
+    .collect()
+}
+
+async fn copy_items(
+    trx: &Transaction<'_>,
+    item_ids: &[i32],
+    items: impl IntoIterator<Item = &Item>,
+) -> Result<(), Error> {
+    let rows: Vec<_> = item_ids
+        .iter()
+        .zip(items)
+        .map(|(id, item)| {
+            vec![
+                id as &(dyn ToSql + Sync),
+                &item.chrt_id,
+                &item.track_number,
+                &item.price,
+                &item.rid,
+                &item.name,
+                &item.sale,
+                &item.size,
+                &item.total_price,
+                &item.nm_id,
+                &item.brand,
+                &item.status,
+            ]
+        })
+        .collect();
+    copy_in(
+        trx,
+        "items",
+        "
+            id
+            , chrt_id
+            , track_number
+            , price
+            , rid
+            , name
+            , sale
+            , size
+            , total_price
+            , nm_id
+            , brand
+            , status
+        ",
+        &rows,
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Write rows into the table with binary `COPY`.
+async fn copy_in(
+    trx: &Transaction<'_>,
+    table: &str,
+    columns: &str,
+    rows: &[Vec<&(dyn ToSql + Sync)>],
+) -> Result<u64, Error> {
+    // binary format needs exact types, custom enums included
+    let types: Vec<Type> = trx
+        .prepare(&format!("SELECT {} FROM {} LIMIT 0", columns, table))
+        .await?
+        .columns()
+        .iter()
+        .map(|column| column.type_().clone())
+        .collect();
+
+    let sink = trx
+        .copy_in(&format!("COPY {} ({}) FROM STDIN BINARY", table, columns))
+        .await?;
+    let mut writer = pin!(BinaryCopyInWriter::new(sink, &types));
+    for row in rows {
+        writer.as_mut().write(row).await?;
+    }
+
+    Ok(writer.finish().await?)
+}
+
+/// Pick the error which failed pipelined queries, the rest fail since the transaction is aborted.
+fn cause<const N: usize>(errors: [Option<Error>; N]) -> Error {
+    let is_aborted = |e: &Error| {
+        matches!(e, Error::PgQueryFailed(e) if e.code() == Some(&SqlState::IN_FAILED_SQL_TRANSACTION))
+    };
+
+    let mut errors = errors.into_iter().flatten();
+    let first = errors.next().expect("at least one error");
+    if is_aborted(&first) {
+        errors.find(|e| !is_aborted(e)).unwrap_or(first)
+    } else {
+        first
+    }
+}
+
+/// The longest prefix of `s` which fits into `max` bytes and ends on a char boundary.
+fn truncate(s: &str, max: usize) -> &str {
+    if s.len() <= max {
+        return s;
+    }
+    let end = (0..=max).rev().find(|i| s.is_char_boundary(*i)).unwrap_or(0);
+    &s[..end]
+}
+
+/// Tell an order rejected by database constraints from a failure of the database itself.
+pub(crate) fn rejected_status(e: &Error) -> Option<CreateStatus> {
+    let Error::PgQueryFailed(e) = e else {
+        return None;
+    };
+
+    match e.code()? {
+        code if *code == SqlState::UNIQUE_VIOLATION => Some(CreateStatus::Duplicate),
+        // data exceptions and integrity constraint violations
+        code if code.code().starts_with("22") || code.code().starts_with("23") => {
+            Some(CreateStatus::Invalid)
+        }
+        _ => None,
+    }
+}
+
+/// Get items of the order along with their status history.
+async fn select_items(conn: &impl GenericClient, order_id: &str) -> Result<Vec<Item>, Error> {
+    let mut items = conn
+        .query(
+            "
+                SELECT *
+                FROM item_details
+                WHERE id IN (
+                    SELECT item_id
+                    FROM items_to_order
+                    WHERE order_id = $1
+                )
+            ",
+            &[&order_id],
+        )
+        .await?
+        .into_iter()
+        .map(Item::try_from)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if items.is_empty() {
+        return Ok(items);
+    }
+
+    let item_ids: Vec<i32> = items.iter().filter_map(|item| item.id).collect();
+    let rows = conn
+        .query(
+            "
+                SELECT item_id, status, changed_at
+                FROM item_status_history
+                WHERE item_id = ANY($1)
+                ORDER BY id
+            ",
+            &[&item_ids],
+        )
+        .await?;
+
+    for row in rows {
+        let item_id: i32 = row.try_get("item_id")?;
+        if let Some(item) = items.iter_mut().find(|item| item.id == Some(item_id)) {
+            item.status_history.push(row.try_into()?);
         }
     }
 
+    Ok(items)
+}
+
+/// Record current status of the items.
+async fn insert_into_item_status_history(
+    trx: &Transaction<'_>,
+    item_ids: &[impl ToSql + Sync],
+) -> Result<(), Error> {
+    if item_ids.is_empty() {
+        return Ok(());
+    }
+
+    trx.execute(
+        "
+            INSERT INTO item_status_history (item_id, status)
+            SELECT id, status
+            FROM items
+            WHERE id = ANY($1)
+        ",
+        &[&item_ids],
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Remove items of the order along with their links to it.
+async fn delete_items(trx: &Transaction<'_>, order_id: &str) -> Result<(), Error> {
+    trx.execute(
+        "
+            WITH links AS (
+                DELETE FROM items_to_order
+                WHERE order_id = $1
+                RETURNING item_id
+            )
+            DELETE FROM items
+            WHERE id IN (SELECT item_id FROM links)
+        ",
+        &[&order_id],
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Returns `false` if the delivery is shared, i.e. has content hash, so it isn't changed.
+async fn update_delivery(
+    trx: &Transaction<'_>,
+    delivery_id: i32,
+    delivery: &Delivery,
+) -> Result<bool, Error> {
+    let count = trx.execute(
+        "
+            UPDATE deliveries
+            SET name = $2
+                , phone = $3
+                , zip = $4
+                , city = $5
+                , address = $6
+                , region = $7
+                , email = $8
+            WHERE id = $1
+                AND content_hash IS NULL
+        ",
+        &[
+            &delivery_id,
+            &delivery.name,
+            &delivery.phone,
+            &delivery.zip,
+            &delivery.city,
+            &delivery.address,
+            &delivery.region,
+            &delivery.email,
+        ],
+    )
+    .await?;
+
+    Ok(count > 0)
+}
+
+async fn update_payment(trx: &Transaction<'_>, payment: &Payment) -> Result<(), Error> {
+    trx.execute(
+        "
+            UPDATE payments
+            SET request_id = $2
+                , currency = $3
+                , provider = $4
+                , amount = $5
+                , payment_dt = $6
+                , bank = $7
+                , delivery_cost = $8
+                , goods_total = $9
+                , custom_fee = $10
+            WHERE transaction = $1
+        ",
+        &[
+            &payment.transaction,
+            &payment.request_id,
+            &payment.currency,
+            &payment.provider,
+            &payment.amount,
+            &payment.payment_dt,
+            &payment.bank,
+            &payment.delivery_cost,
+            &payment.goods_total,
+            &payment.custom_fee,
+        ],
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Status is left as it is, it's changed via its own transitions only.
+async fn update_orders(
+    trx: &Transaction<'_>,
+    order: &Order,
+    delivery_id: i32,
+) -> Result<(), Error> {
+    trx.execute(
+        "
+            UPDATE orders
+            SET track_number = $2
+                , entry = $3
+                , payment_id = $4
+                , locale = $5
+                , internal_signature = $6
+                , customer_id = $7
+                , delivery_service = $8
+                , shardkey = $9
+                , sm_id = $10
+                , date_created = $11
+                , oof_shard = $12
+                , delivery_id = $13
+            WHERE order_uid = $1
+        ",
+        &[
+            &order.order_uid,
+            &order.track_number,
+            &order.entry,
+            &order.payment.transaction,
+            &order.locale,
+            &order.internal_signature,
+            &order.customer_id,
+            &order.delivery_service,
+            &order.shardkey,
+            &order.sm_id,
+            &order.date_created,
+            &order.oof_shard,
+            &delivery_id,
+        ],
+    )
+    .await?;
+
+    Ok(())
+}
+
+async fn select_delivery_id(
+    trx: &Transaction<'_>, 
+    delivery: &Delivery,
+) -> Result<i32, Error> 
+{
+    Ok(trx.query_one(
+        "
+            INSERT INTO deliveries (name, phone, zip, city, address, region, email)
+            VALUES ($1, $2, $3, $4, $5, $6, $7) 
+            RETURNING id
+        ",
+        &[
+            &delivery.name,
+            &delivery.phone,
+            &delivery.zip,
+            &delivery.city,
+            &delivery.address,
+            &delivery.region,
+            &delivery.email,
+        ],
+    )
+    .await?
+    .get::<usize, i32>(0))
+}
+
+async fn select_payment_id(
+    trx: &Transaction<'_>, 
+    payment: &Payment
+) -> Result<impl ToSql + Sync, Error> 
+{
+    Ok(trx.query_one(
+        "
+            INSERT INTO payments 
+                (transaction
+                , request_id
+                , currency
+                , provider
+                , amount
+                , payment_dt
+                , bank
+                , delivery_cost
+                , goods_total
+                , custom_fee)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10) 
+            RETURNING transaction
+        ",
+        &[
+            &payment.transaction,
+            &payment.request_id,
+            &payment.currency,
+            &payment.provider,
+            &payment.amount,
+            &payment.payment_dt,
+            &payment.bank,
+            &payment.delivery_cost,
+            &payment.goods_total,
+            &payment.custom_fee,
+        ],
+    )
+    .await?
+    .get::<usize, String>(0))
+}
+
+async fn select_item_ids(
+    trx: &Transaction<'_>, 
+    items: &[Item]
+) -> Result<Vec<i32>, Error> {
+    if items.is_empty() {
+        return Ok(Vec::<i32>::new());
+    }
+
+    #[rustfmt::skip]
+    let mut query = 
+        "
+            WITH item_ids AS (
+                INSERT INTO items
+                    (chrt_id
+                    , track_number
+                    , price
+                    , rid
+                    , name
+                    , sale
+                    , size
+                    , total_price
+                    , nm_id
+                    , brand
+                    , status)
+                VALUES
+        "
+        .to_owned();
+
+    let params = (1..)
+        .step_by(11)
+        .zip(items.iter().enumerate().rev())
+        .try_fold(
+            Vec::<&(dyn ToSql + Sync)>::with_capacity(items.len() * 11),
+            |mut params, (param_id, (id, item))| {
+                use std::fmt::Write;
+
+                write!(
+                    &mut query, 
+                    "(${}, ${}, ${}, ${}, ${}, ${}, ${}, ${}, ${}, ${}, ${})", 
+                    param_id, 
+                    param_id + 1, 
+                    param_id + 2, 
+                    param_id + 3, 
+                    param_id + 4, 
+                    param_id + 5, 
+                    param_id + 6, 
+                    param_id + 7, 
+                    param_id + 8, 
+                    param_id + 9, 
+                    param_id + 10
+                )?;
+                if id > 0 {
+                    query.push(',');
+                }
+
+                params.extend_from_slice(&[
+                    &item.chrt_id,
+                    &item.track_number,
+                    &item.price,
+                    &item.rid,
+                    &item.name,
+                    &item.sale,
+                    &item.size,
+                    &item.total_price,
+                    &item.nm_id,
+                    &item.brand,
+                    &item.status,
+                ]);
+
+                Ok::<_, anyhow::Error>(params)
+            },
+        )?;
+
+    #[rustfmt::skip]
+    query.push_str(
+        "
+                RETURNING id
+            )
+            SELECT id
+            FROM item_ids
+        "
+    );
+
+    Ok(trx
+        .query(&query, &params)
+        .await?
+        .into_iter()
+        .map(|row| row.get(0))
+        .collect())
+}
+
+async fn insert_into_orders(
+    trx: &Transaction<'_>, 
+    order: &Order, 
+    delivery_id: impl ToSql + Sync, 
+    payment_id: impl ToSql + Sync
+) -> Result<(), Error> 
+{
+    trx.execute(
+        "
+            INSERT INTO orders
+                (order_uid
+                , track_number
+                , entry
+                , delivery_id
+                , payment_id
+                , locale
+                , internal_signature
+                , customer_id
+                , delivery_service
+                , shardkey
+                , sm_id
+                , date_created
+                , oof_shard
+                , status)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14)
+        ",
+        &[
+            &order.order_uid,
+            &order.track_number,
+            &order.entry,
+            &delivery_id,
+            &payment_id,
+            &order.locale,
+            &order.internal_signature,
+            &order.customer_id,
+            &order.delivery_service,
+            &order.shardkey,
+            &order.sm_id,
+            &order.date_created,
+            &order.oof_shard,
+            &order.status,
+        ],
+    )
+    .await?;
+
+    Ok(())
+}
+
+async fn insert_into_items_to_order(
+    trx: &Transaction<'_>, 
+    order_id: impl ToSql + Sync, 
+    item_ids: &[impl ToSql + Sync]
+) -> Result<(), Error> 
+{
+    if item_ids.is_empty() {
+        return Ok(());
+    }
+
+    #[rustfmt::skip]
+    let mut query = 
+        "
+            INSERT INTO items_to_order (order_id, item_id)
+            VALUES
+        "
+        .to_owned();
+
+    let params = (1..)
+        .step_by(2)
+        .zip(item_ids.iter().enumerate().rev())
+        .try_fold(
+            Vec::<&(dyn ToSql + Sync)>::with_capacity(item_ids.len() * 2),
+            |mut params, (param_id, (id, item_id))| {
+                use std::fmt::Write;
+
+                write!(&mut query, "(${}, ${})", param_id, param_id + 1)?;
+                if id > 0 {
+                    query.push(',');
+                }
+
+                params.extend_from_slice(&[&order_id, item_id]);
+
+                Ok::<_, anyhow::Error>(params)
+            },
+        )?;
+
+    trx.execute(&query, &params).await?;
+
+    Ok(())
 }
@@ -0,0 +1,1194 @@
+use axum::{
+    extract::DefaultBodyLimit,
+    middleware,
+    routing::{get, patch, post},
+    Router,
+};
+
+use crate::{
+    auth, handler, limit,
+    state::{
+        AppState, CacheOrder, StoreApiKey, StoreEvent, StoreOrder, StoreRejected, StoreWebhook,
+    },
+};
+
+/// Routes of the service, requests are served with the given state.
+pub fn app_with_state(
+    state: AppState<
+        impl StoreOrder
+            + StoreEvent
+            + StoreRejected
+            + StoreWebhook
+            + StoreApiKey
+            + Clone
+            + Send
+            + Sync
+            + 'static,
+        impl CacheOrder + Clone + Send + Sync + 'static,
+    >,
+) -> Router {
+    let orders = Router::new()
+        .route("/order", post(handler::create_order))
+        .route("/orders:method", post(handler::call_orders_method))
+        .route("/orders/stream", get(handler::stream_orders))
+        .route(
+            "/orders/:order_id",
+            get(handler::get_order)
+                .put(handler::update_order)
+                .delete(handler::delete_order),
+        )
+        .route("/orders/:order_id/delivery", get(handler::get_delivery))
+        .route("/orders/:order_id/items", get(handler::get_items))
+        .route("/orders/:order_id/payment", get(handler::get_payment))
+        .route(
+            "/orders/:order_id/status",
+            patch(handler::update_order_status),
+        )
+        .route(
+            "/tracks/:track_number",
+            get(handler::get_order_by_track_number),
+        )
+        .route(
+            "/customers/:customer_id/orders",
+            get(handler::get_orders_by_customer),
+        )
+        .route(
+            "/payments/:transaction/order",
+            get(handler::get_order_by_transaction),
+        )
+        .route(
+            "/orders/:order_id/items/:chrt_id/status",
+            patch(handler::update_item_status),
+        );
+    // clients are limited once they are authenticated, IPs are limited before that
+    let mut orders = orders.route_layer(middleware::from_fn_with_state(
+        state.clone(),
+        limit::limit_requests,
+    ));
+    if state.require_api_key || state.jwt.is_some() {
+        orders = orders.route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::authenticate,
+        ));
+    }
+    let orders = orders.route_layer(middleware::from_fn_with_state(
+        state.clone(),
+        limit::limit_ips,
+    ));
+
+    // the admin is authorized by its own token
+    let admin = Router::new()
+        .route("/orders/:order_id/raw", get(handler::get_order_payload))
+        .route("/orders/:order_id/audit", get(handler::get_order_audit))
+        .route(
+            "/webhooks",
+            get(handler::get_webhooks).post(handler::create_webhook),
+        )
+        .route(
+            "/webhooks/:id",
+            get(handler::get_webhook).delete(handler::delete_webhook),
+        )
+        .route("/webhooks/:id/attempts", get(handler::get_webhook_attempts))
+        .route("/rejected_orders", get(handler::get_rejected_orders))
+        .route(
+            "/rejected_orders/:id",
+            get(handler::get_rejected_order).delete(handler::delete_rejected_order),
+        )
+        .route(
+            "/rejected_orders/:id/replay",
+            post(handler::replay_rejected_order),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            limit::limit_ips,
+        ));
+
+    orders
+        .merge(admin)
+        .layer(DefaultBodyLimit::max(state.max_body_size))
+        .layer(middleware::from_fn(handler::request_id))
+        .with_state(state)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+    };
+
+    use axum::{
+        body::{to_bytes, Body},
+        extract::ConnectInfo,
+        http::{header, Request, StatusCode},
+    };
+    use tower::ServiceExt;
+
+    use crate::{
+        auth::{self, JwtVerifier},
+        error::Error,
+        handler::{BatchCreateResult, BatchGetResult},
+        limit::RateLimiter,
+        mock::{MockCache, MockStore, NoCache},
+        model::{
+            demo_order, Actor, ApiKey, ApiScope, AuditEntry, AuditOperation, BatchMode,
+            CreateOutcome, CreateStatus, EventType, Item, Order, OrderEvent, OrderPayload,
+            OrderStatus, RejectionSource, Webhook,
+        },
+        state::AppState,
+    };
+    use jsonwebtoken::{Algorithm, EncodingKey, Header};
+    use serde_json::value::RawValue;
+
+    use super::app_with_state;
+
+    #[tokio::test]
+    async fn get_items_of_no_order() {
+        #[derive(Clone)]
+        struct MockRepo;
+
+        impl MockStore for MockRepo {
+            async fn get_items(&self, _: &str) -> Result<Option<Vec<Item>>, Error> {
+                Ok(None)
+            }
+        }
+
+        let state = AppState::new(MockRepo, Option::<NoCache>::None);
+        let response = app_with_state(state)
+            .oneshot(
+                Request::builder()
+                    .uri("/orders/defenetly_does_not_exist_order_id/items")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn get_items_of_order_without_items() {
+        #[derive(Clone)]
+        struct MockRepo;
+
+        impl MockStore for MockRepo {
+            async fn get_items(&self, _: &str) -> Result<Option<Vec<Item>>, Error> {
+                Ok(Some(vec![]))
+            }
+        }
+
+        let state = AppState::new(MockRepo, Option::<NoCache>::None);
+        let response = app_with_state(state)
+            .oneshot(
+                Request::builder()
+                    .uri("/orders/b563feb7b2b84b6test/items")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn get_delivery_of_cached_order() {
+        #[derive(Clone)]
+        struct MockRepo;
+
+        // database must not be touched if order is cached
+        impl MockStore for MockRepo {}
+
+        #[derive(Clone)]
+        struct Cache;
+
+        impl MockCache for Cache {
+            async fn get_order(&self, _: &str) -> Result<Option<Order>, Error> {
+                Ok(Some(demo_order("b563feb7b2b84b6test", 0)))
+            }
+        }
+
+        let state = AppState::new(MockRepo, Some(Cache));
+        let response = app_with_state(state)
+            .oneshot(
+                Request::builder()
+                    .uri("/orders/b563feb7b2b84b6test/delivery")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn get_payment_of_not_cached_order() {
+        #[derive(Clone)]
+        struct MockRepo;
+
+        // full order is fetched to fill cache
+        impl MockStore for MockRepo {
+            async fn get_order(&self, _: &str) -> Result<Option<Order>, Error> {
+                Ok(Some(demo_order("b563feb7b2b84b6test", 0)))
+            }
+        }
+
+        #[derive(Clone)]
+        struct Cache;
+
+        impl MockCache for Cache {
+            async fn get_order(&self, _: &str) -> Result<Option<Order>, Error> {
+                Ok(None)
+            }
+
+            async fn insert_order(&self, _: &Order) -> Result<(), Error> {
+                Ok(())
+            }
+        }
+
+        let state = AppState::new(MockRepo, Some(Cache));
+        let response = app_with_state(state)
+            .oneshot(
+                Request::builder()
+                    .uri("/orders/b563feb7b2b84b6test/payment")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn get_order_from_cached_body() {
+        #[derive(Clone)]
+        struct MockRepo;
+
+        // database must not be touched if order is cached
+        impl MockStore for MockRepo {}
+
+        #[derive(Clone)]
+        struct Cache;
+
+        impl MockCache for Cache {
+            async fn get_order_json(&self, _: &str) -> Result<Option<Vec<u8>>, Error> {
+                Ok(Some(
+                    serde_json::to_vec(&demo_order("b563feb7b2b84b6test", 0)).unwrap(),
+                ))
+            }
+        }
+
+        let state = AppState::new(MockRepo, Some(Cache));
+        let response = app_with_state(state)
+            .oneshot(
+                Request::builder()
+                    .uri("/orders/b563feb7b2b84b6test")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(
+            serde_json::from_slice::<Order>(&body).unwrap(),
+            demo_order("b563feb7b2b84b6test", 0)
+        );
+    }
+
+    #[tokio::test]
+    async fn update_order_with_other_id() {
+        #[derive(Clone)]
+        struct MockRepo;
+
+        // database must not be touched if ids don't match
+        impl MockStore for MockRepo {}
+
+        let state = AppState::new(MockRepo, Option::<NoCache>::None);
+        let response = app_with_state(state)
+            .oneshot(
+                Request::builder()
+                    .method("PUT")
+                    .uri("/orders/another_order_id")
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(
+                        serde_json::to_vec(&demo_order("b563feb7b2b84b6test", 0)).unwrap(),
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[tokio::test]
+    async fn batch_get_orders_from_cache_and_database() {
+        #[derive(Clone)]
+        struct MockRepo;
+
+        // only orders missed by cache are fetched
+        impl MockStore for MockRepo {
+            async fn get_orders(&self, order_ids: &[String]) -> Result<Vec<Order>, Error> {
+                assert_eq!(order_ids, ["missing_order_id"]);
+                Ok(Vec::new())
+            }
+        }
+
+        #[derive(Clone)]
+        struct Cache;
+
+        impl MockCache for Cache {
+            async fn get_orders(&self, order_ids: &[String]) -> Result<Vec<Option<Order>>, Error> {
+                Ok(order_ids
+                    .iter()
+                    .map(|id| {
+                        (id == "b563feb7b2b84b6test").then(|| demo_order("b563feb7b2b84b6test", 0))
+                    })
+                    .collect())
+            }
+        }
+
+        let state = AppState::new(MockRepo, Some(Cache));
+        let response = app_with_state(state)
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/orders:batchGet")
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(
+                        r#"{"ids": ["b563feb7b2b84b6test", "missing_order_id", "missing_order_id"]}"#,
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let result: BatchGetResult = serde_json::from_slice(&body).unwrap();
+        assert_eq!(result.orders, [demo_order("b563feb7b2b84b6test", 0)]);
+        assert_eq!(result.missing, ["missing_order_id"]);
+    }
+
+    #[tokio::test]
+    async fn batch_create_orders_from_ndjson() {
+        #[derive(Clone)]
+        struct MockRepo;
+
+        // invalid entries don't reach database
+        impl MockStore for MockRepo {
+            async fn create_orders(
+                &self,
+                orders: Vec<(Order, Box<RawValue>)>,
+                mode: BatchMode,
+                _: &Actor,
+            ) -> Result<Vec<CreateOutcome>, Error> {
+                assert_eq!(orders.len(), 2);
+                assert_eq!(mode, BatchMode::Savepoint);
+                // payloads are kept as they are in the body
+                assert!(orders
+                    .iter()
+                    .all(|(_, payload)| payload.get().contains("\"extra\": true")));
+                Ok(vec![
+                    CreateOutcome::new(CreateStatus::Created),
+                    CreateOutcome::new(CreateStatus::Duplicate),
+                ])
+            }
+
+            async fn reject_order(
+                &self,
+                source: RejectionSource,
+                payload: &str,
+                _: &str,
+            ) -> Result<(), Error> {
+                // the line which isn't JSON isn't kept
+                assert_eq!(source, RejectionSource::Http);
+                assert_eq!(payload, r#"{"order_uid": "broken"}"#);
+                Ok(())
+            }
+        }
+
+        let order = serde_json::to_string(&demo_order("b563feb7b2b84b6test", 0)).unwrap();
+        let order = format!("{}, \"extra\": true}}", order.trim_end_matches('}'));
+        let body = format!(
+            "{}\n{{\"order_uid\": \"broken\"}}\n\nnot json\n{}\n",
+            order, order
+        );
+
+        let state = AppState::new(MockRepo, Option::<NoCache>::None);
+        let response = app_with_state(state)
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/orders:batchCreate?mode=savepoint")
+                    .header(header::CONTENT_TYPE, "application/x-ndjson")
+                    .body(Body::from(body))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let results: Vec<BatchCreateResult> = serde_json::from_slice(&body).unwrap();
+        let statuses: Vec<_> = results
+            .iter()
+            .map(|result| (result.order_uid.as_deref(), result.status))
+            .collect();
+        assert_eq!(
+            statuses,
+            [
+                (Some("b563feb7b2b84b6test"), CreateStatus::Created),
+                (Some("broken"), CreateStatus::Invalid),
+                (None, CreateStatus::Invalid),
+                (Some("b563feb7b2b84b6test"), CreateStatus::Duplicate),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn keep_json_of_rejected_orders() {
+        #[derive(Clone)]
+        struct MockRepo(Arc<Mutex<Vec<String>>>);
+
+        impl MockStore for MockRepo {
+            async fn reject_order(
+                &self,
+                source: RejectionSource,
+                payload: &str,
+                _: &str,
+            ) -> Result<(), Error> {
+                assert_eq!(source, RejectionSource::Http);
+                self.0.lock().unwrap().push(payload.to_owned());
+                Ok(())
+            }
+        }
+
+        let rejected = Arc::new(Mutex::new(vec![]));
+        let app = app_with_state(AppState::new(
+            MockRepo(rejected.clone()),
+            Option::<NoCache>::None,
+        ));
+        let create = |content_type: &str, body: &str| {
+            Request::builder()
+                .method("POST")
+                .uri("/order")
+                .header(header::CONTENT_TYPE, content_type)
+                .body(Body::from(body.to_owned()))
+                .unwrap()
+        };
+
+        for (content_type, body, status) in [
+            ("application/json", "not json", StatusCode::BAD_REQUEST),
+            ("text/plain", "{}", StatusCode::UNSUPPORTED_MEDIA_TYPE),
+            (
+                "application/json",
+                r#"{"order_uid": 42}"#,
+                StatusCode::UNPROCESSABLE_ENTITY,
+            ),
+        ] {
+            let response = app
+                .clone()
+                .oneshot(create(content_type, body))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), status, "{}", body);
+        }
+        // a body of another content type isn't kept
+        assert_eq!(
+            *rejected.lock().unwrap(),
+            ["not json", r#"{"order_uid": 42}"#]
+        );
+    }
+
+    #[tokio::test]
+    async fn get_order_payload_with_admin_token() {
+        #[derive(Clone)]
+        struct MockRepo;
+
+        impl MockStore for MockRepo {
+            async fn get_order_payload(&self, _: &str) -> Result<Option<OrderPayload>, Error> {
+                Ok(Some(OrderPayload {
+                    received_at: "2021-11-26T06:22:19Z".parse().unwrap(),
+                    payload: RawValue::from_string(
+                        r#"{"order_uid":"b563feb7b2b84b6test","extra":1}"#.to_owned(),
+                    )
+                    .unwrap(),
+                }))
+            }
+        }
+
+        let app = app_with_state(
+            AppState::new(MockRepo, Option::<NoCache>::None)
+                .with_admin_token(Some("secret".to_owned())),
+        );
+
+        for token in [None, Some("Bearer wrong"), Some("secret")] {
+            let mut request = Request::builder().uri("/orders/b563feb7b2b84b6test/raw");
+            if let Some(token) = token {
+                request = request.header(header::AUTHORIZATION, token);
+            }
+
+            let response = app
+                .clone()
+                .oneshot(request.body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        }
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/orders/b563feb7b2b84b6test/raw")
+                    .header(header::AUTHORIZATION, "Bearer secret")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let payload: OrderPayload = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            payload.payload.get(),
+            r#"{"order_uid":"b563feb7b2b84b6test","extra":1}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn create_webhook_with_generated_secret() {
+        #[derive(Clone)]
+        struct MockRepo;
+
+        impl MockStore for MockRepo {
+            async fn create_webhook(
+                &self,
+                url: &str,
+                event_types: &[EventType],
+                secret: &str,
+            ) -> Result<Webhook, Error> {
+                Ok(Webhook {
+                    id: 1,
+                    url: url.to_owned(),
+                    event_types: event_types.to_vec(),
+                    secret: Some(secret.to_owned()),
+                    created_at: "2021-11-26T06:22:19Z".parse().unwrap(),
+                })
+            }
+        }
+
+        let app = app_with_state(
+            AppState::new(MockRepo, Option::<NoCache>::None)
+                .with_admin_token(Some("secret".to_owned())),
+        );
+        let create = |body: &'static str| {
+            Request::builder()
+                .method("POST")
+                .uri("/webhooks")
+                .header(header::CONTENT_TYPE, "application/json")
+                .header(header::AUTHORIZATION, "Bearer secret")
+                .body(Body::from(body))
+                .unwrap()
+        };
+
+        let response = app
+            .clone()
+            .oneshot(create(
+                r#"{"url":"ftp://example.com","event_types":["order_created"]}"#,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let response = app
+            .oneshot(create(
+                r#"{"url":"https://example.com/events","event_types":["order_created"]}"#,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let webhook: Webhook = serde_json::from_slice(&body).unwrap();
+        assert_eq!(webhook.event_types, [EventType::OrderCreated]);
+        assert_eq!(webhook.secret.map(|secret| secret.len()), Some(64));
+    }
+
+    #[tokio::test]
+    async fn stream_orders_after_last_event_id() {
+        #[derive(Clone)]
+        struct MockRepo;
+
+        impl MockStore for MockRepo {
+            async fn get_events(
+                &self,
+                event_type: EventType,
+                after_id: i64,
+                _: i64,
+            ) -> Result<Vec<OrderEvent>, Error> {
+                assert_eq!(event_type, EventType::OrderCreated);
+                assert_eq!(after_id, 5);
+                let event = |id, customer_id| OrderEvent {
+                    id,
+                    event_type,
+                    order_id: id.to_string(),
+                    created_at: "2021-11-26T06:22:19Z".parse().unwrap(),
+                    data: RawValue::from_string(format!(
+                        r#"{{"customer_id":"{}","delivery_service":"meest"}}"#,
+                        customer_id
+                    ))
+                    .unwrap(),
+                    attempts: 0,
+                };
+                Ok(vec![event(6, "test"), event(7, "other"), event(8, "test")])
+            }
+        }
+
+        // the feed is closed once the state is dropped, so the stream ends after replay
+        let (events, _) = tokio::sync::broadcast::channel(1);
+        let app =
+            app_with_state(AppState::new(MockRepo, Option::<NoCache>::None).with_events(events));
+        let stream = |last_event_id: &'static str| {
+            Request::builder()
+                .uri("/orders/stream?customer_id=test")
+                .header("last-event-id", last_event_id)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = app.clone().oneshot(stream("five")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let response = app.oneshot(stream("5")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "text/event-stream"
+        );
+
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body = std::str::from_utf8(&body).unwrap();
+        let ids: Vec<_> = body
+            .lines()
+            .filter_map(|line| line.strip_prefix("id: "))
+            .collect();
+        assert_eq!(ids, ["6", "8"]);
+        assert!(body.contains("event: order_created\n"));
+    }
+
+    #[tokio::test]
+    async fn replay_rejected_order_with_fixed_payload() {
+        #[derive(Clone)]
+        struct MockRepo;
+
+        impl MockStore for MockRepo {
+            async fn replay_rejected_order(
+                &self,
+                id: i64,
+                order: Order,
+                payload: Box<RawValue>,
+                _: &Actor,
+            ) -> Result<bool, Error> {
+                assert_eq!(order.order_uid, "b563feb7b2b84b6test");
+                assert!(payload.get().contains("\"extra\": true"));
+                Ok(id == 3)
+            }
+        }
+
+        let app = app_with_state(
+            AppState::new(MockRepo, Option::<NoCache>::None)
+                .with_admin_token(Some("secret".to_owned())),
+        );
+        let replay = |id: i64, body: String| {
+            Request::builder()
+                .method("POST")
+                .uri(format!("/rejected_orders/{}/replay", id))
+                .header(header::AUTHORIZATION, "Bearer secret")
+                .body(Body::from(body))
+                .unwrap()
+        };
+        let order = serde_json::to_string(&demo_order("b563feb7b2b84b6test", 0)).unwrap();
+        let fixed = format!("{}, \"extra\": true}}", order.trim_end_matches('}'));
+
+        let response = app
+            .clone()
+            .oneshot(replay(3, r#"{"order_uid": 42}"#.to_owned()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let response = app.clone().oneshot(replay(4, fixed.clone())).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = app.oneshot(replay(3, fixed)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn update_order_status_with_invalid_transition() {
+        #[derive(Clone)]
+        struct MockRepo;
+
+        impl MockStore for MockRepo {
+            async fn update_order_status(
+                &self,
+                _: &str,
+                status: OrderStatus,
+                _: &Actor,
+            ) -> Result<Option<OrderStatus>, Error> {
+                assert_eq!(status, OrderStatus::Completed);
+                Err(Error::Conflict("order is not in delivery".to_owned()))
+            }
+        }
+
+        let state = AppState::new(MockRepo, Option::<NoCache>::None);
+        let response = app_with_state(state)
+            .oneshot(
+                Request::builder()
+                    .method("PATCH")
+                    .uri("/orders/b563feb7b2b84b6test/status")
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(r#"{"status":"completed"}"#))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+    }
+
+    #[tokio::test]
+    async fn replace_invalid_request_id() {
+        #[derive(Clone)]
+        struct MockRepo;
+
+        impl MockStore for MockRepo {
+            async fn delete_order(&self, _: &str, actor: &Actor) -> Result<bool, Error> {
+                assert_eq!(actor.request_id.len(), 32);
+                Ok(true)
+            }
+        }
+
+        let app = app_with_state(AppState::new(MockRepo, Option::<NoCache>::None));
+        for id in ["a".repeat(129).as_str(), "req 1", "req\t1"] {
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .method("DELETE")
+                        .uri("/orders/b563feb7b2b84b6test")
+                        .header("x-request-id", id)
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::NO_CONTENT);
+            assert_eq!(response.headers()["x-request-id"].len(), 32, "{}", id);
+        }
+    }
+
+    #[tokio::test]
+    async fn delete_order_with_audit() {
+        #[derive(Clone)]
+        struct MockRepo;
+
+        impl MockStore for MockRepo {
+            async fn delete_order(&self, order_id: &str, actor: &Actor) -> Result<bool, Error> {
+                assert_eq!(order_id, "b563feb7b2b84b6test");
+                assert_eq!(actor.name, "anonymous");
+                assert_eq!(actor.request_id, "req-1");
+                Ok(true)
+            }
+
+            async fn get_order_audit(
+                &self,
+                order_id: &str,
+                limit: i64,
+                offset: i64,
+            ) -> Result<Vec<AuditEntry>, Error> {
+                assert_eq!((limit, offset), (50, 0));
+                if order_id != "b563feb7b2b84b6test" {
+                    return Ok(vec![]);
+                }
+                Ok(vec![AuditEntry {
+                    id: 1,
+                    order_id: order_id.to_owned(),
+                    operation: AuditOperation::Delete,
+                    actor: "anonymous".to_owned(),
+                    request_id: "req-1".to_owned(),
+                    diff: RawValue::from_string(
+                        r#"{"": {"before": {}, "after": null}}"#.to_owned(),
+                    )
+                    .unwrap(),
+                    recorded_at: "2021-11-26T06:22:19Z".parse().unwrap(),
+                }])
+            }
+        }
+
+        let app = app_with_state(
+            AppState::new(MockRepo, Option::<NoCache>::None)
+                .with_admin_token(Some("secret".to_owned())),
+        );
+        let audit = |order_id: &str, token: &str| {
+            Request::builder()
+                .uri(format!("/orders/{}/audit", order_id))
+                .header(header::AUTHORIZATION, format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method("DELETE")
+                    .uri("/orders/b563feb7b2b84b6test")
+                    .header("x-request-id", "req-1")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(response.headers()["x-request-id"], "req-1");
+
+        let response = app
+            .clone()
+            .oneshot(audit("b563feb7b2b84b6test", "wrong"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        // a request without id is given one
+        assert_eq!(response.headers()["x-request-id"].len(), 32);
+
+        let response = app
+            .clone()
+            .oneshot(audit("unknown", "secret"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = app
+            .oneshot(audit("b563feb7b2b84b6test", "secret"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let entries: Vec<AuditEntry> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].operation, AuditOperation::Delete);
+    }
+
+    #[tokio::test]
+    async fn authenticate_with_api_key() {
+        #[derive(Clone)]
+        struct MockRepo;
+
+        impl MockStore for MockRepo {
+            async fn get_order(&self, _: &str) -> Result<Option<Order>, Error> {
+                Ok(Some(demo_order("b563feb7b2b84b6test", 0)))
+            }
+
+            async fn delete_order(&self, _: &str, actor: &Actor) -> Result<bool, Error> {
+                assert_eq!(actor.name, "writer");
+                Ok(true)
+            }
+
+            async fn get_api_key(&self, key_hash: &str) -> Result<Option<ApiKey>, Error> {
+                let key =
+                    |id: i32, name: &str, scopes: Vec<ApiScope>, rate_limit: Option<i32>| ApiKey {
+                        id,
+                        name: name.to_owned(),
+                        scopes,
+                        rate_limit,
+                        created_at: "2021-11-26T06:22:19Z".parse().unwrap(),
+                        revoked_at: None,
+                    };
+                Ok(if key_hash == auth::hash_api_key(b"reader") {
+                    Some(key(1, "reader", vec![ApiScope::OrdersRead], Some(1)))
+                } else if key_hash == auth::hash_api_key(b"writer") {
+                    Some(key(
+                        2,
+                        "writer",
+                        vec![ApiScope::OrdersRead, ApiScope::OrdersWrite],
+                        None,
+                    ))
+                } else if key_hash == auth::hash_api_key(b"broken") {
+                    Some(key(3, "broken", vec![ApiScope::OrdersRead], Some(-1)))
+                } else {
+                    None
+                })
+            }
+        }
+
+        let app = app_with_state(
+            AppState::new(MockRepo, Option::<NoCache>::None).with_require_api_key(true),
+        );
+        let request = |method: &str, key: Option<&str>| {
+            let mut request = Request::builder()
+                .method(method)
+                .uri("/orders/b563feb7b2b84b6test");
+            if let Some(key) = key {
+                request = request.header("x-api-key", key);
+            }
+            request.body(Body::empty()).unwrap()
+        };
+
+        let response = app.clone().oneshot(request("GET", None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = app
+            .clone()
+            .oneshot(request("GET", Some("unknown")))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        // the rate limit isn't turned into another one
+        let response = app
+            .clone()
+            .oneshot(request("GET", Some("broken")))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        let response = app
+            .clone()
+            .oneshot(request("DELETE", Some("reader")))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = app
+            .clone()
+            .oneshot(request("DELETE", Some("writer")))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        // the reader may make a request per minute
+        let response = app
+            .clone()
+            .oneshot(request("GET", Some("reader")))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app
+            .clone()
+            .oneshot(request("GET", Some("reader")))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "60");
+        let response = app.oneshot(request("GET", Some("writer"))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn limit_ips_before_authentication() {
+        #[derive(Clone, Default)]
+        struct MockRepo(Arc<AtomicUsize>);
+
+        impl MockStore for MockRepo {
+            async fn get_api_key(&self, _: &str) -> Result<Option<ApiKey>, Error> {
+                self.0.fetch_add(1, Ordering::Relaxed);
+                Ok(None)
+            }
+        }
+
+        let repo = MockRepo::default();
+        let app = app_with_state(
+            AppState::new(repo.clone(), Option::<NoCache>::None)
+                .with_require_api_key(true)
+                .with_rate_limit(Some(1), RateLimiter::default()),
+        );
+        let request = || {
+            Request::builder()
+                .uri("/orders/b563feb7b2b84b6test")
+                .header("x-api-key", "guess")
+                .extension(ConnectInfo(SocketAddr::from(([10, 0, 0, 1], 3000))))
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = app.clone().oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        // the next guess isn't even looked up
+        let response = app.oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(repo.0.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn limit_requests_by_ip() {
+        #[derive(Clone)]
+        struct MockRepo;
+
+        impl MockStore for MockRepo {
+            async fn get_order(&self, _: &str) -> Result<Option<Order>, Error> {
+                Ok(Some(demo_order("b563feb7b2b84b6test", 0)))
+            }
+        }
+
+        let app = app_with_state(
+            AppState::new(MockRepo, Option::<NoCache>::None)
+                .with_rate_limit(Some(1), RateLimiter::default())
+                .with_max_body_size(16),
+        );
+        let request = |method: &str, uri: &str, ip: [u8; 4], body: &'static str| {
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(header::CONTENT_TYPE, "application/json")
+                .extension(ConnectInfo(SocketAddr::from((ip, 3000))))
+                .body(Body::from(body))
+                .unwrap()
+        };
+
+        // a request per minute from an IP
+        let response = app
+            .clone()
+            .oneshot(request(
+                "GET",
+                "/orders/b563feb7b2b84b6test",
+                [10, 0, 0, 1],
+                "",
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app
+            .clone()
+            .oneshot(request(
+                "GET",
+                "/orders/b563feb7b2b84b6test",
+                [10, 0, 0, 1],
+                "",
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "60");
+        let response = app
+            .clone()
+            .oneshot(request(
+                "GET",
+                "/orders/b563feb7b2b84b6test",
+                [10, 0, 0, 2],
+                "",
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // too large body isn't even stored as rejected
+        let body = r#"{"order_uid": "b563feb7b2b84b6test"}"#;
+        let response = app
+            .oneshot(request("POST", "/order", [10, 0, 0, 3], body))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn authorize_customer_with_jwt() {
+        #[derive(Clone)]
+        struct MockRepo;
+
+        impl MockStore for MockRepo {
+            async fn get_order(&self, _: &str) -> Result<Option<Order>, Error> {
+                Ok(Some(demo_order("b563feb7b2b84b6test", 0)))
+            }
+        }
+
+        let path = std::env::temp_dir().join(format!("jwt-{}.key", std::process::id()));
+        std::fs::write(&path, "secret").unwrap();
+        let jwt = JwtVerifier::from_key(&path, Algorithm::HS256, "orders", "idp").unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let app =
+            app_with_state(AppState::new(MockRepo, Option::<NoCache>::None).with_jwt(Some(jwt)));
+        let token = |customer_id: &str| {
+            let claims = serde_json::json!({
+                "sub": "support",
+                "aud": "orders",
+                "iss": "idp",
+                "exp": chrono::Utc::now().timestamp() + 60,
+                "scope": "orders:read orders:write",
+                "customer_id": customer_id,
+            });
+            jsonwebtoken::encode(
+                &Header::default(),
+                &claims,
+                &EncodingKey::from_secret(b"secret"),
+            )
+            .unwrap()
+        };
+        let request = |method: &str, uri: &str, token: &str| {
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(header::AUTHORIZATION, format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = app
+            .clone()
+            .oneshot(request("GET", "/orders/b563feb7b2b84b6test", "invalid"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = app
+            .clone()
+            .oneshot(request(
+                "GET",
+                "/orders/b563feb7b2b84b6test",
+                &token("test"),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        // orders of other customers are missing for the token
+        let response = app
+            .clone()
+            .oneshot(request(
+                "GET",
+                "/orders/b563feb7b2b84b6test",
+                &token("other"),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = app
+            .clone()
+            .oneshot(request("GET", "/customers/test/orders", &token("other")))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let response = app
+            .oneshot(request(
+                "DELETE",
+                "/orders/b563feb7b2b84b6test",
+                &token("test"),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+}
@@ -1,8 +1,17 @@
-use std::future::Future;
+use std::{future::Future, sync::Arc, time::Duration};
+
+use serde_json::value::RawValue;
+use tokio::sync::broadcast;
 
 use crate::{
+    auth::JwtVerifier,
     error::Error,
-    model::{Delivery, Item, Order, Payment},
+    limit::RateLimiter,
+    model::{
+        Actor, ApiKey, AttemptOutcome, AuditEntry, BatchMode, CreateOutcome, Delivery, EventType,
+        Item, ItemStatus, Order, OrderEvent, OrderPayload, OrderStatus, Payment, RejectedOrder,
+        RejectionSource, Webhook, WebhookAttempt, WebhookDelivery,
+    },
 };
 
 pub trait CacheOrder {
@@ -11,17 +20,113 @@ pub trait CacheOrder {
         order_id: &str,
     ) -> impl Future<Output = Result<Option<Order>, Error>> + Send;
 
+    /// Get order as JSON bytes which are ready to be sent as a response body.
+    fn get_order_json(
+        &self,
+        order_id: &str,
+    ) -> impl Future<Output = Result<Option<Vec<u8>>, Error>> + Send;
+
+    /// Get orders by their ids at once.
+    /// Result has an entry per id in the same order, `None` is a miss.
+    fn get_orders(
+        &self,
+        order_ids: &[String],
+    ) -> impl Future<Output = Result<Vec<Option<Order>>, Error>> + Send;
+
     fn insert_order(&self, order: &Order) -> impl Future<Output = Result<(), Error>> + Send;
+
+    fn remove_order(&self, order_id: &str) -> impl Future<Output = Result<(), Error>> + Send;
+
+    /// Count of invalidations seen so far, it's taken before the order is read elsewhere.
+    fn version(&self) -> u64 {
+        0
+    }
+
+    /// Put the order which was read elsewhere unless it's been removed since `version`,
+    /// so a stale read racing with an invalidation doesn't stay in cache.
+    fn fill_order(
+        &self,
+        order: &Order,
+        version: u64,
+    ) -> impl Future<Output = Result<(), Error>> + Send {
+        let _ = version;
+        self.insert_order(order)
+    }
 }
 
+/// Every change of orders is made on behalf of an actor, it's written to the audit log.
 pub trait StoreOrder {
-    fn create_order(&self, order: Order) -> impl Future<Output = Result<(), Error>> + Send;
+    /// Create the order, `payload` is the JSON it was received as.
+    fn create_order(
+        &self,
+        order: Order,
+        payload: Box<RawValue>,
+        actor: &Actor,
+    ) -> impl Future<Output = Result<(), Error>> + Send;
+
+    /// Create orders along with their payloads in a single transaction.
+    /// Result has an outcome per order in the same order.
+    fn create_orders(
+        &self,
+        orders: Vec<(Order, Box<RawValue>)>,
+        mode: BatchMode,
+        actor: &Actor,
+    ) -> impl Future<Output = Result<Vec<CreateOutcome>, Error>> + Send;
+
+    /// Replace the order with the same `order_uid`, the payload is replaced as well.
+    /// Returns `false` if there is no such order.
+    fn update_order(
+        &self,
+        order: Order,
+        payload: Box<RawValue>,
+        actor: &Actor,
+    ) -> impl Future<Output = Result<bool, Error>> + Send;
+
+    /// Remove the order along with its delivery, payment and items.
+    /// Returns `false` if there is no such order.
+    fn delete_order(
+        &self,
+        order_id: &str,
+        actor: &Actor,
+    ) -> impl Future<Output = Result<bool, Error>> + Send;
 
     fn get_order(
         &self,
         order_id: &str,
     ) -> impl Future<Output = Result<Option<Order>, Error>> + Send;
 
+    /// Get existing orders out of the given ids, in no particular order.
+    fn get_orders(
+        &self,
+        order_ids: &[String],
+    ) -> impl Future<Output = Result<Vec<Order>, Error>> + Send;
+
+    /// Get the latest order with the track number.
+    fn get_order_by_track_number(
+        &self,
+        track_number: &str,
+    ) -> impl Future<Output = Result<Option<Order>, Error>> + Send;
+
+    fn get_order_by_transaction(
+        &self,
+        transaction: &str,
+    ) -> impl Future<Output = Result<Option<Order>, Error>> + Send;
+
+    /// Get orders of the customer, the latest go first.
+    fn get_orders_by_customer(
+        &self,
+        customer_id: &str,
+        limit: i64,
+        offset: i64,
+    ) -> impl Future<Output = Result<Vec<Order>, Error>> + Send;
+
+    /// Get the payload the order was received as.
+    /// Returns `None` if there is no such order or it was stored without payload.
+    fn get_order_payload(
+        &self,
+        order_id: &str,
+    ) -> impl Future<Output = Result<Option<OrderPayload>, Error>> + Send;
+
     fn get_delivery(
         &self,
         order_id: &str,
@@ -36,8 +141,157 @@ pub trait StoreOrder {
         &self,
         order_id: &str,
     ) -> impl Future<Output = Result<Option<Payment>, Error>> + Send;
+
+    /// Move the order to the given status.
+    /// Returns `None` if there is no such order.
+    fn update_order_status(
+        &self,
+        order_id: &str,
+        status: OrderStatus,
+        actor: &Actor,
+    ) -> impl Future<Output = Result<Option<OrderStatus>, Error>> + Send;
+
+    /// Move items of the order with `chrt_id` to the given status.
+    /// Returns changed items or `None` if there are no such items.
+    fn update_item_status(
+        &self,
+        order_id: &str,
+        chrt_id: i32,
+        status: ItemStatus,
+        actor: &Actor,
+    ) -> impl Future<Output = Result<Option<Vec<Item>>, Error>> + Send;
+
+    /// Get changes of the order, the latest go first.
+    /// They are kept after the order is deleted, and empty if there has never been such order.
+    fn get_order_audit(
+        &self,
+        order_id: &str,
+        limit: i64,
+        offset: i64,
+    ) -> impl Future<Output = Result<Vec<AuditEntry>, Error>> + Send;
 }
 
+/// Outbox of events written in the same transaction as orders.
+pub trait StoreEvent {
+    /// Take up to `limit` pending events, oldest first.
+    /// They aren't given to anyone else for `lease`, unless they are completed or retried.
+    fn claim_events(
+        &self,
+        limit: i64,
+        lease: Duration,
+    ) -> impl Future<Output = Result<Vec<OrderEvent>, Error>> + Send;
+
+    /// Get events of the type which come after `after_id`, oldest first.
+    fn get_events(
+        &self,
+        event_type: EventType,
+        after_id: i64,
+        limit: i64,
+    ) -> impl Future<Output = Result<Vec<OrderEvent>, Error>> + Send;
+
+    /// Mark the event as published.
+    fn complete_event(&self, event_id: i64) -> impl Future<Output = Result<(), Error>> + Send;
+
+    /// Keep the event pending and give it out again after `delay`.
+    fn retry_event(
+        &self,
+        event_id: i64,
+        error: &str,
+        delay: Duration,
+    ) -> impl Future<Output = Result<(), Error>> + Send;
+}
+
+/// Webhook subscriptions and deliveries of events to them.
+pub trait StoreWebhook {
+    /// Subscribe `url` to events of the given types, later events are delivered to it.
+    fn create_webhook(
+        &self,
+        url: &str,
+        event_types: &[EventType],
+        secret: &str,
+    ) -> impl Future<Output = Result<Webhook, Error>> + Send;
+
+    fn get_webhooks(&self) -> impl Future<Output = Result<Vec<Webhook>, Error>> + Send;
+
+    fn get_webhook(&self, id: i32) -> impl Future<Output = Result<Option<Webhook>, Error>> + Send;
+
+    /// Remove the webhook along with its pending deliveries and log.
+    /// Returns `false` if there is no such webhook.
+    fn delete_webhook(&self, id: i32) -> impl Future<Output = Result<bool, Error>> + Send;
+
+    /// Get the delivery log of the webhook, the latest attempts go first.
+    /// Returns `None` if there is no such webhook.
+    fn get_webhook_attempts(
+        &self,
+        id: i32,
+        limit: i64,
+        offset: i64,
+    ) -> impl Future<Output = Result<Option<Vec<WebhookAttempt>>, Error>> + Send;
+
+    /// Take up to `limit` pending deliveries, oldest first.
+    /// They aren't given to anyone else for `lease`, unless their attempt is recorded.
+    fn claim_deliveries(
+        &self,
+        limit: i64,
+        lease: Duration,
+    ) -> impl Future<Output = Result<Vec<WebhookDelivery>, Error>> + Send;
+
+    /// Log the attempt and complete, retry or give up the delivery depending on its outcome.
+    fn record_attempt(
+        &self,
+        delivery_id: i64,
+        outcome: &AttemptOutcome,
+    ) -> impl Future<Output = Result<(), Error>> + Send;
+}
+
+/// Dead letters of orders which couldn't be created.
+pub trait StoreRejected {
+    /// Keep the payload along with the reason it was rejected.
+    fn reject_order(
+        &self,
+        source: RejectionSource,
+        payload: &str,
+        error: &str,
+    ) -> impl Future<Output = Result<(), Error>> + Send;
+
+    /// Get rejected orders without payloads, the latest go first.
+    fn get_rejected_orders(
+        &self,
+        limit: i64,
+        offset: i64,
+    ) -> impl Future<Output = Result<Vec<RejectedOrder>, Error>> + Send;
+
+    fn get_rejected_order(
+        &self,
+        id: i64,
+    ) -> impl Future<Output = Result<Option<RejectedOrder>, Error>> + Send;
+
+    /// Create the order in place of the rejected one at once.
+    /// Returns `false` if there is no such rejected order.
+    fn replay_rejected_order(
+        &self,
+        id: i64,
+        order: Order,
+        payload: Box<RawValue>,
+        actor: &Actor,
+    ) -> impl Future<Output = Result<bool, Error>> + Send;
+
+    /// Returns `false` if there is no such rejected order.
+    fn delete_rejected_order(&self, id: i64) -> impl Future<Output = Result<bool, Error>> + Send;
+}
+
+/// API keys of clients, only hashes of keys are stored.
+pub trait StoreApiKey {
+    /// Get the key by its hash unless it's revoked.
+    fn get_api_key(
+        &self,
+        key_hash: &str,
+    ) -> impl Future<Output = Result<Option<ApiKey>, Error>> + Send;
+}
+
+/// The same as the default limit of `axum`.
+pub const DEFAULT_MAX_BODY_SIZE: usize = 2 * 1024 * 1024;
+
 #[derive(Clone)]
 pub struct AppState<R, C>
 where
@@ -46,6 +300,22 @@ where
 {
     pub repo: R,
     pub cache: Option<C>,
+    /// Bearer token required by administrative endpoints, they are closed without it.
+    pub admin_token: Option<Arc<str>>,
+    /// Feed of events committed by any instance, streaming is unavailable without it.
+    pub events: Option<broadcast::Sender<OrderEvent>>,
+    /// Whether endpoints of orders accept an API key with the scope.
+    pub require_api_key: bool,
+    /// Verifier of JWTs, endpoints of orders accept a token with the scope if it's configured.
+    /// The endpoints are open if neither API keys nor tokens are accepted.
+    pub jwt: Option<Arc<JwtVerifier>>,
+    /// Requests per minute of clients without a rate limit of their own.
+    pub rate_limit: Option<u32>,
+    /// Requests per minute from an IP whether it's authenticated or not, `rate_limit` if unset.
+    pub ip_rate_limit: Option<u32>,
+    pub limiter: RateLimiter,
+    /// Limit of request bodies in bytes.
+    pub max_body_size: usize,
 }
 
 impl<R, C> AppState<R, C>
@@ -54,6 +324,53 @@ where
     C: Clone,
 {
     pub fn new(repo: R, cache: Option<C>) -> Self {
-        Self { repo, cache }
+        Self {
+            repo,
+            cache,
+            admin_token: None,
+            events: None,
+            require_api_key: false,
+            jwt: None,
+            rate_limit: None,
+            ip_rate_limit: None,
+            limiter: RateLimiter::default(),
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+        }
+    }
+
+    pub fn with_admin_token(mut self, admin_token: Option<String>) -> Self {
+        self.admin_token = admin_token.map(Arc::from);
+        self
+    }
+
+    pub fn with_require_api_key(mut self, require_api_key: bool) -> Self {
+        self.require_api_key = require_api_key;
+        self
+    }
+
+    pub fn with_jwt(mut self, jwt: Option<JwtVerifier>) -> Self {
+        self.jwt = jwt.map(Arc::new);
+        self
+    }
+
+    pub fn with_rate_limit(mut self, rate_limit: Option<u32>, limiter: RateLimiter) -> Self {
+        self.rate_limit = rate_limit;
+        self.limiter = limiter;
+        self
+    }
+
+    pub fn with_ip_rate_limit(mut self, ip_rate_limit: Option<u32>) -> Self {
+        self.ip_rate_limit = ip_rate_limit;
+        self
+    }
+
+    pub fn with_max_body_size(mut self, max_body_size: usize) -> Self {
+        self.max_body_size = max_body_size;
+        self
+    }
+
+    pub fn with_events(mut self, events: broadcast::Sender<OrderEvent>) -> Self {
+        self.events = Some(events);
+        self
     }
 }
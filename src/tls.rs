@@ -0,0 +1,250 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+use axum_server::tls_rustls::RustlsConfig;
+use rustls::{
+    crypto::{ring, CryptoProvider},
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::{danger::ClientCertVerifier, WebPkiClientVerifier},
+    RootCertStore, ServerConfig,
+};
+use tracing::{debug, info, warn};
+
+/// PEM files of the server certificate, it's reloaded once any of them changes.
+#[derive(Clone, Debug)]
+pub struct TlsFiles {
+    /// Certificate chain, the certificate of the server goes first.
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// CA certificates of clients, they must present a certificate signed by one of them.
+    pub client_ca: Option<PathBuf>,
+}
+
+impl TlsFiles {
+    fn paths(&self) -> impl Iterator<Item = &Path> {
+        [Some(&self.cert), Some(&self.key), self.client_ca.as_ref()]
+            .into_iter()
+            .flatten()
+            .map(PathBuf::as_path)
+    }
+
+    /// Modification times of the files, a missing file has none.
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        self.paths()
+            .map(|path| fs::metadata(path).and_then(|meta| meta.modified()).ok())
+            .collect()
+    }
+
+    fn server_config(&self) -> anyhow::Result<ServerConfig> {
+        let provider = Arc::new(ring::default_provider());
+
+        let certs = CertificateDer::pem_file_iter(&self.cert)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .with_context(|| format!("failed to read certificates of {:?}", self.cert))?;
+        anyhow::ensure!(!certs.is_empty(), "no certificates in {:?}", self.cert);
+        let key = PrivateKeyDer::from_pem_file(&self.key)
+            .with_context(|| format!("failed to read private key of {:?}", self.key))?;
+
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?;
+        let builder = match &self.client_ca {
+            Some(path) => builder.with_client_cert_verifier(client_verifier(path, provider)?),
+            None => builder.with_no_client_auth(),
+        };
+
+        let mut config = builder
+            .with_single_cert(certs, key)
+            .context("invalid certificate or private key")?;
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Ok(config)
+    }
+}
+
+fn client_verifier(
+    path: &Path,
+    provider: Arc<CryptoProvider>,
+) -> anyhow::Result<Arc<dyn ClientCertVerifier>> {
+    let mut roots = RootCertStore::empty();
+    for cert in CertificateDer::pem_file_iter(path)
+        .with_context(|| format!("failed to read client CA of {:?}", path))?
+    {
+        roots.add(cert.with_context(|| format!("failed to read client CA of {:?}", path))?)?;
+    }
+
+    Ok(WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider).build()?)
+}
+
+/// Keeps TLS config of the server up to date with its files,
+/// so renewed certificates are used for new connections without restart.
+pub struct Reloader {
+    files: TlsFiles,
+    config: RustlsConfig,
+    modified: Vec<Option<SystemTime>>,
+    interval: Duration,
+}
+
+impl Reloader {
+    pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(10);
+
+    pub fn try_new(files: TlsFiles) -> anyhow::Result<Self> {
+        // taken before reading, so changes made meanwhile aren't missed
+        let modified = files.modified();
+        let config = RustlsConfig::from_config(Arc::new(files.server_config()?));
+
+        Ok(Self {
+            files,
+            config,
+            modified,
+            interval: Self::DEFAULT_INTERVAL,
+        })
+    }
+
+    /// How often files are checked for changes.
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Config for the server, it's shared with the reloader.
+    pub fn config(&self) -> RustlsConfig {
+        self.config.clone()
+    }
+
+    /// Reload the config once files change until the task is dropped.
+    /// The previous config is kept if the files are broken, e.g. while they are being replaced.
+    pub async fn run(mut self) {
+        debug!(files = ?self.files, "run reloader of certificates");
+
+        loop {
+            tokio::time::sleep(self.interval).await;
+
+            let modified = self.files.modified();
+            if modified == self.modified {
+                continue;
+            }
+            match self.files.server_config() {
+                Ok(config) => {
+                    self.config.reload_from_config(Arc::new(config));
+                    self.modified = modified;
+                    info!("certificates are reloaded");
+                }
+                Err(e) => warn!("failed to reload certificates: {:#}", e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CA: &str = include_str!("../tests/fixtures/tls/ca.pem");
+    const CERT: &str = include_str!("../tests/fixtures/tls/server.pem");
+    const KEY: &str = include_str!("../tests/fixtures/tls/server.key");
+    const RENEWED_CERT: &str = include_str!("../tests/fixtures/tls/server2.pem");
+    const RENEWED_KEY: &str = include_str!("../tests/fixtures/tls/server2.key");
+
+    const INTERVAL: Duration = Duration::from_millis(10);
+
+    /// Files of the server in a directory of the test.
+    fn files(name: &str) -> TlsFiles {
+        let dir = std::env::temp_dir().join(format!("{}-{}", std::process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+        let files = TlsFiles {
+            cert: dir.join("cert.pem"),
+            key: dir.join("key.pem"),
+            client_ca: None,
+        };
+        fs::write(&files.cert, CERT).unwrap();
+        fs::write(&files.key, KEY).unwrap();
+        files
+    }
+
+    /// Write the file, it's modified later than before even on coarse clocks.
+    fn replace(path: &Path, content: &str) {
+        let modified = fs::metadata(path).unwrap().modified().unwrap();
+        fs::write(path, content).unwrap();
+        fs::File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(modified + Duration::from_secs(1))
+            .unwrap();
+    }
+
+    /// Wait for a while until the config differs from `before`.
+    async fn reloaded(config: &RustlsConfig, before: &Arc<ServerConfig>) -> bool {
+        for _ in 0..50 {
+            tokio::time::sleep(INTERVAL).await;
+            if !Arc::ptr_eq(&config.get_inner(), before) {
+                return true;
+            }
+        }
+        false
+    }
+
+    #[tokio::test]
+    async fn reload_changed_certificate() {
+        let files = files("reload-changed");
+        let reloader = Reloader::try_new(files.clone())
+            .unwrap()
+            .with_interval(INTERVAL);
+        let config = reloader.config();
+        let running = tokio::spawn(reloader.run());
+
+        // nothing is reloaded until files change
+        let before = config.get_inner();
+        assert!(!reloaded(&config, &before).await);
+
+        replace(&files.cert, RENEWED_CERT);
+        replace(&files.key, RENEWED_KEY);
+        assert!(reloaded(&config, &before).await);
+
+        running.abort();
+        fs::remove_dir_all(files.cert.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn keep_config_of_broken_certificate() {
+        let files = files("keep-broken");
+        let reloader = Reloader::try_new(files.clone())
+            .unwrap()
+            .with_interval(INTERVAL);
+        let config = reloader.config();
+        let running = tokio::spawn(reloader.run());
+        let before = config.get_inner();
+
+        // the key doesn't match the certificate yet, e.g. while files are being replaced
+        replace(&files.cert, RENEWED_CERT);
+        assert!(!reloaded(&config, &before).await);
+        replace(&files.cert, "not a certificate");
+        assert!(!reloaded(&config, &before).await);
+
+        // the change is picked up once files are fine
+        replace(&files.cert, RENEWED_CERT);
+        replace(&files.key, RENEWED_KEY);
+        assert!(reloaded(&config, &before).await);
+
+        running.abort();
+        fs::remove_dir_all(files.cert.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn verify_clients_by_ca() {
+        let files = files("client-ca");
+        let path = files.cert.with_file_name("ca.pem");
+        let provider = Arc::new(ring::default_provider());
+
+        fs::write(&path, CA).unwrap();
+        assert!(client_verifier(&path, provider.clone()).is_ok());
+        fs::write(&path, "").unwrap();
+        assert!(client_verifier(&path, provider).is_err());
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,314 @@
+use std::time::Duration;
+
+use chrono::Utc;
+use futures_util::future::join_all;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+use tracing::{debug, trace, warn};
+
+use crate::{
+    error::Error,
+    model::{AttemptOutcome, WebhookDelivery},
+    outbox::{backoff, LEASE, PUBLISH_TIMEOUT},
+    state::StoreWebhook,
+};
+
+/// Delivery is given up after that many failed attempts.
+const MAX_ATTEMPTS: i32 = 10;
+
+pub const SIGNATURE_HEADER: &str = "x-webhook-signature";
+pub const TIMESTAMP_HEADER: &str = "x-webhook-timestamp";
+pub const DELIVERY_HEADER: &str = "x-webhook-delivery";
+
+/// Signature of the body sent at `timestamp` (unix time in seconds):
+/// `sha256=` followed by hex of HMAC-SHA256 over `{timestamp}.{body}` with the webhook secret.
+pub fn sign(secret: &str, timestamp: i64, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("any key size is accepted");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body);
+
+    format!("sha256={}", hex(&mac.finalize().into_bytes()))
+}
+
+/// Random secret for a webhook created without one.
+pub fn generate_secret() -> String {
+    let mut bytes = [0; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    hex(&bytes)
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Delivers events to subscribed webhooks, an event is sent to a webhook at least once.
+pub struct Dispatcher<W> {
+    webhooks: W,
+    client: reqwest::Client,
+    batch_size: i64,
+    interval: Duration,
+}
+
+impl<W> Dispatcher<W>
+where
+    W: StoreWebhook,
+{
+    pub const DEFAULT_BATCH_SIZE: i64 = 100;
+    pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(1);
+
+    pub fn try_new(webhooks: W) -> Result<Self, reqwest::Error> {
+        Ok(Self {
+            webhooks,
+            client: reqwest::Client::builder()
+                .timeout(PUBLISH_TIMEOUT)
+                .build()?,
+            batch_size: Self::DEFAULT_BATCH_SIZE,
+            interval: Self::DEFAULT_INTERVAL,
+        })
+    }
+
+    /// How long to wait for new deliveries once there are no pending ones.
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Deliver events until the task is dropped.
+    pub async fn run(self) {
+        debug!(batch_size = self.batch_size, "run webhook dispatcher");
+
+        loop {
+            match self.dispatch_batch().await {
+                Ok(0) => tokio::time::sleep(self.interval).await,
+                Ok(_) => {}
+                Err(e) => {
+                    warn!("failed to dispatch webhook deliveries: {}", e);
+                    tokio::time::sleep(self.interval).await;
+                }
+            }
+        }
+    }
+
+    /// Make an attempt of a batch of pending deliveries, returns how many of them were claimed.
+    pub async fn dispatch_batch(&self) -> Result<usize, Error> {
+        let deliveries = self
+            .webhooks
+            .claim_deliveries(self.batch_size, LEASE)
+            .await?;
+
+        let outcomes = join_all(deliveries.iter().map(|delivery| self.deliver(delivery))).await;
+        for (delivery, outcome) in deliveries.iter().zip(outcomes) {
+            self.webhooks.record_attempt(delivery.id, &outcome).await?;
+        }
+
+        Ok(deliveries.len())
+    }
+
+    async fn deliver(&self, delivery: &WebhookDelivery) -> AttemptOutcome {
+        let body = match serde_json::to_vec(&delivery.event) {
+            Ok(body) => body,
+            Err(e) => return self.failed(delivery, None, e.to_string()),
+        };
+        let timestamp = Utc::now().timestamp();
+
+        let result = self
+            .client
+            .post(&delivery.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(DELIVERY_HEADER, delivery.id)
+            .header(TIMESTAMP_HEADER, timestamp)
+            .header(SIGNATURE_HEADER, sign(&delivery.secret, timestamp, &body))
+            .body(body)
+            .send()
+            .await;
+
+        match result {
+            Ok(response) if response.status().is_success() => {
+                trace!(delivery_id = delivery.id, "event is delivered");
+                AttemptOutcome::Delivered {
+                    status_code: response.status().as_u16().into(),
+                }
+            }
+            Ok(response) => self.failed(
+                delivery,
+                Some(response.status().as_u16().into()),
+                format!("unexpected status {}", response.status()),
+            ),
+            Err(e) => self.failed(delivery, None, e.to_string()),
+        }
+    }
+
+    fn failed(
+        &self,
+        delivery: &WebhookDelivery,
+        status_code: Option<i32>,
+        error: String,
+    ) -> AttemptOutcome {
+        let retry_after =
+            (delivery.attempts + 1 < MAX_ATTEMPTS).then(|| backoff(delivery.attempts));
+        warn!(
+            delivery_id = delivery.id,
+            ?retry_after,
+            "failed to deliver event to webhook: {}",
+            error
+        );
+
+        AttemptOutcome::Failed {
+            status_code,
+            error,
+            retry_after,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    use axum::{
+        http::{HeaderMap, StatusCode},
+        routing::post,
+        Router,
+    };
+    use serde_json::value::RawValue;
+
+    use crate::{
+        mock::MockStore,
+        model::{EventType, OrderEvent},
+    };
+
+    const SECRET: &str = "secret";
+
+    /// Webhook with a single pending delivery, which records outcomes of its attempts.
+    struct MockWebhooks {
+        url: String,
+        attempts: i32,
+        outcomes: Mutex<Vec<AttemptOutcome>>,
+    }
+
+    impl MockStore for MockWebhooks {
+        async fn claim_deliveries(
+            &self,
+            _: i64,
+            _: Duration,
+        ) -> Result<Vec<WebhookDelivery>, Error> {
+            Ok(vec![WebhookDelivery {
+                id: 3,
+                url: self.url.clone(),
+                secret: SECRET.to_owned(),
+                attempts: self.attempts,
+                event: OrderEvent {
+                    id: 7,
+                    event_type: EventType::OrderCreated,
+                    order_id: "a".to_owned(),
+                    created_at: "2021-11-26T06:22:19Z".parse().unwrap(),
+                    data: RawValue::from_string(r#"{"order_uid":"a"}"#.to_owned()).unwrap(),
+                    attempts: 0,
+                },
+            }])
+        }
+
+        async fn record_attempt(
+            &self,
+            delivery_id: i64,
+            outcome: &AttemptOutcome,
+        ) -> Result<(), Error> {
+            assert_eq!(delivery_id, 3);
+            self.outcomes.lock().unwrap().push(outcome.clone());
+            Ok(())
+        }
+    }
+
+    /// Local stand-in of a partner, which accepts events with a valid signature only.
+    async fn partner(status: StatusCode) -> String {
+        let app = Router::new().route(
+            "/events",
+            post(move |headers: HeaderMap, body: String| async move {
+                let timestamp = headers[TIMESTAMP_HEADER].to_str().unwrap().parse().unwrap();
+                let signature = headers[SIGNATURE_HEADER].to_str().unwrap();
+                if signature != sign(SECRET, timestamp, body.as_bytes()) {
+                    return StatusCode::UNAUTHORIZED;
+                }
+                assert_eq!(headers[DELIVERY_HEADER], "3");
+                assert!(body.contains(r#""data":{"order_uid":"a"}"#));
+                status
+            }),
+        );
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/events", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        url
+    }
+
+    fn dispatcher(url: String, attempts: i32) -> Dispatcher<MockWebhooks> {
+        Dispatcher::try_new(MockWebhooks {
+            url,
+            attempts,
+            outcomes: Mutex::default(),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn sign_with_known_signature() {
+        assert_eq!(
+            sign("key", 1637907727, b"{}"),
+            "sha256=a542b1e18cefcd92b22dca6c09977ef1f87ff8b72673145a6a55b1dd99b02434"
+        );
+        assert_ne!(
+            sign("key", 1637907727, b"{}"),
+            sign("key", 1637907728, b"{}")
+        );
+        assert_ne!(
+            sign("key", 1637907727, b"{}"),
+            sign("other", 1637907727, b"{}")
+        );
+    }
+
+    #[tokio::test]
+    async fn deliver_signed_event() {
+        let dispatcher = dispatcher(partner(StatusCode::NO_CONTENT).await, 0);
+
+        assert_eq!(dispatcher.dispatch_batch().await.unwrap(), 1);
+        assert_eq!(
+            *dispatcher.webhooks.outcomes.lock().unwrap(),
+            [AttemptOutcome::Delivered { status_code: 204 }]
+        );
+    }
+
+    #[tokio::test]
+    async fn retry_failed_delivery_with_backoff() {
+        let dispatcher = dispatcher(partner(StatusCode::BAD_GATEWAY).await, 2);
+
+        dispatcher.dispatch_batch().await.unwrap();
+        assert_eq!(
+            *dispatcher.webhooks.outcomes.lock().unwrap(),
+            [AttemptOutcome::Failed {
+                status_code: Some(502),
+                error: "unexpected status 502 Bad Gateway".to_owned(),
+                retry_after: Some(Duration::from_secs(4)),
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn give_up_delivery_after_last_attempt() {
+        let dispatcher = dispatcher(partner(StatusCode::BAD_GATEWAY).await, MAX_ATTEMPTS - 1);
+
+        dispatcher.dispatch_batch().await.unwrap();
+        assert!(matches!(
+            dispatcher.webhooks.outcomes.lock().unwrap()[..],
+            [AttemptOutcome::Failed {
+                retry_after: None,
+                ..
+            }]
+        ));
+    }
+}
@@ -0,0 +1,43 @@
+//! Fixtures of tests which need external services.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use l_0_demo::{
+    model::{demo_order, Actor, ItemStatus, Order, OrderStatus},
+    repo::PostgresRepo,
+};
+
+const DB_PARAMS_VAR: &str = "WBTECH_L0_DEMO_TEST_DB_PARAMS";
+
+pub async fn repo() -> PostgresRepo {
+    let params =
+        std::env::var(DB_PARAMS_VAR).unwrap_or_else(|_| panic!("{} is not set", DB_PARAMS_VAR));
+    PostgresRepo::try_new(&params).await.unwrap()
+}
+
+pub fn actor() -> Actor {
+    Actor {
+        name: "test".to_owned(),
+        request_id: "test".to_owned(),
+    }
+}
+
+/// Id which isn't taken by other runs, a prefix of 4 chars fits `order_uid` and `transaction`.
+pub fn unique_id(prefix: &str) -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    format!("{}{:0>15}", prefix, nanos % 10u128.pow(15))
+}
+
+/// Order with items of the given `chrt_id`s, statuses are given as a client might send them.
+pub fn order(order_uid: &str, chrt_ids: &[i32]) -> Order {
+    let mut order = demo_order(order_uid, chrt_ids.len());
+    order.status = OrderStatus::Completed;
+    for (item, chrt_id) in order.items.iter_mut().zip(chrt_ids) {
+        item.chrt_id = *chrt_id;
+        item.status = ItemStatus::Delivered;
+    }
+    order
+}
@@ -0,0 +1,85 @@
+//! Checks of the consumer which need a broker and a database, so they are ignored by default.
+//! Run them with `cargo test --features kafka --test kafka -- --ignored` once
+//! `WBTECH_L0_DEMO_TEST_KAFKA_BROKERS` (e.g. `localhost:9092` of `docker compose --profile kafka`)
+//! and `WBTECH_L0_DEMO_TEST_DB_PARAMS` are set. Topics are new for every run.
+#![cfg(feature = "kafka")]
+
+mod common;
+
+use std::time::Duration;
+
+use l_0_demo::{kafka::Consumer, state::StoreOrder};
+use rdkafka::{
+    consumer::{Consumer as _, StreamConsumer},
+    message::Headers,
+    producer::{FutureProducer, FutureRecord},
+    ClientConfig, Message,
+};
+
+use self::common::{actor, order, repo, unique_id};
+
+const BROKERS_VAR: &str = "WBTECH_L0_DEMO_TEST_KAFKA_BROKERS";
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+fn brokers() -> String {
+    std::env::var(BROKERS_VAR).unwrap_or_else(|_| panic!("{} is not set", BROKERS_VAR))
+}
+
+#[tokio::test]
+#[ignore = "needs a broker and a database"]
+async fn consume_orders_and_dead_letter_invalid_ones() {
+    let (brokers, repo) = (brokers(), repo().await);
+    let order_uid = unique_id("tstk");
+    let topic = format!("orders-{}", order_uid);
+    let dead_letter_topic = format!("{}-dead-letter", topic);
+
+    let producer: FutureProducer = ClientConfig::new()
+        .set("bootstrap.servers", &brokers)
+        .create()
+        .unwrap();
+    let valid = serde_json::to_vec(&order(&order_uid, &[1])).unwrap();
+    for payload in [valid.as_slice(), b"not an order"] {
+        producer
+            .send(FutureRecord::<(), _>::to(&topic).payload(payload), TIMEOUT)
+            .await
+            .unwrap();
+    }
+
+    let consumer =
+        Consumer::try_new(repo.clone(), &brokers, &topic, &topic, &dead_letter_topic).unwrap();
+    let running = tokio::spawn(consumer.run());
+
+    let created = tokio::time::timeout(TIMEOUT, async {
+        loop {
+            if let Some(order) = repo.get_order(&order_uid).await.unwrap() {
+                return order;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await
+    .expect("order isn't created");
+    assert_eq!(created.order_uid, order_uid);
+
+    let dead_letters: StreamConsumer = ClientConfig::new()
+        .set("bootstrap.servers", &brokers)
+        .set("group.id", &dead_letter_topic)
+        .set("auto.offset.reset", "earliest")
+        .create()
+        .unwrap();
+    dead_letters.subscribe(&[&dead_letter_topic]).unwrap();
+    let message = tokio::time::timeout(TIMEOUT, dead_letters.recv())
+        .await
+        .expect("message isn't dead-lettered")
+        .unwrap();
+    assert_eq!(message.payload(), Some(b"not an order".as_slice()));
+    let headers = message.headers().unwrap();
+    assert!(headers.iter().any(|header| header.key == "error"));
+    assert!(headers
+        .iter()
+        .any(|header| header.key == "source-topic" && header.value == Some(topic.as_bytes())));
+
+    running.abort();
+    repo.delete_order(&order_uid, &actor()).await.unwrap();
+}
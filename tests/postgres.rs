@@ -0,0 +1,259 @@
+//! Checks of the repository which need a database, so they are ignored by default.
+//! Run them with `cargo test --test postgres -- --ignored` once `WBTECH_L0_DEMO_TEST_DB_PARAMS`
+//! is set, e.g. `host=localhost user=postgres password=postgres dbname=wb`.
+//! Created orders are removed afterwards.
+
+mod common;
+
+use std::time::Duration;
+
+use l_0_demo::{
+    error::Error,
+    model::{
+        BatchMode, CreateStatus, EventType, ItemStatus, Order, OrderEvent, OrderStatus,
+        RejectionSource,
+    },
+    repo::PostgresRepo,
+    state::{StoreEvent, StoreOrder, StoreRejected},
+};
+use serde_json::value::to_raw_value;
+
+use self::common::{actor, order, repo, unique_id};
+
+async fn create(repo: &PostgresRepo, order: Order) {
+    let payload = to_raw_value(&order).unwrap();
+    repo.create_order(order, payload, &actor()).await.unwrap();
+}
+
+async fn get(repo: &PostgresRepo, order_id: &str) -> Order {
+    repo.get_order(order_id).await.unwrap().unwrap()
+}
+
+/// Events of the order in the outbox, all of them are paged through.
+async fn events(repo: &PostgresRepo, event_type: EventType, order_id: &str) -> Vec<OrderEvent> {
+    let (mut events, mut after_id) = (vec![], 0);
+    loop {
+        let page = repo.get_events(event_type, after_id, 1000).await.unwrap();
+        let Some(last) = page.last() else {
+            return events;
+        };
+        after_id = last.id;
+        events.extend(page.into_iter().filter(|event| event.order_id == order_id));
+    }
+}
+
+fn item_statuses(order: &Order) -> Vec<(i32, ItemStatus)> {
+    let mut statuses: Vec<_> = order
+        .items
+        .iter()
+        .map(|item| (item.chrt_id, item.status))
+        .collect();
+    statuses.sort_by_key(|(chrt_id, _)| *chrt_id);
+    statuses
+}
+
+#[tokio::test]
+#[ignore = "needs a database"]
+async fn create_orders_in_initial_statuses() {
+    let repo = repo().await;
+    let (a, b) = (unique_id("tsta"), unique_id("tstb"));
+
+    create(&repo, order(&a, &[1])).await;
+    let orders = [&b].map(|id| {
+        let order = order(id, &[1]);
+        let payload = to_raw_value(&order).unwrap();
+        (order, payload)
+    });
+    repo.create_orders(orders.to_vec(), BatchMode::Atomic, &actor())
+        .await
+        .unwrap();
+
+    for id in [&a, &b] {
+        let order = get(&repo, id).await;
+        assert_eq!(order.status, OrderStatus::Created);
+        assert_eq!(item_statuses(&order), [(1, ItemStatus::Accepted)]);
+
+        repo.delete_order(id, &actor()).await.unwrap();
+    }
+}
+
+#[tokio::test]
+#[ignore = "needs a database"]
+async fn refuse_invalid_transitions() {
+    let repo = repo().await;
+    let id = unique_id("tstt");
+    create(&repo, order(&id, &[1])).await;
+
+    let result = repo
+        .update_order_status(&id, OrderStatus::Completed, &actor())
+        .await;
+    assert!(matches!(result, Err(Error::Conflict(_))), "{:?}", result);
+    let result = repo
+        .update_order_status(&id, OrderStatus::Paid, &actor())
+        .await;
+    assert!(
+        matches!(result, Ok(Some(OrderStatus::Paid))),
+        "{:?}",
+        result
+    );
+
+    let result = repo
+        .update_item_status(&id, 1, ItemStatus::Delivered, &actor())
+        .await;
+    assert!(matches!(result, Err(Error::Conflict(_))), "{:?}", result);
+    let result = repo
+        .update_item_status(&id, 1, ItemStatus::Assembled, &actor())
+        .await;
+    assert!(matches!(result, Ok(Some(_))), "{:?}", result);
+
+    let order = get(&repo, &id).await;
+    assert_eq!(order.status, OrderStatus::Paid);
+    assert_eq!(item_statuses(&order), [(1, ItemStatus::Assembled)]);
+
+    repo.delete_order(&id, &actor()).await.unwrap();
+}
+
+#[tokio::test]
+#[ignore = "needs a database"]
+async fn update_keeps_statuses() {
+    let repo = repo().await;
+    let id = unique_id("tstu");
+    create(&repo, order(&id, &[1])).await;
+    repo.update_order_status(&id, OrderStatus::Paid, &actor())
+        .await
+        .unwrap();
+    repo.update_item_status(&id, 1, ItemStatus::Assembled, &actor())
+        .await
+        .unwrap();
+
+    // the replacement tells other statuses and adds an item
+    let replacement = order(&id, &[1, 2]);
+    let payload = to_raw_value(&replacement).unwrap();
+    assert!(repo
+        .update_order(replacement, payload, &actor())
+        .await
+        .unwrap());
+
+    let order = get(&repo, &id).await;
+    assert_eq!(order.status, OrderStatus::Paid);
+    assert_eq!(
+        item_statuses(&order),
+        [(1, ItemStatus::Assembled), (2, ItemStatus::Accepted)]
+    );
+
+    repo.delete_order(&id, &actor()).await.unwrap();
+}
+
+#[tokio::test]
+#[ignore = "needs a database"]
+async fn insert_items_up_to_max_copy_threshold() {
+    let threshold = PostgresRepo::MAX_COPY_THRESHOLD;
+    let repo = repo().await.with_copy_threshold(threshold);
+    // the largest order inserted with a single statement and the smallest one copied
+    for items in [threshold - 1, threshold] {
+        let id = unique_id("tstc");
+        let chrt_ids: Vec<_> = (1..=items as i32).collect();
+        create(&repo, order(&id, &chrt_ids)).await;
+        assert_eq!(get(&repo, &id).await.items.len(), items);
+
+        repo.delete_order(&id, &actor()).await.unwrap();
+    }
+}
+
+#[tokio::test]
+#[ignore = "needs a database"]
+async fn update_and_delete_emit_events() {
+    let repo = repo().await;
+    let id = unique_id("tste");
+    create(&repo, order(&id, &[1])).await;
+
+    let mut replacement = order(&id, &[1]);
+    replacement.delivery.city = "Kazan".to_owned();
+    let payload = to_raw_value(&replacement).unwrap();
+    repo.update_order(replacement, payload, &actor())
+        .await
+        .unwrap();
+    repo.delete_order(&id, &actor()).await.unwrap();
+
+    let updated = events(&repo, EventType::OrderUpdated, &id).await;
+    assert_eq!(updated.len(), 1);
+    let order: Order = serde_json::from_str(updated[0].data.get()).unwrap();
+    assert_eq!(order.delivery.city, "Kazan");
+
+    let deleted = events(&repo, EventType::OrderDeleted, &id).await;
+    assert_eq!(deleted.len(), 1);
+    let order: Order = serde_json::from_str(deleted[0].data.get()).unwrap();
+    assert_eq!(order.delivery.city, "Kazan");
+}
+
+#[tokio::test]
+#[ignore = "needs a database"]
+async fn prune_only_published_events() {
+    let repo = repo().await;
+    let published = unique_id("tsto");
+    let pending = unique_id("tsto");
+    create(&repo, order(&published, &[1])).await;
+    create(&repo, order(&pending, &[1])).await;
+    for event in events(&repo, EventType::OrderCreated, &published).await {
+        repo.complete_event(event.id).await.unwrap();
+    }
+
+    repo.prune_events(Duration::ZERO).await.unwrap();
+
+    assert!(events(&repo, EventType::OrderCreated, &published)
+        .await
+        .is_empty());
+    assert_eq!(
+        events(&repo, EventType::OrderCreated, &pending).await.len(),
+        1
+    );
+
+    repo.delete_order(&published, &actor()).await.unwrap();
+    repo.delete_order(&pending, &actor()).await.unwrap();
+}
+
+#[tokio::test]
+#[ignore = "needs a database"]
+async fn tell_why_orders_of_batch_are_rejected() {
+    let repo = repo().await;
+    let id = unique_id("tstr");
+    create(&repo, order(&id, &[1])).await;
+
+    let duplicate = order(&id, &[1]);
+    let payload = to_raw_value(&duplicate).unwrap();
+    let outcomes = repo
+        .create_orders(vec![(duplicate, payload)], BatchMode::Savepoint, &actor())
+        .await
+        .unwrap();
+    assert_eq!(outcomes[0].status, CreateStatus::Duplicate);
+    let error = outcomes[0].error.as_deref().unwrap_or_default();
+    assert!(error.contains("duplicate key"), "{}", error);
+
+    repo.delete_order(&id, &actor()).await.unwrap();
+}
+
+#[tokio::test]
+#[ignore = "needs a database"]
+async fn truncate_payloads_of_rejected_orders() {
+    let repo = repo().await;
+    let error = unique_id("tstp");
+    // chars take two bytes after the first one, so the limit splits one of them
+    let payload = format!("a{}", "я".repeat(40 * 1024));
+    repo.reject_order(RejectionSource::Http, &payload, &error)
+        .await
+        .unwrap();
+
+    let rejected = repo.get_rejected_orders(100, 0).await.unwrap();
+    let id = rejected
+        .iter()
+        .find(|rejected| rejected.error.starts_with(&error))
+        .expect("order isn't rejected")
+        .id;
+    let rejected = repo.get_rejected_order(id).await.unwrap().unwrap();
+    let kept = rejected.payload.unwrap();
+    assert_eq!(kept.len(), 64 * 1024 - 1);
+    assert!(payload.starts_with(&kept));
+    assert!(rejected.error.contains("truncated"), "{}", rejected.error);
+
+    repo.delete_rejected_order(id).await.unwrap();
+}